@@ -0,0 +1,699 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+
+use crate::echo_canceller::EchoCanceller;
+use crate::{FRAME_SAMPLES_16K, SEND_SAMPLE_RATE};
+
+// The suppressor works on 10 ms hops with 50% overlapping 20 ms windows
+const HOP: usize = FRAME_SAMPLES_16K / 2;
+const FFT_LEN: usize = HOP * 2;
+const BINS: usize = FFT_LEN / 2 + 1;
+
+// Hops at the start of the stream assumed to be background noise
+const NOISE_INIT_HOPS: usize = 10;
+// Bins this many times above the noise estimate are not learned from quickly
+const NOISE_JUMP: f32 = 10.0;
+// Fastest the noise estimate rises otherwise, per hop (about 1 dB/s)
+const NOISE_CREEP: f32 = 1.0025;
+// Smoothing of the a priori SNR ("decision-directed" estimate)
+const DD_ALPHA: f32 = 0.98;
+
+// Echo canceller length, 64 ms: output latency plus a small room's echo
+const ECHO_TAPS: usize = 1024;
+// Playback not yet matched with mic audio is dropped beyond this (2 s)
+const MAX_REFERENCE: usize = SEND_SAMPLE_RATE as usize * 2;
+// How far the reference may drift from the mic before it is realigned (5 ms).
+// Smaller offsets are left to the echo filter, which relearns after a shift.
+const ALIGN_TOLERANCE: usize = SEND_SAMPLE_RATE as usize / 200;
+
+// Quietest frame that can still be speech, in dBFS
+const MIN_SPEECH_DBFS: f32 = -55.0;
+
+// Maximum attenuation of each noise suppression level, in dB
+const NS_ATTENUATION_DB: [f32; 4] = [6.0, 10.0, 15.0, 21.0];
+// How far above the noise floor a frame must be to count as speech, in dB,
+// and how many frames speech is held for after it stops, per VAD level.
+// Higher levels trigger less often, like WebRTC's aggressiveness modes.
+const VAD_MARGIN_DB: [f32; 4] = [3.0, 5.0, 7.0, 9.0];
+const VAD_HANGOVER_FRAMES: [usize; 4] = [12, 10, 8, 5];
+// How far above the usual echo residual speech must be to be the child
+// rather than the bear, in dB, and for how many frames in a row before it
+// interrupts the bear, per barge-in level. Level 0 never interrupts.
+const BARGE_IN_EXCESS_DB: [f32; 4] = [f32::INFINITY, 12.0, 9.0, 6.0];
+const BARGE_IN_FRAMES: [usize; 4] = [usize::MAX, 12, 8, 5];
+
+// Noise suppression by Wiener filtering in the frequency domain. The noise
+// spectrum is learned while the VAD hears no speech.
+#[derive(Clone)]
+struct NoiseSuppressor {
+    fft: Arc<dyn RealToComplex<f32>>,
+    ifft: Arc<dyn ComplexToReal<f32>>,
+    // sqrt-Hann, applied before the FFT and again before overlap-add
+    window: Vec<f32>,
+    input: Vec<f32>,
+    overlap: Vec<f32>,
+    noise: Vec<f32>,
+    prev_gain: Vec<f32>,
+    prev_snr: Vec<f32>,
+    hops: usize,
+    min_gain: f32,
+}
+
+impl NoiseSuppressor {
+    fn new(level: u8) -> Self {
+        let mut planner = RealFftPlanner::<f32>::new();
+        let window = (0..FFT_LEN)
+            .map(|n| {
+                (0.5 - 0.5 * (2.0 * std::f32::consts::PI * n as f32 / FFT_LEN as f32).cos()).sqrt()
+            })
+            .collect();
+        let mut suppressor = Self {
+            fft: planner.plan_fft_forward(FFT_LEN),
+            ifft: planner.plan_fft_inverse(FFT_LEN),
+            window,
+            input: vec![0.0; FFT_LEN],
+            overlap: vec![0.0; HOP],
+            noise: vec![0.0; BINS],
+            prev_gain: vec![1.0; BINS],
+            prev_snr: vec![1.0; BINS],
+            hops: 0,
+            min_gain: 1.0,
+        };
+        suppressor.set_level(level);
+        suppressor
+    }
+
+    fn set_level(&mut self, level: u8) {
+        let attenuation_db = NS_ATTENUATION_DB[level.min(3) as usize];
+        self.min_gain = 10f32.powf(-attenuation_db / 20.0);
+    }
+
+    // Filters one hop of samples; the output lags the input by one hop
+    fn process_hop(&mut self, hop: &[f32], speech: bool) -> Vec<f32> {
+        self.input.copy_within(HOP.., 0);
+        self.input[FFT_LEN - HOP..].copy_from_slice(hop);
+
+        let mut frame: Vec<f32> = self
+            .input
+            .iter()
+            .zip(&self.window)
+            .map(|(sample, w)| sample * w)
+            .collect();
+        let mut spectrum = self.fft.make_output_vec();
+        self.fft.process(&mut frame, &mut spectrum).unwrap();
+
+        let init_rate = (self.hops < NOISE_INIT_HOPS).then(|| 1.0 / (self.hops + 1) as f32);
+        self.hops += 1;
+
+        for (k, bin) in spectrum.iter_mut().enumerate() {
+            let power = bin.norm_sqr();
+            // Learn the noise quickly at the start, when it falls and in
+            // pauses. Otherwise it may only creep up, so speech, and held
+            // vowels the VAD missed, aren't taken for noise.
+            let noise = &mut self.noise[k];
+            *noise = match init_rate {
+                Some(rate) => *noise + rate * (power - *noise),
+                None if power < *noise || (!speech && power < NOISE_JUMP * *noise) => {
+                    *noise + 0.1 * (power - *noise)
+                }
+                None => (*noise + 0.01 * (power - *noise)).min(*noise * NOISE_CREEP),
+            };
+            let noise = self.noise[k].max(1e-12);
+
+            let post_snr = power / noise;
+            let prio_snr = DD_ALPHA * self.prev_gain[k].powi(2) * self.prev_snr[k]
+                + (1.0 - DD_ALPHA) * (post_snr - 1.0).max(0.0);
+            let gain = (prio_snr / (1.0 + prio_snr)).max(self.min_gain);
+
+            self.prev_gain[k] = gain;
+            self.prev_snr[k] = post_snr;
+            *bin *= gain;
+        }
+        // The inverse transform expects purely real DC and Nyquist bins
+        spectrum[0].im = 0.0;
+        spectrum[BINS - 1] = Complex::new(spectrum[BINS - 1].re, 0.0);
+
+        self.ifft.process(&mut spectrum, &mut frame).unwrap();
+        let scale = 1.0 / FFT_LEN as f32;
+        let output: Vec<f32> = (0..HOP)
+            .map(|n| self.overlap[n] + frame[n] * self.window[n] * scale)
+            .collect();
+        for n in 0..HOP {
+            self.overlap[n] = frame[HOP + n] * self.window[HOP + n] * scale;
+        }
+        output
+    }
+}
+
+// Energy-based voice activity detection on 20 ms frames, against a noise
+// floor that follows the quietest recent frames
+#[derive(Clone)]
+struct VoiceActivityDetector {
+    margin_db: f32,
+    hangover_frames: usize,
+    noise_db: Option<f32>,
+    hangover: usize,
+    // DC-blocking high-pass state, so hum and offsets don't read as speech
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl VoiceActivityDetector {
+    fn new(level: u8) -> Self {
+        let mut vad = Self {
+            margin_db: 0.0,
+            hangover_frames: 0,
+            noise_db: None,
+            hangover: 0,
+            prev_in: 0.0,
+            prev_out: 0.0,
+        };
+        vad.set_level(level);
+        vad
+    }
+
+    fn set_level(&mut self, level: u8) {
+        self.margin_db = VAD_MARGIN_DB[level.min(3) as usize];
+        self.hangover_frames = VAD_HANGOVER_FRAMES[level.min(3) as usize];
+    }
+
+    fn classify(&mut self, frame: &[f32]) -> bool {
+        let mut energy = 0.0;
+        for &sample in frame {
+            let out = sample - self.prev_in + 0.995 * self.prev_out;
+            self.prev_in = sample;
+            self.prev_out = out;
+            energy += out * out;
+        }
+        let energy_db = 10.0 * (energy / frame.len() as f32 + 1e-10).log10();
+
+        let noise_db = *self.noise_db.get_or_insert(energy_db);
+        let active = energy_db > noise_db + self.margin_db && energy_db > MIN_SPEECH_DBFS;
+
+        // Fall quickly to quieter frames, rise slowly (0.5 dB/s) otherwise
+        self.noise_db = Some(if energy_db < noise_db {
+            noise_db + 0.3 * (energy_db - noise_db)
+        } else {
+            noise_db + 0.01
+        });
+
+        if active {
+            self.hangover = self.hangover_frames;
+            true
+        } else if self.hangover > 0 {
+            self.hangover -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Detects the child talking over the bear: speech the echo canceller can't
+// explain as the bear's own voice, lasting long enough not to be a glitch
+#[derive(Clone)]
+struct BargeInDetector {
+    excess_db: f32,
+    frames_needed: usize,
+    frames: usize,
+}
+
+impl BargeInDetector {
+    fn new(level: u8) -> Self {
+        let mut detector = Self {
+            excess_db: 0.0,
+            frames_needed: 0,
+            frames: 0,
+        };
+        detector.set_level(level);
+        detector
+    }
+
+    fn set_level(&mut self, level: u8) {
+        self.excess_db = BARGE_IN_EXCESS_DB[level.min(3) as usize];
+        self.frames_needed = BARGE_IN_FRAMES[level.min(3) as usize];
+    }
+
+    // True only on the frame that completes a run of barge-in frames
+    fn update(&mut self, speech: bool, excess_residual_db: Option<f32>) -> bool {
+        match excess_residual_db {
+            Some(excess) if speech && excess >= self.excess_db => {
+                self.frames += 1;
+                self.frames == self.frames_needed
+            }
+            _ => {
+                self.frames = 0;
+                false
+            }
+        }
+    }
+}
+
+// Echo cancellation, noise suppression and voice activity detection for the
+// 16 kHz mono mic stream, in 20 ms frames like WebRTC's audio processing module
+#[derive(Clone)]
+pub struct AudioProcessor {
+    enable_aec: bool,
+    enable_ns: bool,
+    enable_vad: bool,
+    echo: EchoCanceller,
+    // Played samples waiting for the mic audio they echo into
+    reference: VecDeque<f32>,
+    ns: NoiseSuppressor,
+    vad: VoiceActivityDetector,
+    barge_in: BargeInDetector,
+    // Samples waiting for a full frame
+    pending: Vec<f32>,
+    speech: bool,
+    barge_in_detected: bool,
+}
+
+impl AudioProcessor {
+    pub fn new(enable_aec: bool, enable_ns: bool, enable_vad: bool) -> Self {
+        Self {
+            enable_aec,
+            enable_ns,
+            enable_vad,
+            echo: EchoCanceller::new(ECHO_TAPS),
+            reference: VecDeque::with_capacity(MAX_REFERENCE),
+            ns: NoiseSuppressor::new(3),
+            vad: VoiceActivityDetector::new(3),
+            barge_in: BargeInDetector::new(2),
+            pending: Vec::with_capacity(FRAME_SAMPLES_16K),
+            speech: false,
+            barge_in_detected: false,
+        }
+    }
+
+    // 0 (mild) to 3 (very high)
+    pub fn set_ns_level(&mut self, level: u8) {
+        self.ns.set_level(level);
+    }
+
+    // 0 (least) to 3 (most aggressive about rejecting non-speech)
+    pub fn set_vad_level(&mut self, level: u8) {
+        self.vad.set_level(level);
+    }
+
+    // 0 (never) to 3 (interrupts soonest, on the quietest speech). Needs
+    // echo cancellation.
+    pub fn set_barge_in_level(&mut self, level: u8) {
+        self.barge_in.set_level(level);
+    }
+
+    // Whether the last processed frame was speech. The mic path only needs
+    // the silenced frames and barge_in_detected; this is for checking the VAD.
+    #[cfg(test)]
+    pub fn speech_detected(&self) -> bool {
+        self.speech
+    }
+
+    // Whether the child started talking over the playback during the last
+    // call to process_stream
+    pub fn barge_in_detected(&self) -> bool {
+        self.barge_in_detected
+    }
+
+    // Takes the audio being played, as 16 kHz 16-bit little-endian samples,
+    // as the echo canceller's reference. Call it as the audio starts playing.
+    pub fn process_reverse_stream(&mut self, data: &[u8]) {
+        if !self.enable_aec {
+            return;
+        }
+        self.reference.extend(
+            data.chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0),
+        );
+        let excess = self.reference.len().saturating_sub(MAX_REFERENCE);
+        self.reference.drain(..excess);
+    }
+
+    // Lines the queued reference up with the mic, so each mic sample is
+    // matched with what was played as it was recorded. `lead` is how many
+    // samples after the newest mic sample the newest reference sample is
+    // heard, from the devices' timestamps, and `incoming` the mic samples
+    // about to be processed. Stale reference is dropped and missing
+    // reference is filled with silence. Returns the samples dropped, or
+    // filled in when negative.
+    pub fn align_reference(&mut self, lead: i64, incoming: usize) -> i64 {
+        if !self.enable_aec {
+            return 0;
+        }
+        let mic = (self.pending.len() + incoming) as i64;
+        let target = (mic + lead).clamp(0, MAX_REFERENCE as i64);
+        let shift = self.reference.len() as i64 - target;
+        if shift.unsigned_abs() as usize <= ALIGN_TOLERANCE {
+            return 0;
+        }
+        if shift > 0 {
+            self.reference.drain(..shift as usize);
+        } else {
+            for _ in shift..0 {
+                self.reference.push_front(0.0);
+            }
+        }
+        shift
+    }
+
+    // Processes 16-bit little-endian samples. Only whole frames are returned,
+    // the rest is kept for the next call. With VAD enabled, frames without
+    // speech are replaced by silence.
+    pub fn process_stream(&mut self, data: &[u8]) -> Vec<u8> {
+        if !self.enable_aec && !self.enable_ns && !self.enable_vad {
+            return data.to_vec();
+        }
+
+        self.pending.extend(
+            data.chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0),
+        );
+        let frames = self.pending.len() / FRAME_SAMPLES_16K;
+        self.barge_in_detected = false;
+        let mut output = Vec::with_capacity(frames * FRAME_SAMPLES_16K * 2);
+
+        for start in (0..frames * FRAME_SAMPLES_16K).step_by(FRAME_SAMPLES_16K) {
+            let mut frame = self.pending[start..start + FRAME_SAMPLES_16K].to_vec();
+            // Echo first, so neither the VAD nor the noise estimate hears the bear
+            if self.enable_aec {
+                let available = self.reference.len().min(FRAME_SAMPLES_16K);
+                let mut reference: Vec<f32> = self.reference.drain(..available).collect();
+                reference.resize(FRAME_SAMPLES_16K, 0.0);
+                self.echo.process(&mut frame, &reference);
+            }
+
+            // The detector always runs since the suppressor learns noise from it
+            self.speech = self.vad.classify(&frame);
+            if self.enable_aec {
+                self.barge_in_detected |= self
+                    .barge_in
+                    .update(self.speech, self.echo.excess_residual_db());
+            }
+
+            let mut processed = if self.enable_ns {
+                let mut processed = self.ns.process_hop(&frame[..HOP], self.speech);
+                processed.extend(self.ns.process_hop(&frame[HOP..], self.speech));
+                processed
+            } else {
+                frame
+            };
+            if self.enable_vad && !self.speech {
+                processed.fill(0.0);
+            }
+
+            for sample in processed {
+                let sample = (sample * 32768.0).round().clamp(-32768.0, 32767.0) as i16;
+                output.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        self.pending.drain(..frames * FRAME_SAMPLES_16K);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = SEND_SAMPLE_RATE as f32;
+
+    // Deterministic white noise with the given RMS
+    fn noise(len: usize, rms: f32, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                // Uniform in [-1, 1) has an RMS of 1/sqrt(3)
+                ((state >> 8) as f32 / (1 << 23) as f32 - 1.0) * rms * 3f32.sqrt()
+            })
+            .collect()
+    }
+
+    // Voiced-speech stand-in: a 150 Hz harmonic series with a syllable-rate
+    // envelope, scaled to the given RMS
+    fn speech(len: usize, rms: f32) -> Vec<f32> {
+        let raw: Vec<f32> = (0..len)
+            .map(|n| {
+                let t = n as f32 / RATE;
+                let envelope = 0.7 + 0.3 * (2.0 * std::f32::consts::PI * 3.0 * t).sin();
+                let voiced: f32 = (1..=20)
+                    .map(|h| (2.0 * std::f32::consts::PI * 150.0 * h as f32 * t).sin() / h as f32)
+                    .sum();
+                envelope * voiced
+            })
+            .collect();
+        let scale = rms / rms_of(&raw);
+        raw.iter().map(|sample| sample * scale).collect()
+    }
+
+    fn rms_of(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    fn db(ratio: f32) -> f32 {
+        20.0 * ratio.log10()
+    }
+
+    fn to_bytes(samples: &[f32]) -> Vec<u8> {
+        samples
+            .iter()
+            .flat_map(|s| ((s * 32768.0) as i16).to_le_bytes())
+            .collect()
+    }
+
+    fn to_samples(bytes: &[u8]) -> Vec<f32> {
+        bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect()
+    }
+
+    // One second of noise, then one second of speech at 10 dB SNR in the
+    // same noise
+    fn noisy_speech() -> (Vec<f32>, usize) {
+        let second = SEND_SAMPLE_RATE as usize;
+        let mut signal = noise(2 * second, 0.01, 7);
+        for (sample, clean) in signal[second..].iter_mut().zip(speech(second, 0.0316)) {
+            *sample += clean;
+        }
+        (signal, second)
+    }
+
+    #[test]
+    fn passes_audio_through_when_disabled() {
+        let mut processor = AudioProcessor::new(false, false, false);
+        let bytes = to_bytes(&noise(500, 0.1, 1));
+        assert_eq!(processor.process_stream(&bytes), bytes);
+    }
+
+    #[test]
+    fn returns_whole_frames_and_keeps_the_rest() {
+        let mut processor = AudioProcessor::new(false, true, true);
+        let bytes = to_bytes(&noise(FRAME_SAMPLES_16K + 100, 0.01, 2));
+        assert_eq!(
+            processor.process_stream(&bytes).len(),
+            FRAME_SAMPLES_16K * 2
+        );
+        let rest = to_bytes(&noise(FRAME_SAMPLES_16K - 100, 0.01, 3));
+        assert_eq!(processor.process_stream(&rest).len(), FRAME_SAMPLES_16K * 2);
+    }
+
+    #[test]
+    fn vad_separates_speech_from_noise_frames() {
+        let (signal, second) = noisy_speech();
+        let mut processor = AudioProcessor::new(false, false, true);
+        let decisions: Vec<bool> = signal
+            .chunks_exact(FRAME_SAMPLES_16K)
+            .map(|frame| {
+                processor.process_stream(&to_bytes(frame));
+                processor.speech_detected()
+            })
+            .collect();
+
+        let split = second / FRAME_SAMPLES_16K;
+        let false_alarms = decisions[..split].iter().filter(|&&speech| speech).count();
+        let detected = decisions[split..].iter().filter(|&&speech| speech).count();
+        assert!(false_alarms <= split / 20, "{} false alarms", false_alarms);
+        assert!(
+            detected >= (decisions.len() - split) * 9 / 10,
+            "{} of {} speech frames detected",
+            detected,
+            decisions.len() - split
+        );
+    }
+
+    #[test]
+    fn vad_silences_noise_frames() {
+        let (signal, second) = noisy_speech();
+        let mut processor = AudioProcessor::new(false, false, true);
+        let output = to_samples(&processor.process_stream(&to_bytes(&signal)));
+        // Skip the first frames, which seed the noise floor
+        assert!(rms_of(&output[second / 4..second]) < 1e-4);
+        assert!(rms_of(&output[second..]) > 0.03);
+    }
+
+    #[test]
+    fn suppresses_noise_and_keeps_speech() {
+        let (signal, second) = noisy_speech();
+        let clean = speech(second, 0.0316);
+        let mut processor = AudioProcessor::new(false, true, false);
+        let output = to_samples(&processor.process_stream(&to_bytes(&signal)));
+
+        // Noise after the suppressor has settled, and speech away from the onset
+        let noise_reduction = db(rms_of(&output[second / 2..second]) / 0.01);
+        let speech_change =
+            db(rms_of(&output[second + second / 4..]) / rms_of(&clean[second / 4..]));
+        assert!(
+            noise_reduction < -12.0,
+            "noise reduced by {} dB",
+            noise_reduction
+        );
+        assert!(
+            speech_change.abs() < 2.0,
+            "speech changed by {} dB",
+            speech_change
+        );
+    }
+
+    #[test]
+    fn cancels_echo_of_the_reverse_stream() {
+        let second = SEND_SAMPLE_RATE as usize;
+        let played = noise(3 * second, 0.1, 13);
+        // 10 ms from the speaker to the mic, at half the volume
+        let mut mic = vec![0.0; 160];
+        mic.extend(played.iter().map(|sample| sample * 0.5));
+        mic.truncate(played.len());
+
+        let mut processor = AudioProcessor::new(true, false, false);
+        let mut output = Vec::new();
+        for (mic, played) in mic
+            .chunks(FRAME_SAMPLES_16K)
+            .zip(played.chunks(FRAME_SAMPLES_16K))
+        {
+            processor.process_reverse_stream(&to_bytes(played));
+            output.extend(to_samples(&processor.process_stream(&to_bytes(mic))));
+        }
+
+        let reduction = db(rms_of(&output[2 * second..]) / rms_of(&mic[2 * second..]));
+        assert!(reduction < -20.0, "echo reduced by {} dB", -reduction);
+    }
+
+    // Echo reduction in the last second of three, with the mic starting
+    // `backlog` samples after the playback, the newest reference sample heard
+    // `lead` samples after the newest mic sample, and the reference for
+    // `lost_frames` frames lost halfway through, as when its queue overflows
+    fn echo_reduction(backlog: usize, lead: usize, lost_frames: usize, align: bool) -> f32 {
+        let second = SEND_SAMPLE_RATE as usize;
+        let played = noise(backlog + 3 * second + lead, 0.1, 16);
+        let mic: Vec<f32> = (backlog..backlog + 3 * second)
+            .map(|k| k.checked_sub(160).map_or(0.0, |k| played[k] * 0.5))
+            .collect();
+
+        let mut processor = AudioProcessor::new(true, false, false);
+        let mut delivered = 0;
+        let mut output = Vec::new();
+        for (idx, frame) in mic.chunks(FRAME_SAMPLES_16K).enumerate() {
+            let newest = backlog + (idx + 1) * FRAME_SAMPLES_16K + lead;
+            if !(75..75 + lost_frames).contains(&idx) {
+                processor.process_reverse_stream(&to_bytes(&played[delivered..newest]));
+            }
+            delivered = newest;
+            if align {
+                processor.align_reference(lead as i64, frame.len());
+            }
+            output.extend(to_samples(&processor.process_stream(&to_bytes(frame))));
+        }
+        db(rms_of(&output[2 * second..]) / rms_of(&mic[2 * second..]))
+    }
+
+    #[test]
+    fn drops_the_reference_backlog_from_before_the_mic_started() {
+        let half_second = SEND_SAMPLE_RATE as usize / 2;
+        let unaligned = echo_reduction(half_second, 480, 0, false);
+        assert!(unaligned > -6.0, "echo reduced by {} dB", -unaligned);
+        let aligned = echo_reduction(half_second, 480, 0, true);
+        assert!(aligned < -20.0, "echo reduced by {} dB", -aligned);
+    }
+
+    #[test]
+    fn realigns_after_reference_is_lost() {
+        let unaligned = echo_reduction(0, 480, 5, false);
+        assert!(unaligned > -6.0, "echo reduced by {} dB", -unaligned);
+        let aligned = echo_reduction(0, 480, 5, true);
+        assert!(aligned < -20.0, "echo reduced by {} dB", -aligned);
+    }
+
+    #[test]
+    fn leaves_small_offsets_to_the_echo_filter() {
+        let mut processor = AudioProcessor::new(true, false, false);
+        processor.process_reverse_stream(&to_bytes(&[0.1; 800]));
+        // 800 queued against 320 + 400 wanted is within the tolerance
+        assert_eq!(processor.align_reference(400, 320), 0);
+        assert_eq!(processor.align_reference(0, 320), 480);
+        assert_eq!(processor.reference.len(), 320);
+        assert_eq!(processor.align_reference(800, 320), -800);
+        assert_eq!(processor.reference.len(), 1_120);
+        assert_eq!(processor.reference[0], 0.0);
+    }
+
+    // Three seconds of the bear talking, with the child joining in for the
+    // last second; returns the frames where barge-in was detected
+    fn barge_in_frames(level: u8) -> Vec<usize> {
+        let second = SEND_SAMPLE_RATE as usize;
+        let played = speech(3 * second, 0.1);
+        let mut mic = vec![0.0; 160];
+        mic.extend(played.iter().map(|sample| sample * 0.5));
+        mic.truncate(played.len());
+        for (sample, noise) in mic.iter_mut().zip(noise(3 * second, 0.001, 14)) {
+            *sample += noise;
+        }
+        for (sample, child) in mic[2 * second..].iter_mut().zip(noise(second, 0.03, 15)) {
+            *sample += child;
+        }
+
+        let mut processor = AudioProcessor::new(true, true, true);
+        processor.set_barge_in_level(level);
+        mic.chunks(FRAME_SAMPLES_16K)
+            .zip(played.chunks(FRAME_SAMPLES_16K))
+            .enumerate()
+            .filter_map(|(idx, (mic, played))| {
+                processor.process_reverse_stream(&to_bytes(played));
+                processor.process_stream(&to_bytes(mic));
+                processor.barge_in_detected().then_some(idx)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detects_the_child_talking_over_the_bear() {
+        let frames = barge_in_frames(2);
+        // Once, within 300 ms of the child starting at frame 100
+        assert_eq!(frames.len(), 1, "detected at frames {:?}", frames);
+        assert!(
+            (100..115).contains(&frames[0]),
+            "detected at frame {}",
+            frames[0]
+        );
+    }
+
+    #[test]
+    fn barge_in_level_0_never_interrupts() {
+        assert!(barge_in_frames(0).is_empty());
+    }
+
+    #[test]
+    fn higher_levels_suppress_more() {
+        let second = SEND_SAMPLE_RATE as usize;
+        let signal = to_bytes(&noise(second, 0.01, 11));
+        let residual = |level| {
+            let mut processor = AudioProcessor::new(false, true, false);
+            processor.set_ns_level(level);
+            let output = to_samples(&processor.process_stream(&signal));
+            rms_of(&output[second / 2..])
+        };
+        assert!(residual(0) > residual(1));
+        assert!(residual(1) > residual(3));
+    }
+}
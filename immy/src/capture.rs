@@ -0,0 +1,182 @@
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, Sample, SampleFormat, SizedSample, StreamConfig};
+
+use crate::device::{choose_config, print_supported, DeviceError, StreamThread};
+use crate::resampler::Resampler;
+use crate::SEND_SAMPLE_RATE;
+
+// The default input device, recorded at whatever rate, format and channel
+// count it supports and converted to the 16 kHz mono that Gemini expects
+pub struct Capture {
+    _thread: StreamThread,
+}
+
+impl Capture {
+    // Hands each stretch of converted audio to `on_audio`, on the audio
+    // thread, with when the last of it was recorded
+    pub fn start<F>(on_audio: F) -> Result<Self, DeviceError>
+    where
+        F: FnMut(&[i16], Instant) + Send + 'static,
+    {
+        let (thread, ()) = StreamThread::spawn(move || Ok((open_stream(on_audio)?, ())))?;
+        Ok(Self { _thread: thread })
+    }
+}
+
+fn open_stream<F>(on_audio: F) -> Result<cpal::Stream, DeviceError>
+where
+    F: FnMut(&[i16], Instant) + Send + 'static,
+{
+    let host = cpal::default_host();
+    let device = host
+        .default_input_device()
+        .ok_or("No input device available")?;
+    println!("Using input device: {}", device.name()?);
+
+    let supported: Vec<_> = device.supported_input_configs()?.collect();
+    print_supported("input", &supported);
+    let supported = choose_config(
+        &supported,
+        device.default_input_config().ok(),
+        SEND_SAMPLE_RATE,
+    )
+    .ok_or("Input device has no usable config")?;
+    let format = supported.sample_format();
+    let config: StreamConfig = supported.into();
+    println!(
+        "Input format: {:?}, {} channel(s) at {} Hz",
+        format, config.channels, config.sample_rate.0
+    );
+
+    let input = Input {
+        channels: config.channels as usize,
+        resampler: Resampler::new(config.sample_rate.0, SEND_SAMPLE_RATE),
+        on_audio,
+    };
+    let stream = match format {
+        SampleFormat::F32 => build::<f32, F>(&device, &config, input)?,
+        SampleFormat::I16 => build::<i16, F>(&device, &config, input)?,
+        SampleFormat::I32 => build::<i32, F>(&device, &config, input)?,
+        SampleFormat::F64 => build::<f64, F>(&device, &config, input)?,
+        SampleFormat::U16 => build::<u16, F>(&device, &config, input)?,
+        SampleFormat::I8 => build::<i8, F>(&device, &config, input)?,
+        SampleFormat::U8 => build::<u8, F>(&device, &config, input)?,
+        SampleFormat::U32 => build::<u32, F>(&device, &config, input)?,
+        format => return Err(format!("Unsupported input format {:?}", format).into()),
+    };
+    stream.play()?;
+    Ok(stream)
+}
+
+fn build<T, F>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut input: Input<F>,
+) -> Result<cpal::Stream, DeviceError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+    F: FnMut(&[i16], Instant) + Send + 'static,
+{
+    let rate = config.sample_rate.0 as f64;
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], info: &cpal::InputCallbackInfo| {
+            let samples = input.convert(data);
+            if !samples.is_empty() {
+                // When the last sample in this buffer was recorded
+                let timestamp = info.timestamp();
+                let latency = timestamp
+                    .callback
+                    .duration_since(&timestamp.capture)
+                    .unwrap_or_default();
+                let frames = data.len() / input.channels;
+                let now = Instant::now();
+                let recorded_at = now.checked_sub(latency).unwrap_or(now)
+                    + Duration::from_secs_f64(frames as f64 / rate);
+                (input.on_audio)(&samples, recorded_at);
+            }
+        },
+        |err| eprintln!("An error occurred on the input stream: {}", err),
+        None,
+    )?;
+    Ok(stream)
+}
+
+// State of the input callback
+struct Input<F> {
+    channels: usize,
+    resampler: Resampler,
+    on_audio: F,
+}
+
+impl<F> Input<F> {
+    // Downmixes a device buffer and resamples it to 16 kHz
+    fn convert<T>(&mut self, data: &[T]) -> Vec<i16>
+    where
+        T: Sample,
+        f32: FromSample<T>,
+    {
+        let mono = downmix(data, self.channels);
+        self.resampler
+            .process(&mono)
+            .into_iter()
+            .map(i16::from_sample)
+            .collect()
+    }
+}
+
+// Averages the channels of each frame
+fn downmix<T>(data: &[T], channels: usize) -> Vec<f32>
+where
+    T: Sample,
+    f32: FromSample<T>,
+{
+    data.chunks(channels)
+        .map(|frame| {
+            frame
+                .iter()
+                .map(|&sample| f32::from_sample(sample))
+                .sum::<f32>()
+                / frame.len() as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_channels_into_mono() {
+        assert_eq!(downmix(&[0.5f32, -0.5, 1.0, 0.0], 2), [0.0, 0.5]);
+        assert_eq!(downmix(&[i16::MIN, i16::MIN, 0, 0], 2), [-1.0, 0.0]);
+    }
+
+    #[test]
+    fn converts_stereo_48k_to_16k_mono_i16() {
+        let mut input = Input {
+            channels: 2,
+            resampler: Resampler::new(48_000, 16_000),
+            on_audio: |_: &[i16], _: Instant| {},
+        };
+        // A full-scale stereo signal, one channel inverted, cancels out
+        let stereo: Vec<f32> = (0..4_800)
+            .flat_map(|n| {
+                let sample = (n as f32 * 0.05).sin();
+                [sample, -sample]
+            })
+            .collect();
+        let samples = input.convert(&stereo);
+        assert!((1_550..=1_600).contains(&samples.len()));
+        assert!(samples.iter().all(|&sample| sample == 0));
+
+        let dc = vec![0.25f32; 9_600];
+        let samples = input.convert(&dc);
+        assert!(samples[200..]
+            .iter()
+            .all(|&sample| (sample - 8_192).abs() <= 2));
+    }
+}
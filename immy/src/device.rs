@@ -0,0 +1,206 @@
+// What the capture and playback streams share: choosing a stream config the
+// device actually supports, and keeping a stream alive on its own thread.
+
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use cpal::{SampleFormat, SampleRate, SupportedStreamConfig, SupportedStreamConfigRange};
+
+pub type DeviceError = Box<dyn Error + Send + Sync>;
+
+// Sample formats the streams convert from and to, most preferred first
+pub const SAMPLE_FORMATS: [SampleFormat; 8] = [
+    SampleFormat::F32,
+    SampleFormat::I16,
+    SampleFormat::I32,
+    SampleFormat::F64,
+    SampleFormat::U16,
+    SampleFormat::I8,
+    SampleFormat::U8,
+    SampleFormat::U32,
+];
+
+fn format_rank(format: SampleFormat) -> Option<usize> {
+    SAMPLE_FORMATS.iter().position(|&handled| handled == format)
+}
+
+// Picks from what the device supports: `rate` natively if it can, so nothing
+// needs resampling, then its default config, then anything usable at its
+// highest rate. Fewer channels and better sample formats win ties.
+pub fn choose_config(
+    supported: &[SupportedStreamConfigRange],
+    default: Option<SupportedStreamConfig>,
+    rate: u32,
+) -> Option<SupportedStreamConfig> {
+    let key = |range: &&SupportedStreamConfigRange| {
+        (range.channels(), format_rank(range.sample_format()))
+    };
+    let usable = || {
+        supported
+            .iter()
+            .filter(|range| format_rank(range.sample_format()).is_some())
+    };
+
+    if let Some(range) = usable()
+        .filter(|range| range.min_sample_rate().0 <= rate && range.max_sample_rate().0 >= rate)
+        .min_by_key(key)
+    {
+        return Some((*range).with_sample_rate(SampleRate(rate)));
+    }
+    if let Some(default) = default.filter(|config| format_rank(config.sample_format()).is_some()) {
+        return Some(default);
+    }
+    usable()
+        .min_by_key(key)
+        .map(|range| (*range).with_max_sample_rate())
+}
+
+// Lists what the device can do, to see why a config was chosen
+pub fn print_supported(direction: &str, supported: &[SupportedStreamConfigRange]) {
+    println!("Supported {} configs:", direction);
+    for range in supported {
+        println!(
+            "  {:?}, {} channel(s) at {}-{} Hz",
+            range.sample_format(),
+            range.channels(),
+            range.min_sample_rate().0,
+            range.max_sample_rate().0
+        );
+    }
+}
+
+// Owns a thread holding a cpal stream, which can't move between threads.
+// The stream stops when this is dropped.
+pub struct StreamThread {
+    stop: Arc<AtomicBool>,
+}
+
+impl StreamThread {
+    // Runs `open` on a new thread and waits for it; whatever it returns
+    // alongside the stream comes back here
+    pub fn spawn<F, R>(open: F) -> Result<(Self, R), DeviceError>
+    where
+        F: FnOnce() -> Result<(cpal::Stream, R), DeviceError> + Send + 'static,
+        R: Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready_tx, ready_rx) = std_mpsc::channel();
+
+        let thread_stop = stop.clone();
+        thread::spawn(move || {
+            let stream = match open() {
+                Ok((stream, result)) => {
+                    let _ = ready_tx.send(Ok(result));
+                    stream
+                }
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err.to_string()));
+                    return;
+                }
+            };
+            // The stream plays for as long as it is alive
+            while !thread_stop.load(Ordering::Relaxed) {
+                thread::park_timeout(Duration::from_millis(100));
+            }
+            drop(stream);
+        });
+
+        let result = ready_rx.recv()??;
+        Ok((Self { stop }, result))
+    }
+}
+
+impl Drop for StreamThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SupportedBufferSize;
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    fn default_config(
+        channels: u16,
+        rate: u32,
+        format: SampleFormat,
+    ) -> Option<SupportedStreamConfig> {
+        Some(SupportedStreamConfig::new(
+            channels,
+            SampleRate(rate),
+            SupportedBufferSize::Unknown,
+            format,
+        ))
+    }
+
+    #[test]
+    fn prefers_the_wanted_rate_with_fewest_channels() {
+        let supported = [
+            range(2, 8_000, 48_000, SampleFormat::F32),
+            range(1, 8_000, 48_000, SampleFormat::I16),
+            range(1, 8_000, 48_000, SampleFormat::F32),
+        ];
+        let config = choose_config(
+            &supported,
+            default_config(2, 48_000, SampleFormat::F32),
+            16_000,
+        )
+        .unwrap();
+        assert_eq!(config.channels(), 1);
+        assert_eq!(config.sample_rate(), SampleRate(16_000));
+        assert_eq!(config.sample_format(), SampleFormat::F32);
+    }
+
+    #[test]
+    fn falls_back_to_the_device_default() {
+        let supported = [
+            range(2, 44_100, 44_100, SampleFormat::I16),
+            range(2, 48_000, 48_000, SampleFormat::I16),
+        ];
+        let config = choose_config(
+            &supported,
+            default_config(2, 48_000, SampleFormat::I16),
+            16_000,
+        )
+        .unwrap();
+        assert_eq!(config.sample_rate(), SampleRate(48_000));
+        assert_eq!(config.channels(), 2);
+    }
+
+    #[test]
+    fn skips_formats_it_cannot_convert() {
+        let supported = [
+            range(1, 8_000, 48_000, SampleFormat::I64),
+            range(2, 48_000, 96_000, SampleFormat::I32),
+        ];
+        let config = choose_config(
+            &supported,
+            default_config(1, 48_000, SampleFormat::U64),
+            24_000,
+        )
+        .unwrap();
+        assert_eq!(config.sample_format(), SampleFormat::I32);
+        assert_eq!(config.sample_rate(), SampleRate(96_000));
+        assert!(choose_config(&supported[..1], None, 24_000).is_none());
+    }
+}
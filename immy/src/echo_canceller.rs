@@ -0,0 +1,311 @@
+// Acoustic echo cancellation with a normalized LMS adaptive filter. The
+// filter learns the path from the speaker to the mic, using what the bear
+// plays as the reference, and subtracts the predicted echo from the mic.
+
+// NLMS step size; larger converges faster but leaves more residual echo
+const STEP: f32 = 0.5;
+// Keeps the normalization finite while the reference is silent
+const REGULARIZATION: f32 = 1e-3;
+// After convergence, a frame whose residual is this many times above the
+// usual share of the mic energy means the child is talking over the bear.
+// The filter doesn't adapt then, or it would learn to cancel their voice.
+const DOUBLE_TALK_FACTOR: f32 = 8.0;
+// Energy reduction that counts as converged (6 dB)
+const CONVERGED_RATIO: f32 = 0.25;
+// Frames without adapting, while the bear plays, after which the echo path
+// is assumed to have changed (the bear was moved) and is learned again
+const MAX_FROZEN_FRAMES: usize = 100;
+
+#[derive(Clone)]
+pub struct EchoCanceller {
+    weights: Vec<f32>,
+    // Reference history, written twice so the last `taps` samples are always
+    // one contiguous slice
+    history: Vec<f32>,
+    pos: usize,
+    // Energy of the reference samples under the filter
+    energy: f32,
+    // Samples since the reference was last non-silent
+    idle: usize,
+    converged: bool,
+    // Usual residual-to-mic energy ratio once converged
+    residual_ratio: f32,
+    frozen_frames: usize,
+    // How far the last frame's residual was above the usual, in dB
+    excess_residual_db: Option<f32>,
+}
+
+impl EchoCanceller {
+    // `taps` must cover the playback latency plus the room's echo tail
+    pub fn new(taps: usize) -> Self {
+        Self {
+            weights: vec![0.0; taps],
+            history: vec![0.0; taps * 2],
+            pos: 0,
+            energy: 0.0,
+            idle: usize::MAX,
+            converged: false,
+            residual_ratio: 1.0,
+            frozen_frames: 0,
+            excess_residual_db: None,
+        }
+    }
+
+    fn taps(&self) -> usize {
+        self.weights.len()
+    }
+
+    fn push_reference(&mut self, sample: f32) {
+        let taps = self.taps();
+        let oldest = self.history[self.pos];
+        self.energy = (self.energy + sample * sample - oldest * oldest).max(0.0);
+        self.history[self.pos] = sample;
+        self.history[self.pos + taps] = sample;
+        self.pos = (self.pos + 1) % taps;
+        if self.pos == 0 {
+            // Recomputed once per pass over the history so rounding errors
+            // from the running update don't build up
+            self.energy = self.window().iter().map(|x| x * x).sum();
+        }
+        self.idle = if sample == 0.0 {
+            self.idle.saturating_add(1)
+        } else {
+            0
+        };
+    }
+
+    // The last `taps` reference samples, oldest first
+    fn window(&self) -> &[f32] {
+        &self.history[self.pos..self.pos + self.taps()]
+    }
+
+    fn estimate(&self) -> f32 {
+        self.window()
+            .iter()
+            .zip(&self.weights)
+            .map(|(x, w)| x * w)
+            .sum()
+    }
+
+    // How much more of the last frame was left after cancelling than usual,
+    // in dB. Near 0 while only the bear plays, well above it when someone
+    // talks over the bear. None without playback or before convergence.
+    pub fn excess_residual_db(&self) -> Option<f32> {
+        self.excess_residual_db
+    }
+
+    // Removes the echo of `reference` from `mic` in place. Both hold the same
+    // span of time, sample for sample.
+    pub fn process(&mut self, mic: &mut [f32], reference: &[f32]) {
+        let taps = self.taps();
+        let active = reference.iter().any(|&sample| sample != 0.0) || self.idle < taps;
+        if !active {
+            self.excess_residual_db = None;
+            // Nothing played recently, so there is no echo to remove
+            for &sample in reference {
+                self.push_reference(sample);
+            }
+            return;
+        }
+
+        // First pass with the filter frozen, to decide whether it may adapt
+        let saved = (self.history.clone(), self.pos, self.energy, self.idle);
+        let mut mic_energy = 0.0;
+        let mut error_energy = 0.0;
+        for (&sample, &reference) in mic.iter().zip(reference) {
+            self.push_reference(reference);
+            let error = sample - self.estimate();
+            mic_energy += sample * sample;
+            error_energy += error * error;
+        }
+        (self.history, self.pos, self.energy, self.idle) = saved;
+        let ratio = if mic_energy > 0.0 {
+            error_energy / mic_energy
+        } else {
+            1.0
+        };
+        self.excess_residual_db = self
+            .converged
+            .then(|| 10.0 * (ratio / self.residual_ratio.max(1e-6)).log10());
+        let adapt = if !self.converged {
+            if ratio < CONVERGED_RATIO {
+                self.converged = true;
+                self.residual_ratio = ratio;
+            }
+            true
+        } else if ratio < DOUBLE_TALK_FACTOR * self.residual_ratio {
+            self.residual_ratio = 0.9 * self.residual_ratio + 0.1 * ratio;
+            self.frozen_frames = 0;
+            true
+        } else {
+            self.frozen_frames += 1;
+            if self.frozen_frames > MAX_FROZEN_FRAMES {
+                self.converged = false;
+                self.frozen_frames = 0;
+            }
+            false
+        };
+
+        // Second pass: cancel, adapting sample by sample
+        for (sample, &reference) in mic.iter_mut().zip(reference) {
+            self.push_reference(reference);
+            let error = *sample - self.estimate();
+            if adapt {
+                let step = STEP * error / (self.energy + REGULARIZATION);
+                let (history, pos) = (&self.history, self.pos);
+                for (weight, x) in self.weights.iter_mut().zip(&history[pos..pos + taps]) {
+                    *weight += step * x;
+                }
+            }
+            *sample = error;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FRAME_SAMPLES_16K;
+
+    fn noise(len: usize, rms: f32, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                ((state >> 8) as f32 / (1 << 23) as f32 - 1.0) * rms * 3f32.sqrt()
+            })
+            .collect()
+    }
+
+    // Speaker-to-mic path: a bulk delay, then a decaying room response
+    fn echo_path(delay: usize, gain: f32, seed: u32) -> Vec<f32> {
+        let mut path = vec![0.0; delay];
+        let tail = noise(64, 1.0, seed);
+        path.extend(
+            tail.iter()
+                .enumerate()
+                .map(|(n, x)| gain * x * (-(n as f32) / 12.0).exp()),
+        );
+        path
+    }
+
+    fn convolve(signal: &[f32], path: &[f32]) -> Vec<f32> {
+        (0..signal.len())
+            .map(|n| {
+                path.iter()
+                    .enumerate()
+                    .take_while(|(k, _)| *k <= n)
+                    .map(|(k, h)| h * signal[n - k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum()
+    }
+
+    fn db(ratio: f32) -> f32 {
+        10.0 * ratio.log10()
+    }
+
+    // Runs the canceller over whole frames and returns its output
+    fn cancel(canceller: &mut EchoCanceller, mic: &[f32], reference: &[f32]) -> Vec<f32> {
+        let mut output = mic.to_vec();
+        for (frame, reference) in output
+            .chunks_mut(FRAME_SAMPLES_16K)
+            .zip(reference.chunks(FRAME_SAMPLES_16K))
+        {
+            canceller.process(frame, reference);
+        }
+        output
+    }
+
+    #[test]
+    fn removes_echo_once_converged() {
+        let reference = noise(32_000, 0.1, 1);
+        let mic = convolve(&reference, &echo_path(40, 0.8, 2));
+        let output = cancel(&mut EchoCanceller::new(256), &mic, &reference);
+
+        let erle = db(energy(&mic[24_000..]) / energy(&output[24_000..]));
+        assert!(erle > 25.0, "echo reduced by {} dB", erle);
+    }
+
+    #[test]
+    fn keeps_the_child_talking_over_the_bear() {
+        let reference = noise(48_000, 0.1, 3);
+        let echo = convolve(&reference, &echo_path(80, 0.5, 4));
+        // The child starts talking after the filter has converged
+        let child: Vec<f32> = (0..48_000)
+            .map(|n| {
+                if n < 24_000 {
+                    0.0
+                } else {
+                    0.05 * (2.0 * std::f32::consts::PI * 220.0 * n as f32 / 16_000.0).sin()
+                }
+            })
+            .collect();
+        let mic: Vec<f32> = echo.iter().zip(&child).map(|(e, c)| e + c).collect();
+        let output = cancel(&mut EchoCanceller::new(256), &mic, &reference);
+
+        let residual: Vec<f32> = output[24_000..]
+            .iter()
+            .zip(&child[24_000..])
+            .map(|(o, c)| o - c)
+            .collect();
+        let erle = db(energy(&echo[24_000..]) / energy(&residual));
+        let child_change = db(energy(&output[24_000..]) / energy(&child[24_000..]));
+        assert!(erle > 20.0, "echo reduced by {} dB", erle);
+        assert!(
+            child_change.abs() < 1.0,
+            "child changed by {} dB",
+            child_change
+        );
+    }
+
+    #[test]
+    fn measures_the_child_over_the_residual() {
+        let reference = noise(48_000, 0.1, 9);
+        // A real mic always picks up some noise, which the filter can't cancel
+        let echo: Vec<f32> = convolve(&reference, &echo_path(40, 0.8, 10))
+            .iter()
+            .zip(noise(48_000, 0.001, 12))
+            .map(|(e, n)| e + n)
+            .collect();
+        let child = noise(16_000, 0.02, 11);
+        let mut canceller = EchoCanceller::new(256);
+        assert_eq!(canceller.excess_residual_db(), None);
+
+        cancel(&mut canceller, &echo[..32_000], &reference[..32_000]);
+        let excess = canceller.excess_residual_db().unwrap();
+        assert!(excess < 3.0, "bear alone: {} dB", excess);
+
+        let mic: Vec<f32> = echo[32_000..]
+            .iter()
+            .zip(&child)
+            .map(|(e, c)| e + c)
+            .collect();
+        cancel(&mut canceller, &mic[..1_600], &reference[32_000..33_600]);
+        let excess = canceller.excess_residual_db().unwrap();
+        assert!(excess > 10.0, "child over the bear: {} dB", excess);
+    }
+
+    #[test]
+    fn relearns_a_changed_echo_path() {
+        let reference = noise(96_000, 0.1, 5);
+        let mut mic = convolve(&reference[..32_000], &echo_path(40, 0.8, 6));
+        // The bear is moved: a longer, louder path
+        mic.extend(convolve(&reference, &echo_path(120, 1.2, 7))[32_000..].iter());
+        let output = cancel(&mut EchoCanceller::new(256), &mic, &reference);
+
+        let erle = db(energy(&mic[80_000..]) / energy(&output[80_000..]));
+        assert!(erle > 20.0, "echo reduced by {} dB", erle);
+    }
+
+    #[test]
+    fn passes_mic_through_without_playback() {
+        let mic = noise(3_200, 0.1, 8);
+        let output = cancel(&mut EchoCanceller::new(256), &mic, &vec![0.0; 3_200]);
+        assert_eq!(output, mic);
+    }
+}
@@ -0,0 +1,51 @@
+use std::error::Error;
+
+use google_generativeai::LiveSession as GeminiLiveSession;
+
+// One message from the model
+#[derive(Debug, Default)]
+pub struct ServerEvent {
+    pub audio: Option<Vec<u8>>,
+    pub text: Option<String>,
+    // The server stopped the reply because it heard the child cut in
+    pub interrupted: bool,
+    // The model finished its reply
+    pub turn_complete: bool,
+}
+
+// What the voice chat needs from a live model session
+pub trait LiveSession {
+    async fn send_audio(&mut self, audio: &[u8]) -> Result<(), Box<dyn Error>>;
+
+    // Tells the model the child cut in, once playback is already flushed.
+    // The reply only counts as over once `next_event` reports it.
+    async fn interrupt(&mut self) -> Result<(), Box<dyn Error>>;
+
+    // None once the session is closed
+    async fn next_event(&mut self) -> Option<ServerEvent>;
+}
+
+impl LiveSession for GeminiLiveSession {
+    async fn send_audio(&mut self, audio: &[u8]) -> Result<(), Box<dyn Error>> {
+        GeminiLiveSession::send_audio(self, audio).await?;
+        Ok(())
+    }
+
+    // The Live API has no client message for this. Its own VAD hears the
+    // child in the mic audio sent after barge-in, which includes the
+    // pre-roll, stops the reply and sends `interrupted`. Nothing to do here.
+    async fn interrupt(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn next_event(&mut self) -> Option<ServerEvent> {
+        let response = self.next_response().await?;
+        let content = response.server_content.unwrap_or_default();
+        Some(ServerEvent {
+            audio: response.audio,
+            text: response.text,
+            interrupted: content.interrupted.unwrap_or(false),
+            turn_complete: content.turn_complete.unwrap_or(false),
+        })
+    }
+}
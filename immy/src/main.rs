@@ -1,19 +1,36 @@
 use std::env;
 use std::error::Error;
 use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use dotenv::dotenv;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{BufferSize, SampleFormat, SampleRate, StreamConfig};
+use cpal::{Sample, SampleFormat};
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::time;
 use google_generativeai::{Client, ClientOptions, GenerativeModel, LiveConnectConfig, Modality, SpeechConfig, VoiceConfig, PrebuiltVoiceConfig, Content, Part};
 use tokio::spawn;
 use std::io::{self, Write};
 
+mod audio_processing;
+mod capture;
+mod device;
+mod echo_canceller;
+mod live;
+mod playback;
+mod pre_roll;
+mod resampler;
+
+use audio_processing::AudioProcessor;
+use capture::Capture;
+use device::DeviceError;
+use live::LiveSession;
+use playback::{Flush, Playback, PlaybackEnd, ReferenceClock};
+use pre_roll::PreRoll;
+use resampler::Resampler;
+use rtrb::Consumer;
+
 // Constants
 const FORMAT: SampleFormat = SampleFormat::I16;
-const CHANNELS: u16 = 1; // Mono
 const SEND_SAMPLE_RATE: u32 = 16000;
 const RECEIVE_SAMPLE_RATE: u32 = 24000;
 const FRAME_DURATION_MS: u64 = 20;
@@ -21,60 +38,35 @@ const FRAME_SAMPLES_16K: usize = (SEND_SAMPLE_RATE as usize * FRAME_DURATION_MS
 const FRAME_SIZE_BYTES_16K: usize = FRAME_SAMPLES_16K * 2; // 16-bit = 2 bytes per sample
 const FRAME_SAMPLES_OUTPUT: usize = (RECEIVE_SAMPLE_RATE as usize * FRAME_DURATION_MS as usize) / 1000;
 const FRAME_SIZE_BYTES_OUTPUT: usize = FRAME_SAMPLES_OUTPUT * 2;
+// How often mic frames dropped on the audio thread are reported
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+// Mic frames held back while the bear speaks and sent on barge-in, enough
+// to cover the slowest barge-in detection and the start of the word
+const PRE_ROLL_FRAMES: usize = 20;
 
 // Default system prompt
 const SYS_PROMPT: &str = "
 You are Immy, a magical, AI-powered teddy bear who loves chatting with children. You're warm, funny, and full of wonder, always ready to share a story, answer curious questions, or offer gentle advice.
 ";
 
-// Audio Processing struct (placeholder for WebRTC audio processing)
-struct AudioProcessor {
-    enable_ns: bool,
-    enable_vad: bool,
-    ns_level: u8,
-    vad_level: u8,
+// Processing level from the environment, 0 to 3
+fn env_level(name: &str, default: u8) -> u8 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+        .min(3)
 }
 
-impl AudioProcessor {
-    fn new(enable_ns: bool, enable_vad: bool) -> Self {
-        Self {
-            enable_ns,
-            enable_vad,
-            ns_level: 3,
-            vad_level: 3,
-        }
-    }
-
-    fn set_ns_level(&mut self, level: u8) {
-        self.ns_level = level;
-    }
-
-    fn set_vad_level(&mut self, level: u8) {
-        self.vad_level = level;
-    }
-
-    fn process_reverse_stream(&mut self, _data: &[u8]) {
-        // Placeholder for WebRTC audio processing
-        // In a real implementation, this would process the reference audio
-    }
-
-    fn process_stream(&mut self, data: &[u8]) -> Vec<u8> {
-        // Placeholder for WebRTC audio processing
-        // In a real implementation, this would apply noise suppression and VAD
-        data.to_vec()
-    }
-}
-
-// Helper function to convert stereo to mono
-fn stereo_to_mono(data: &[u8]) -> Vec<u8> {
-    let mut result = Vec::with_capacity(data.len() / 2);
-    for i in (0..data.len()).step_by(4) {
-        if i + 1 < data.len() {
-            result.push(data[i]);
-            result.push(data[i + 1]);
-        }
-    }
-    result
+// How many 16 kHz samples after `recorded_at` the reference heard at
+// `heard_at` is, negative if before
+fn lead_samples(heard_at: Instant, recorded_at: Instant) -> i64 {
+    let seconds = if heard_at >= recorded_at {
+        (heard_at - recorded_at).as_secs_f64()
+    } else {
+        -(recorded_at - heard_at).as_secs_f64()
+    };
+    (seconds * SEND_SAMPLE_RATE as f64).round() as i64
 }
 
 // Text-to-speech announcement function
@@ -90,9 +82,14 @@ struct GeminiVoiceChat {
     system_prompt: String,
     audio_in_tx: Option<Sender<Vec<u8>>>,
     audio_out_tx: Option<Sender<Vec<u8>>>,
-    last_playback_end: Arc<Mutex<Instant>>,
-    playback_cooldown: Duration,
-    audio_processor: Option<AudioProcessor>,
+    last_playback_end: PlaybackEnd,
+    // Mic frames the audio thread couldn't queue for Gemini, reported from a task
+    mic_drops: Arc<AtomicU64>,
+    // Set when the child talks over the bear. The rest of that reply is
+    // dropped, until the next reply starts.
+    interrupted: Arc<AtomicBool>,
+    // Used by the mic callback, with the played audio as the echo reference
+    audio_processor: Option<Arc<Mutex<AudioProcessor>>>,
 }
 
 impl GeminiVoiceChat {
@@ -102,23 +99,41 @@ impl GeminiVoiceChat {
             system_prompt: system_prompt.to_string(),
             audio_in_tx: None,
             audio_out_tx: None,
-            last_playback_end: Arc::new(Mutex::new(Instant::now() - Duration::from_secs(10))),
-            playback_cooldown: Duration::from_millis(300),
-            audio_processor: Some(AudioProcessor::new(true, true)),
+            last_playback_end: PlaybackEnd::new(),
+            mic_drops: Arc::new(AtomicU64::new(0)),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            audio_processor: Some(Arc::new(Mutex::new(Self::audio_processor()))),
         }
     }
 
+    // Echo cancellation, noise suppression, VAD and barge-in, with levels
+    // from NS_LEVEL, VAD_LEVEL and BARGE_IN_LEVEL
+    fn audio_processor() -> AudioProcessor {
+        let mut processor = AudioProcessor::new(true, true, true);
+        processor.set_ns_level(env_level("NS_LEVEL", 3));
+        processor.set_vad_level(env_level("VAD_LEVEL", 3));
+        processor.set_barge_in_level(env_level("BARGE_IN_LEVEL", 2));
+        processor
+    }
+
     async fn run(&mut self) -> Result<(), Box<dyn Error>> {
         // Set up audio channels
         let (audio_in_tx, audio_in_rx) = mpsc::channel::<Vec<u8>>(5);
-        let (audio_out_tx, audio_out_rx) = mpsc::channel::<Vec<u8>>(5);
+        let (audio_out_tx, mut audio_out_rx) = mpsc::channel::<Vec<u8>>(5);
+        let (interrupt_tx, mut interrupt_rx) = mpsc::channel::<()>(1);
         self.audio_in_tx = Some(audio_in_tx);
         self.audio_out_tx = Some(audio_out_tx);
 
+        // Playback opens first, so the mic knows the rate of what is played
+        let reference_clock = ReferenceClock::default();
+        let (playback, reference) = Playback::start(self.last_playback_end.clone(), reference_clock.clone())
+            .map_err(|err| err as Box<dyn Error>)?;
+        let reference_rate = playback.sample_rate();
+
         // Set up and run audio handling tasks
-        let mic_task = self.listen_mic_audio();
-        let reverse_task = self.listen_reverse_audio();
-        let playback_task = self.play_audio(audio_in_rx);
+        let capture = self.listen_mic_audio(reference, reference_rate, reference_clock, playback.flush_handle(), interrupt_tx)
+            .map_err(|err| err as Box<dyn Error>)?;
+        let playback_task = Self::play_audio(audio_in_rx, playback, self.interrupted.clone());
 
         // Set up Gemini client
         let use_vertexai = false;
@@ -149,183 +164,198 @@ impl GeminiVoiceChat {
             .with_live_connect_config(config);
 
         println!("Voice chat started. Speak into your microphone. Press Ctrl+C to quit.");
-        println!("Note: For reliable echo removal, use headphones or a proper loopback device.");
 
         // Start the audio tasks
-        let mic_handle = spawn(mic_task);
-        let reverse_handle = spawn(reverse_task);
         let playback_handle = spawn(playback_task);
+        let drops_handle = spawn(Self::report_mic_drops(self.mic_drops.clone()));
 
         // Set up stream handler for Gemini API
-        let mut session = model.start_live_session(&client).await?;
-        
-        // Process audio from mic_task and send to Gemini
-        let audio_out_tx_clone = self.audio_out_tx.clone().unwrap();
-        let audio_handler = spawn(async move {
-            let mut audio_out_rx = audio_out_rx;
-            while let Some(audio_data) = audio_out_rx.recv().await {
-                if let Err(e) = session.send_audio(&audio_data).await {
-                    eprintln!("Error sending audio to Gemini: {}", e);
-                }
-            }
-        });
-
-        // Process responses from Gemini
-        while let Some(response) = session.next_response().await {
-            if let Some(audio_data) = response.audio {
-                println!("Received {} bytes from Gemini", audio_data.len());
-                if let Some(tx) = &self.audio_in_tx {
-                    let _ = tx.send(audio_data).await;
-                }
-            }
-            if let Some(text) = response.text {
-                print!("Gemini: {}", text);
-                io::stdout().flush().unwrap();
-            }
-        }
+        let session = model.start_live_session(&client).await?;
+        self.converse(session, &mut audio_out_rx, &mut interrupt_rx).await;
 
         // Clean up
-        mic_handle.abort();
-        reverse_handle.abort();
+        drop(capture);
         playback_handle.abort();
-        audio_handler.abort();
+        drops_handle.abort();
 
         println!("Voice chat session ended.");
         Ok(())
     }
 
-    async fn listen_mic_audio(&self) -> Result<(), Box<dyn Error>> {
-        let host = cpal::default_host();
-        let device = host.default_input_device()
-            .ok_or("No input device available")?;
-        
-        println!("Using input device: {}", device.name()?);
-        
-        let config = StreamConfig {
-            channels: CHANNELS,
-            sample_rate: SampleRate(SEND_SAMPLE_RATE),
-            buffer_size: BufferSize::Fixed(FRAME_SAMPLES_16K as u32),
-        };
+    // One loop owns the session: mic audio and interruptions go out,
+    // responses come in, until the session closes
+    async fn converse<S: LiveSession>(
+        &self,
+        mut session: S,
+        audio_out_rx: &mut Receiver<Vec<u8>>,
+        interrupt_rx: &mut Receiver<()>,
+    ) {
+        // Whether a reply has started and the server hasn't ended it yet
+        let mut replying = false;
+        loop {
+            tokio::select! {
+                Some(audio_data) = audio_out_rx.recv() => {
+                    if let Err(e) = session.send_audio(&audio_data).await {
+                        eprintln!("Error sending audio to Gemini: {}", e);
+                    }
+                }
+                Some(()) = interrupt_rx.recv() => {
+                    println!("Child interrupted the bear");
+                    if let Err(e) = session.interrupt().await {
+                        eprintln!("Error interrupting Gemini: {}", e);
+                    }
+                }
+                event = session.next_event() => {
+                    let Some(event) = event else { break };
+                    if let Some(audio_data) = event.audio {
+                        // The child may cut in after the server has already
+                        // finished the reply, while it is still playing, so
+                        // the interruption only lasts until the next reply
+                        if !replying {
+                            replying = true;
+                            self.interrupted.store(false, Ordering::Relaxed);
+                        }
+                        // The rest of an interrupted reply is never played
+                        if self.interrupted.load(Ordering::Relaxed) {
+                            println!("Dropped {} bytes of the interrupted reply", audio_data.len());
+                        } else {
+                            println!("Received {} bytes from Gemini", audio_data.len());
+                            if let Some(tx) = &self.audio_in_tx {
+                                let _ = tx.send(audio_data).await;
+                            }
+                        }
+                    }
+                    if let Some(text) = event.text {
+                        print!("Gemini: {}", text);
+                        io::stdout().flush().unwrap();
+                    }
+                    // Whatever comes after is the reply to the child
+                    if event.interrupted || event.turn_complete {
+                        replying = false;
+                    }
+                }
+            }
+        }
+    }
 
+    // Records the mic, already converted to 16 kHz mono, for as long as the
+    // returned capture is kept. `reference` is what is played, at
+    // `reference_rate`, and `reference_clock` when it is heard, which keeps
+    // it lined up with the mic for the echo canceller. While the bear speaks
+    // the mic is only sent once the child interrupts it, which flushes
+    // `playback`, signals `interrupt` and sends the pre-roll first.
+    fn listen_mic_audio(
+        &self,
+        mut reference: Consumer<f32>,
+        reference_rate: u32,
+        reference_clock: ReferenceClock,
+        playback: Flush,
+        interrupt: Sender<()>,
+    ) -> Result<Capture, DeviceError> {
         let last_playback_end = self.last_playback_end.clone();
-        let playback_cooldown = self.playback_cooldown;
+        let interrupted = self.interrupted.clone();
+        let mic_drops = self.mic_drops.clone();
         let audio_out_tx = self.audio_out_tx.clone().unwrap();
-        let mut processor = self.audio_processor.clone();
-
-        let stream = device.build_input_stream(
-            &config,
-            move |data: &[i16], _: &cpal::InputCallbackInfo| {
-                let now = Instant::now();
-                let last_end = {
-                    let guard = last_playback_end.lock().unwrap();
-                    *guard
-                };
-                
-                if now.duration_since(last_end) < playback_cooldown {
-                    return;
+        let processor = self.audio_processor.clone();
+        let mut reference_resampler = Resampler::new(reference_rate, SEND_SAMPLE_RATE);
+        // Reference samples taken from the queue so far
+        let mut reference_taken: u64 = 0;
+        let mut first_callback = true;
+        let mut pre_roll = PreRoll::new(PRE_ROLL_FRAMES, FRAME_SIZE_BYTES_16K);
+
+        Capture::start(move |data: &[i16], recorded_at: Instant| {
+            // Convert i16 samples to bytes
+            let bytes: Vec<u8> = data.iter()
+                .flat_map(|&sample| sample.to_le_bytes())
+                .collect();
+            
+            // Apply audio processing if available. This runs even while
+            // muted so the echo canceller keeps adapting to the playback.
+            let (mut processed_bytes, barge_in) = if let Some(proc) = &processor {
+                let mut played: Vec<f32> = (0..reference.slots())
+                    .filter_map(|_| reference.pop().ok())
+                    .collect();
+                reference_taken += played.len() as u64;
+                // What played before the mic started echoes into nothing recorded
+                if first_callback {
+                    first_callback = false;
+                    played.clear();
                 }
-                
-                // Convert i16 samples to bytes
-                let bytes: Vec<u8> = data.iter()
-                    .flat_map(|&sample| sample.to_le_bytes().to_vec())
+                let played: Vec<u8> = reference_resampler.process(&played)
+                    .into_iter()
+                    .flat_map(|sample| i16::from_sample(sample).to_le_bytes())
                     .collect();
-                
-                // Apply audio processing if available
-                let processed_bytes = if let Some(proc) = &mut processor {
-                    proc.process_stream(&bytes)
-                } else {
-                    bytes
-                };
-                
-                // Send to Gemini
-                let audio_out_tx_clone = audio_out_tx.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = audio_out_tx_clone.send(processed_bytes).await {
-                        eprintln!("Error sending mic data: {}", e);
-                    }
-                });
-            },
-            |err| eprintln!("An error occurred on the input stream: {}", err),
-            None,
-        )?;
+                let mut proc = proc.lock().unwrap();
+                proc.process_reverse_stream(&played);
+                // Devices drift apart and the reference queue can overflow;
+                // the timestamps put the reference back against the mic
+                if let Some(heard_at) = reference_clock.heard_at(reference_taken, reference_rate) {
+                    proc.align_reference(lead_samples(heard_at, recorded_at), data.len());
+                }
+                let processed = proc.process_stream(&bytes);
+                (processed, proc.barge_in_detected())
+            } else {
+                (bytes, false)
+            };
+            if processed_bytes.is_empty() {
+                return;
+            }
 
-        stream.play()?;
-        
-        // Keep the stream alive
-        loop {
-            time::sleep(Duration::from_secs(1)).await;
-        }
+            let playing = last_playback_end.is_playing();
+            // This is the audio thread, so the interruption is reported by
+            // `converse` when it gets the signal
+            if barge_in && playing && !interrupted.swap(true, Ordering::Relaxed) {
+                playback.flush();
+                let _ = interrupt.try_send(());
+                // Detection lags the start of the word, which is held back
+                processed_bytes = pre_roll.take_before(&processed_bytes);
+            }
+
+            if playing && !interrupted.load(Ordering::Relaxed) {
+                pre_roll.push(&processed_bytes);
+                return;
+            }
+            pre_roll.clear();
+            
+            // Send to Gemini. This is the audio thread, so it can't wait, or
+            // print; drops are counted and reported by `report_mic_drops`.
+            if audio_out_tx.try_send(processed_bytes).is_err() {
+                mic_drops.fetch_add(1, Ordering::Relaxed);
+            }
+        })
     }
 
-    async fn listen_reverse_audio(&self) -> Result<(), Box<dyn Error>> {
-        let host = cpal::default_host();
-        
-        // In a real implementation, you would select the loopback device
-        // For now, we'll just use a dummy implementation
-        println!("Reverse audio capture (loopback) would be initialized here");
-        
-        if let Some(mut processor) = self.audio_processor.clone() {
-            loop {
-                // Dummy implementation - in a real app, this would read from the loopback device
-                let dummy_data = vec![0u8; FRAME_SIZE_BYTES_16K];
-                processor.process_reverse_stream(&dummy_data);
-                time::sleep(Duration::from_millis(FRAME_DURATION_MS)).await;
+    // Reports mic frames dropped since the last report, off the audio thread
+    async fn report_mic_drops(drops: Arc<AtomicU64>) {
+        let mut interval = time::interval(DROP_REPORT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let dropped = drops.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                eprintln!(
+                    "Dropped {} mic frames ({} ms) that Gemini couldn't take in time",
+                    dropped,
+                    dropped * FRAME_DURATION_MS
+                );
             }
         }
-        
-        Ok(())
     }
 
-    async fn play_audio(&self, mut rx: Receiver<Vec<u8>>) -> Result<(), Box<dyn Error>> {
-        let host = cpal::default_host();
-        let device = host.default_output_device()
-            .ok_or("No output device available")?;
-        
-        println!("Using output device: {}", device.name()?);
-        
-        let config = StreamConfig {
-            channels: CHANNELS,
-            sample_rate: SampleRate(RECEIVE_SAMPLE_RATE),
-            buffer_size: BufferSize::Default,
-        };
-
-        let last_playback_end = self.last_playback_end.clone();
-        let (audio_tx, audio_rx) = mpsc::channel::<Vec<u8>>(5);
-
-        let stream = device.build_output_stream(
-            &config,
-            move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
-                // This is called by the audio device when it needs more samples
-                // In a real implementation, we would fill the buffer with
-                // audio data received from Gemini
-                
-                // For simplicity, just generate silence
-                for sample in data.iter_mut() {
-                    *sample = 0;
-                }
-            },
-            |err| eprintln!("An error occurred on the output stream: {}", err),
-            None,
-        )?;
-
-        stream.play()?;
-
+    // Plays Gemini's audio as it arrives, except what was queued before the
+    // child interrupted. An associated function, so the task owns everything
+    // it uses.
+    async fn play_audio(
+        mut rx: Receiver<Vec<u8>>,
+        mut playback: Playback,
+        interrupted: Arc<AtomicBool>,
+    ) -> Result<(), DeviceError> {
         // Process incoming audio and play it
         while let Some(audio_data) = rx.recv().await {
-            println!("Playing {} bytes", audio_data.len());
-            
-            // In a real implementation, we would write the audio data to the output stream
-            // For now, we'll just update the playback timestamp
-            {
-                let mut guard = last_playback_end.lock().unwrap();
-                *guard = Instant::now();
+            if interrupted.load(Ordering::Relaxed) {
+                continue;
             }
-            
-            // Simulate audio playback time
-            let play_duration = Duration::from_millis(
-                (audio_data.len() as u64 * 1000) / (RECEIVE_SAMPLE_RATE as u64 * 2)
-            );
-            time::sleep(play_duration).await;
+            println!("Playing {} bytes", audio_data.len());
+            playback.write(&audio_data).await;
         }
         
         Ok(())
@@ -350,4 +380,127 @@ async fn main() -> Result<(), Box<dyn Error>> {
     
     println!("Audio resources released.");
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use live::ServerEvent;
+    use std::collections::VecDeque;
+
+    // What the scripted session does each time the model is waited on
+    enum Step {
+        Event(ServerEvent),
+        // The mic callback hearing the child over the bear
+        BargeIn,
+    }
+
+    fn audio(byte: u8) -> Step {
+        Step::Event(ServerEvent {
+            audio: Some(vec![byte]),
+            ..Default::default()
+        })
+    }
+
+    fn turn_complete() -> Step {
+        Step::Event(ServerEvent {
+            turn_complete: true,
+            ..Default::default()
+        })
+    }
+
+    fn interrupted() -> Step {
+        Step::Event(ServerEvent {
+            interrupted: true,
+            ..Default::default()
+        })
+    }
+
+    // Plays the steps in order, then closes
+    struct ScriptedSession {
+        steps: VecDeque<Step>,
+        interrupted: Arc<AtomicBool>,
+        interrupt: Sender<()>,
+    }
+
+    impl LiveSession for ScriptedSession {
+        async fn send_audio(&mut self, _audio: &[u8]) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        async fn interrupt(&mut self) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        async fn next_event(&mut self) -> Option<ServerEvent> {
+            loop {
+                match self.steps.pop_front()? {
+                    Step::Event(event) => return Some(event),
+                    Step::BargeIn => {
+                        if !self.interrupted.swap(true, Ordering::Relaxed) {
+                            let _ = self.interrupt.try_send(());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // The audio bytes `converse` passes on to be played
+    async fn played(steps: Vec<Step>) -> Vec<u8> {
+        let mut chat = GeminiVoiceChat::new("Aoede", SYS_PROMPT);
+        let (audio_in_tx, mut audio_in_rx) = mpsc::channel(steps.len().max(1));
+        chat.audio_in_tx = Some(audio_in_tx);
+        let (_audio_out_tx, mut audio_out_rx) = mpsc::channel(1);
+        let (interrupt_tx, mut interrupt_rx) = mpsc::channel(1);
+        let session = ScriptedSession {
+            steps: steps.into(),
+            interrupted: chat.interrupted.clone(),
+            interrupt: interrupt_tx,
+        };
+
+        chat.converse(session, &mut audio_out_rx, &mut interrupt_rx).await;
+
+        chat.audio_in_tx = None;
+        let mut played = Vec::new();
+        while let Some(audio) = audio_in_rx.recv().await {
+            played.extend(audio);
+        }
+        played
+    }
+
+    #[tokio::test]
+    async fn plays_replies_without_barge_in() {
+        let steps = vec![audio(1), audio(2), turn_complete(), audio(3), turn_complete()];
+        assert_eq!(played(steps).await, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn drops_the_rest_of_an_interrupted_reply_and_plays_the_next() {
+        let steps = vec![
+            audio(1),
+            Step::BargeIn,
+            audio(2),
+            audio(3),
+            interrupted(),
+            audio(4),
+            turn_complete(),
+        ];
+        assert_eq!(played(steps).await, [1, 4]);
+    }
+
+    #[tokio::test]
+    async fn barge_in_while_a_finished_reply_plays_keeps_the_next_reply() {
+        // The server sent the whole reply before the child cut in, so it
+        // never reports the interruption
+        let steps = vec![
+            audio(1),
+            turn_complete(),
+            Step::BargeIn,
+            audio(2),
+            turn_complete(),
+            audio(3),
+            turn_complete(),
+        ];
+        assert_eq!(played(steps).await, [1, 2, 3]);
+    }
+}
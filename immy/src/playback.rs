@@ -0,0 +1,443 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, Sample, SampleFormat, SizedSample, StreamConfig};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::device::{choose_config, print_supported, DeviceError, StreamThread};
+use crate::resampler::Resampler;
+use crate::RECEIVE_SAMPLE_RATE;
+
+// Seconds of received audio waiting to be played; Gemini sends faster than
+// real time
+const BUFFER_SECONDS: usize = 10;
+// Seconds of played audio the mic side may fall behind on
+const REFERENCE_SECONDS: usize = 1;
+
+// Gemini's 24 kHz mono audio, played through the default output device at
+// whatever rate, format and channel count it supports. The output callback
+// reads from a lock-free ring buffer, so it never waits on the async side.
+pub struct Playback {
+    producer: Producer<f32>,
+    resampler: Resampler,
+    sample_rate: u32,
+    flush: Flush,
+    _thread: StreamThread,
+}
+
+// Drops everything waiting to be played, from any thread
+#[derive(Clone, Default)]
+pub struct Flush(Arc<AtomicUsize>);
+
+impl Flush {
+    pub fn flush(&self) {
+        self.0.fetch_add(1, Ordering::AcqRel);
+    }
+
+    // Changes with every flush
+    fn count(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+// When the audio queued so far will have been heard. Written by the output
+// callback and read by the mic callback, so it is an atomic count of
+// nanoseconds since a fixed instant rather than a lock.
+#[derive(Clone)]
+pub struct PlaybackEnd {
+    origin: Instant,
+    nanos: Arc<AtomicU64>,
+}
+
+impl Default for PlaybackEnd {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlaybackEnd {
+    // Nothing is playing yet
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            nanos: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn set(&self, end: Instant) {
+        let nanos = end.saturating_duration_since(self.origin).as_nanos();
+        self.nanos
+            .store(nanos.min(u64::MAX as u128) as u64, Ordering::Release);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        let end = self.origin + Duration::from_nanos(self.nanos.load(Ordering::Acquire));
+        Instant::now() < end
+    }
+}
+
+// When each echo reference sample is heard, so the mic side can line the
+// reference up with what it records. One atomic holds both the number of
+// samples queued so far, modulo 2^20, and when the newest of them is heard,
+// in microseconds since a fixed instant, so they are always read together.
+#[derive(Clone)]
+pub struct ReferenceClock {
+    origin: Instant,
+    packed: Arc<AtomicU64>,
+}
+
+const COUNT_BITS: u32 = 20;
+const COUNT_MASK: u64 = (1 << COUNT_BITS) - 1;
+
+impl Default for ReferenceClock {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+            packed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl ReferenceClock {
+    // `queued` samples have gone to the reference so far, the newest heard at `heard_at`
+    fn publish(&self, queued: u64, heard_at: Instant) {
+        let micros = heard_at.saturating_duration_since(self.origin).as_micros() as u64;
+        self.packed
+            .store((micros << COUNT_BITS) | (queued & COUNT_MASK), Ordering::Release);
+    }
+
+    // When the reference sample before number `index` (counting from the
+    // start of the stream) is heard, given the device rate; None until the
+    // first publish. `index` has to be within 2^19 samples of the count
+    // published, which the reference queue's size guarantees.
+    pub fn heard_at(&self, index: u64, rate: u32) -> Option<Instant> {
+        let packed = self.packed.load(Ordering::Acquire);
+        if packed == 0 {
+            return None;
+        }
+        let newest = self.origin + Duration::from_micros(packed >> COUNT_BITS);
+        // Samples from the published count to `index`, which may be negative
+        let mut ahead = (index.wrapping_sub(packed) & COUNT_MASK) as i64;
+        if ahead >= 1 << (COUNT_BITS - 1) {
+            ahead -= 1 << COUNT_BITS;
+        }
+        let offset = Duration::from_secs_f64(ahead.unsigned_abs() as f64 / rate as f64);
+        if ahead >= 0 {
+            Some(newest + offset)
+        } else {
+            newest.checked_sub(offset)
+        }
+    }
+}
+
+impl Playback {
+    // Opens the output stream. Every sample handed to the device, silence
+    // included, also goes to the returned consumer for the echo canceller,
+    // with `reference_clock` telling when each is heard.
+    pub fn start(
+        last_playback_end: PlaybackEnd,
+        reference_clock: ReferenceClock,
+    ) -> Result<(Self, Consumer<f32>), DeviceError> {
+        let flush = Flush::default();
+        let output_flush = flush.clone();
+        let (thread, (producer, reference, sample_rate)) = StreamThread::spawn(move || {
+            open_stream(last_playback_end, reference_clock, output_flush)
+        })?;
+        let playback = Self {
+            producer,
+            resampler: Resampler::new(RECEIVE_SAMPLE_RATE, sample_rate),
+            sample_rate,
+            flush,
+            _thread: thread,
+        };
+        Ok((playback, reference))
+    }
+
+    // The device's rate, which is also the rate of the echo reference
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // For stopping playback when the child interrupts the bear
+    pub fn flush_handle(&self) -> Flush {
+        self.flush.clone()
+    }
+
+    // Queues 16-bit little-endian samples, waiting while the buffer is full.
+    // A flush meanwhile drops the rest.
+    pub async fn write(&mut self, data: &[u8]) {
+        let flushes = self.flush.count();
+        let samples: Vec<f32> = data
+            .chunks_exact(2)
+            .map(|b| f32::from_sample(i16::from_le_bytes([b[0], b[1]])))
+            .collect();
+        let mut samples = self.resampler.process(&samples).into_iter().peekable();
+        while samples.peek().is_some() {
+            while self.producer.slots() > 0 {
+                match samples.next() {
+                    Some(sample) => {
+                        let _ = self.producer.push(sample);
+                    }
+                    None => return,
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if self.flush.count() != flushes {
+                return;
+            }
+        }
+    }
+}
+
+type Opened = (cpal::Stream, (Producer<f32>, Consumer<f32>, u32));
+
+fn open_stream(
+    last_playback_end: PlaybackEnd,
+    reference_clock: ReferenceClock,
+    flush: Flush,
+) -> Result<Opened, DeviceError> {
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .ok_or("No output device available")?;
+    println!("Using output device: {}", device.name()?);
+
+    let supported: Vec<_> = device.supported_output_configs()?.collect();
+    print_supported("output", &supported);
+    let supported = choose_config(
+        &supported,
+        device.default_output_config().ok(),
+        RECEIVE_SAMPLE_RATE,
+    )
+    .ok_or("Output device has no usable config")?;
+    let format = supported.sample_format();
+    let config: StreamConfig = supported.into();
+    let rate = config.sample_rate.0;
+    println!(
+        "Output format: {:?}, {} channel(s) at {} Hz",
+        format, config.channels, rate
+    );
+
+    let (producer, consumer) = RingBuffer::new(rate as usize * BUFFER_SECONDS);
+    let (reference, reference_rx) = RingBuffer::new(rate as usize * REFERENCE_SECONDS);
+    let output = Output {
+        consumer,
+        reference,
+        reference_queued: 0,
+        reference_clock,
+        last_playback_end,
+        flushes: flush.count(),
+        flush,
+        channels: config.channels as usize,
+    };
+    let stream = match format {
+        SampleFormat::F32 => build::<f32>(&device, &config, output)?,
+        SampleFormat::I16 => build::<i16>(&device, &config, output)?,
+        SampleFormat::I32 => build::<i32>(&device, &config, output)?,
+        SampleFormat::F64 => build::<f64>(&device, &config, output)?,
+        SampleFormat::U16 => build::<u16>(&device, &config, output)?,
+        SampleFormat::I8 => build::<i8>(&device, &config, output)?,
+        SampleFormat::U8 => build::<u8>(&device, &config, output)?,
+        SampleFormat::U32 => build::<u32>(&device, &config, output)?,
+        format => return Err(format!("Unsupported output format {:?}", format).into()),
+    };
+    stream.play()?;
+    Ok((stream, (producer, reference_rx, rate)))
+}
+
+fn build<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut output: Output,
+) -> Result<cpal::Stream, DeviceError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let rate = config.sample_rate.0 as f64;
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+            let played = output.fill(data);
+            // When this buffer starts to be heard
+            let timestamp = info.timestamp();
+            let latency = timestamp
+                .playback
+                .duration_since(&timestamp.callback)
+                .unwrap_or_default();
+            let start = Instant::now() + latency;
+            let frames = data.len() / output.channels;
+            output.reference_clock.publish(
+                output.reference_queued,
+                start + Duration::from_secs_f64(frames as f64 / rate),
+            );
+            if played > 0 {
+                // When the last received sample in this buffer will be heard
+                let end = start + Duration::from_secs_f64(played as f64 / rate);
+                output.last_playback_end.set(end);
+            }
+        },
+        |err| eprintln!("An error occurred on the output stream: {}", err),
+        None,
+    )?;
+    Ok(stream)
+}
+
+// State of the output callback
+struct Output {
+    consumer: Consumer<f32>,
+    reference: Producer<f32>,
+    // Samples that made it into `reference`, for `reference_clock`
+    reference_queued: u64,
+    reference_clock: ReferenceClock,
+    last_playback_end: PlaybackEnd,
+    flush: Flush,
+    // Flushes already done
+    flushes: usize,
+    channels: usize,
+}
+
+impl Output {
+    // Fills a device buffer from the ring buffer, repeating each sample on
+    // every channel, with silence once it runs dry. Returns how many received
+    // samples were played.
+    fn fill<T>(&mut self, data: &mut [T]) -> usize
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let flushes = self.flush.count();
+        if flushes != self.flushes {
+            self.flushes = flushes;
+            if let Ok(chunk) = self.consumer.read_chunk(self.consumer.slots()) {
+                chunk.commit_all();
+            }
+        }
+
+        let mut played = 0;
+        for frame in data.chunks_mut(self.channels) {
+            let sample = match self.consumer.pop() {
+                Ok(sample) => {
+                    played += 1;
+                    sample
+                }
+                Err(_) => 0.0,
+            };
+            // Dropped if the mic side isn't keeping up; the clock lets it
+            // realign afterwards
+            if self.reference.push(sample).is_ok() {
+                self.reference_queued += 1;
+            }
+            frame.fill(T::from_sample(sample));
+        }
+        played
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(channels: usize, queued: &[f32]) -> (Output, Consumer<f32>) {
+        let (mut producer, consumer) = RingBuffer::new(64);
+        for &sample in queued {
+            producer.push(sample).unwrap();
+        }
+        let (reference, reference_rx) = RingBuffer::new(64);
+        let output = Output {
+            consumer,
+            reference,
+            reference_queued: 0,
+            reference_clock: ReferenceClock::default(),
+            last_playback_end: PlaybackEnd::new(),
+            flush: Flush::default(),
+            flushes: 0,
+            channels,
+        };
+        (output, reference_rx)
+    }
+
+    #[test]
+    fn fills_underruns_with_silence() {
+        let (mut output, _) = output(1, &[0.25, -0.5]);
+        let mut data = [7i16; 4];
+        assert_eq!(output.fill(&mut data), 2);
+        assert_eq!(data, [8192, -16384, 0, 0]);
+    }
+
+    #[test]
+    fn repeats_samples_on_every_channel_in_the_device_format() {
+        let (mut output, _) = output(2, &[0.5, -1.0]);
+        let mut data = [0u16; 4];
+        output.fill(&mut data);
+        assert_eq!(data, [49152, 49152, 0, 0]);
+    }
+
+    #[test]
+    fn drops_what_is_queued_when_flushed() {
+        let (mut output, _) = output(1, &[0.5, 0.5, 0.5]);
+        let mut data = [0i16; 2];
+        assert_eq!(output.fill(&mut data[..1]), 1);
+        output.flush.flush();
+        assert_eq!(output.fill(&mut data), 0);
+        assert_eq!(data, [0, 0]);
+    }
+
+    #[test]
+    fn passes_everything_played_to_the_reference() {
+        let (mut output, mut reference) = output(2, &[0.5, 0.25]);
+        let mut data = [0i32; 6];
+        output.fill(&mut data);
+        let played: Vec<f32> = std::iter::from_fn(|| reference.pop().ok()).collect();
+        assert_eq!(played, [0.5, 0.25, 0.0]);
+    }
+
+    #[test]
+    fn playback_end_tells_whether_audio_is_still_playing() {
+        let end = PlaybackEnd::new();
+        assert!(!end.is_playing());
+
+        let reader = end.clone();
+        end.set(Instant::now() + Duration::from_secs(60));
+        assert!(reader.is_playing());
+        end.set(Instant::now() - Duration::from_secs(1));
+        assert!(!reader.is_playing());
+    }
+
+    #[test]
+    fn reference_clock_times_samples_around_the_published_one() {
+        let clock = ReferenceClock::default();
+        assert!(clock.heard_at(0, 48_000).is_none());
+
+        let newest = Instant::now() + Duration::from_secs(1);
+        // Across the wrap of the packed count
+        let queued = (1 << COUNT_BITS) + 100;
+        clock.publish(queued, newest);
+        let heard_at = |index| clock.heard_at(index, 48_000).unwrap();
+        let close = |a: Instant, b: Instant| {
+            a.max(b).duration_since(a.min(b)) < Duration::from_micros(2)
+        };
+        assert!(close(heard_at(queued), newest));
+        assert!(close(heard_at(queued + 480), newest + Duration::from_millis(10)));
+        assert!(close(heard_at(queued - 4_800), newest - Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn counts_only_samples_that_reach_the_reference() {
+        let (mut producer, consumer) = RingBuffer::new(8);
+        producer.push(0.5).unwrap();
+        let (reference, _reference_rx) = RingBuffer::new(2);
+        let mut output = Output {
+            consumer,
+            reference,
+            reference_queued: 0,
+            reference_clock: ReferenceClock::default(),
+            last_playback_end: PlaybackEnd::new(),
+            flush: Flush::default(),
+            flushes: 0,
+            channels: 1,
+        };
+        output.fill(&mut [0f32; 4]);
+        assert_eq!(output.reference_queued, 2);
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+
+// The last few frames of mic audio, held back while the bear speaks, so a
+// child who cuts in is sent from their first word rather than from when
+// barge-in was detected
+pub struct PreRoll {
+    bytes: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl PreRoll {
+    // Holds up to `frames` frames of `frame_bytes` each
+    pub fn new(frames: usize, frame_bytes: usize) -> Self {
+        let capacity = frames * frame_bytes;
+        Self {
+            bytes: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    // Keeps `audio`, dropping the oldest beyond the capacity. Frames are
+    // whole, so dropping whole frames keeps sample boundaries.
+    pub fn push(&mut self, audio: &[u8]) {
+        let audio = &audio[audio.len().saturating_sub(self.capacity)..];
+        let excess = (self.bytes.len() + audio.len()).saturating_sub(self.capacity);
+        self.bytes.drain(..excess);
+        self.bytes.extend(audio);
+    }
+
+    // Takes what is held, oldest first, followed by `audio`
+    pub fn take_before(&mut self, audio: &[u8]) -> Vec<u8> {
+        let mut taken: Vec<u8> = self.bytes.drain(..).collect();
+        taken.extend_from_slice(audio);
+        taken
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_latest_frames() {
+        let mut pre_roll = PreRoll::new(3, 2);
+        for frame in 0..5u8 {
+            pre_roll.push(&[frame, frame]);
+        }
+        assert_eq!(pre_roll.take_before(&[9, 9]), [2, 2, 3, 3, 4, 4, 9, 9]);
+        // Taking empties it
+        assert_eq!(pre_roll.take_before(&[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn keeps_the_end_of_a_push_longer_than_it_holds() {
+        let mut pre_roll = PreRoll::new(2, 2);
+        pre_roll.push(&[0, 0, 1, 1, 2, 2]);
+        assert_eq!(pre_roll.take_before(&[]), [1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn holds_nothing_once_cleared() {
+        let mut pre_roll = PreRoll::new(2, 2);
+        pre_roll.push(&[1, 1]);
+        pre_roll.clear();
+        assert_eq!(pre_roll.take_before(&[5, 5]), [5, 5]);
+    }
+}
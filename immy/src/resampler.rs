@@ -0,0 +1,188 @@
+// Sample rate conversion with a polyphase windowed-sinc filter. The ratio
+// between the rates is reduced to up/down; each output sample is one of `up`
+// filter phases applied to the input around its position.
+
+use std::f64::consts::PI;
+
+// Zero crossings of the sinc on each side of the center; more gives a
+// sharper cutoff at the cost of more taps
+const ZERO_CROSSINGS: f64 = 16.0;
+// Cutoff as a fraction of the lower of the two Nyquist rates, leaving room
+// for the transition band so little above the new Nyquist aliases back
+const ROLLOFF: f64 = 0.95;
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// Lowpass impulse response at `x` input samples from the center, `cutoff`
+// relative to the input Nyquist rate, Blackman windowed to `half` samples
+fn kernel(x: f64, cutoff: f64, half: f64) -> f64 {
+    let sinc = if x == 0.0 {
+        1.0
+    } else {
+        (PI * cutoff * x).sin() / (PI * cutoff * x)
+    };
+    let window = 0.42 + 0.5 * (PI * x / half).cos() + 0.08 * (2.0 * PI * x / half).cos();
+    cutoff * sinc * window
+}
+
+#[derive(Clone)]
+pub struct Resampler {
+    up: usize,
+    down: usize,
+    taps: usize,
+    // `up` filters of `taps` coefficients, one after the other
+    filters: Vec<f32>,
+    // Input not yet fully used, starting with the oldest sample under the filter
+    buffer: Vec<f32>,
+    // Which filter the next output sample uses
+    phase: usize,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32) -> Self {
+        let divisor = gcd(from, to);
+        let (up, down) = ((to / divisor) as usize, (from / divisor) as usize);
+        if up == down {
+            return Self {
+                up,
+                down,
+                taps: 0,
+                filters: Vec::new(),
+                buffer: Vec::new(),
+                phase: 0,
+            };
+        }
+
+        let cutoff = ROLLOFF * (up as f64 / down as f64).min(1.0);
+        let half = (ZERO_CROSSINGS / cutoff).ceil() as usize;
+        let taps = 2 * half;
+        let mut filters = Vec::with_capacity(up * taps);
+        for phase in 0..up {
+            let offset = phase as f64 / up as f64;
+            let start = filters.len();
+            filters.extend((0..taps).map(|k| {
+                kernel(offset + (half - 1) as f64 - k as f64, cutoff, half as f64) as f32
+            }));
+            // Each phase passes DC unchanged, so no phase adds a ripple
+            let sum: f32 = filters[start..].iter().sum();
+            for coefficient in &mut filters[start..] {
+                *coefficient /= sum;
+            }
+        }
+
+        Self {
+            up,
+            down,
+            taps,
+            filters,
+            // Lines the center of the first filter up with the first sample
+            buffer: vec![0.0; half - 1],
+            phase: 0,
+        }
+    }
+
+    // Converts the next stretch of the input. Output waits for the input
+    // half a filter ahead of it, which stays buffered for the next call.
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if self.up == self.down {
+            return input.to_vec();
+        }
+
+        self.buffer.extend_from_slice(input);
+        let mut output = Vec::with_capacity(input.len() * self.up / self.down + 1);
+        let mut start = 0;
+        while start + self.taps <= self.buffer.len() {
+            let filter = &self.filters[self.phase * self.taps..][..self.taps];
+            output.push(
+                self.buffer[start..start + self.taps]
+                    .iter()
+                    .zip(filter)
+                    .map(|(x, h)| x * h)
+                    .sum(),
+            );
+            self.phase += self.down;
+            start += self.phase / self.up;
+            self.phase %= self.up;
+        }
+        self.buffer.drain(..start);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| 0.5 * (2.0 * PI * frequency as f64 * n as f64 / rate as f64).sin() as f32)
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    // Largest difference from the ideal signal at the new rate, past the
+    // filter's start-up
+    fn max_error(from: u32, to: u32, frequency: f32) -> f32 {
+        let output = Resampler::new(from, to).process(&sine(frequency, from, from as usize));
+        let expected = sine(frequency, to, output.len());
+        output[200..]
+            .iter()
+            .zip(&expected[200..])
+            .map(|(o, e)| (o - e).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn converts_between_common_rates() {
+        assert!(max_error(48_000, 16_000, 1_000.0) < 1e-3);
+        assert!(max_error(44_100, 16_000, 1_000.0) < 1e-3);
+        assert!(max_error(24_000, 48_000, 1_000.0) < 1e-3);
+        assert!(max_error(24_000, 44_100, 3_000.0) < 1e-3);
+    }
+
+    #[test]
+    fn produces_the_output_rate() {
+        let output = Resampler::new(44_100, 16_000).process(&vec![0.0; 44_100]);
+        assert!(
+            (15_950..=16_000).contains(&output.len()),
+            "{} samples",
+            output.len()
+        );
+    }
+
+    #[test]
+    fn removes_what_the_lower_rate_cannot_hold() {
+        // 12 kHz would alias to 4 kHz at 16 kHz
+        let output = Resampler::new(48_000, 16_000).process(&sine(12_000.0, 48_000, 48_000));
+        let attenuation =
+            20.0 * (rms(&output[200..]) / rms(&sine(12_000.0, 48_000, 48_000))).log10();
+        assert!(attenuation < -60.0, "aliasing at {} dB", attenuation);
+    }
+
+    #[test]
+    fn gives_the_same_output_in_pieces() {
+        let input = sine(440.0, 44_100, 10_000);
+        let whole = Resampler::new(44_100, 16_000).process(&input);
+        let mut resampler = Resampler::new(44_100, 16_000);
+        let mut pieces = Vec::new();
+        for chunk in input.chunks(441) {
+            pieces.extend(resampler.process(chunk));
+        }
+        assert_eq!(pieces, whole);
+    }
+
+    #[test]
+    fn passes_equal_rates_through() {
+        let input = sine(440.0, 16_000, 320);
+        assert_eq!(Resampler::new(16_000, 16_000).process(&input), input);
+    }
+}
@@ -0,0 +1,42 @@
+// Generates the client from the server's OpenAPI document. Operations that
+// take multipart bodies, which progenitor can't generate, are left out and
+// are called through `Client::client()` instead.
+use std::{env, fs, path::Path};
+
+const SPEC: &str = "../openapi.json";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SPEC);
+
+    let file = fs::File::open(SPEC).expect("can't open the OpenAPI document");
+    let mut spec: serde_json::Value =
+        serde_json::from_reader(file).expect("invalid OpenAPI document");
+    drop_multipart_operations(&mut spec);
+    let spec: openapiv3::OpenAPI = serde_json::from_value(spec).expect("invalid OpenAPI document");
+
+    let mut generator = progenitor::Generator::default();
+    let tokens = generator
+        .generate_tokens(&spec)
+        .expect("can't generate the client");
+    let ast = syn::parse2(tokens).expect("generated client doesn't parse");
+    let code = prettyplease::unparse(&ast);
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("codegen.rs");
+    fs::write(out, code).expect("can't write the generated client");
+}
+
+fn drop_multipart_operations(spec: &mut serde_json::Value) {
+    let Some(paths) = spec["paths"].as_object_mut() else {
+        return;
+    };
+    for operations in paths.values_mut() {
+        if let Some(operations) = operations.as_object_mut() {
+            operations.retain(|_, operation| {
+                operation["requestBody"]["content"]
+                    .get("multipart/form-data")
+                    .is_none()
+            });
+        }
+    }
+    paths.retain(|_, operations| operations.as_object().is_none_or(|ops| !ops.is_empty()));
+}
@@ -0,0 +1,15 @@
+// Typed client for the Spectrum HTTP API, generated at build time from the
+// server's OpenAPI document by `build.rs`. Regenerate `../openapi.json` with
+// `cargo run -- openapi > openapi.json` in the server crate after API changes.
+// Photo uploads are multipart, which isn't generated; send them with
+// `client.client()` to `{baseurl}/photo_sessions`.
+//
+//     let client = spectrum_client::Client::new("http://127.0.0.1:3000");
+//     let session = client
+//         .generate_image_handler(None, &spectrum_client::types::GenerateImageRequest { .. })
+//         .await?;
+
+// The generated code is progenitor's, lints included
+#![allow(renamed_and_removed_lints, clippy::all)]
+
+include!(concat!(env!("OUT_DIR"), "/codegen.rs"));
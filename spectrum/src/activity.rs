@@ -0,0 +1,267 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// Exercises a therapist can run on a generated image. Each activity decides
+// how the image is generated, what goes on the checklist and how the child's
+// answers are evaluated.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Activity {
+    // Free description of everything in the image
+    #[default]
+    Describe,
+    // "Find the X": is it in the picture, and where is it?
+    FindObject {
+        target: String,
+    },
+    // Name how the characters in the picture are feeling
+    Emotions,
+    // Count groups of objects
+    Counting,
+    // Put a series of pictures in order and tell the story
+    Sequencing {
+        length: usize,
+    },
+}
+
+impl Activity {
+    // Number of images generated per round
+    pub fn image_count(&self) -> usize {
+        match self {
+            Activity::Sequencing { length } => (*length).clamp(2, 6),
+            _ => 1,
+        }
+    }
+
+    // Extra guidance appended to the image prompt query in `generate_prompt`
+    pub fn generation_guidance(&self, step: usize) -> String {
+        match self {
+            Activity::Describe => String::new(),
+            Activity::FindObject { target } => format!(
+                "The scene must contain exactly one \"{}\", clearly visible but placed naturally among other objects so the child has to look for it.",
+                target
+            ),
+            Activity::Emotions => "Include one to three characters whose facial expressions and body language clearly show a single, easy to name emotion each (for example happy, sad, surprised, scared, angry).".to_string(),
+            Activity::Counting => "Include two or three groups of identical, clearly separated objects that are easy to count (between 1 and 10 items per group). Avoid overlapping objects.".to_string(),
+            Activity::Sequencing { .. } => format!(
+                "This is picture {} of {} in a short, simple story. Keep the same characters, setting and art style in every picture, and show one clear action that follows on from the previous picture.",
+                step + 1,
+                self.image_count()
+            ),
+        }
+    }
+
+    // Instruction shown to the child when a new image appears
+    pub fn child_instructions(&self) -> String {
+        match self {
+            Activity::Describe => "Tell me what you see in the picture.".to_string(),
+            Activity::FindObject { target } => {
+                format!("Can you find the {}? Where is it?", target)
+            }
+            Activity::Emotions => "How do the people in the picture feel?".to_string(),
+            Activity::Counting => "How many things can you count in the picture?".to_string(),
+            Activity::Sequencing { .. } => {
+                "Put the pictures in order and tell me the story.".to_string()
+            }
+        }
+    }
+
+    // Query sent to Gemini to turn an image description into checklist items
+    pub fn key_details_query(&self, description: &str) -> String {
+        let task = match self {
+            Activity::Describe => "extract a list of 10-15 key details that a child might identify.
+        Each detail should be a simple, clear phrase describing one observable element.
+        Example format: [\"red ball on the grass\", \"smiling girl with brown hair\", \"blue sky with clouds\"]".to_string(),
+            Activity::FindObject { target } => format!(
+                "decide whether the image contains a \"{0}\" and where it is.
+        If it is present, return exactly two items: one saying the {0} is in the picture, and one short phrase describing its location.
+        If it is not present, return exactly one item saying there is no {0} in the picture.
+        Example format: [\"the {0} is in the picture\", \"the {0} is under the table\"]",
+                target
+            ),
+            Activity::Emotions => "list every character together with the emotion they are showing.
+        Each item should name the character and one simple emotion word.
+        Example format: [\"the boy with the kite feels happy\", \"the girl on the bench feels sad\"]".to_string(),
+            Activity::Counting => "list every group of countable objects together with how many there are.
+        Each item should start with the number written as digits.
+        Example format: [\"3 red apples\", \"2 birds in the sky\"]".to_string(),
+            Activity::Sequencing { .. } => "list the main event shown in each picture, in story order.
+        Each item should start with the picture number and be one short sentence.
+        Example format: [\"1: the boy wakes up\", \"2: the boy eats breakfast\", \"3: the boy walks to school\"]".to_string(),
+        };
+
+        format!(
+            r#"
+        From the following detailed image description, {}
+        Description:
+        {}
+        Format your response as a JSON array of strings, each representing one checklist item.
+        "#,
+            task, description
+        )
+    }
+
+    // Checklist used when the model's key details cannot be parsed
+    pub fn fallback_details(&self) -> Vec<String> {
+        match self {
+            Activity::Describe => vec![
+                "object in image".to_string(),
+                "color".to_string(),
+                "shape".to_string(),
+                "background".to_string(),
+            ],
+            Activity::FindObject { target } => vec![
+                format!("the {} is in the picture", target),
+                format!("where the {} is", target),
+            ],
+            Activity::Emotions => vec!["how the character feels".to_string()],
+            Activity::Counting => vec!["how many objects there are".to_string()],
+            Activity::Sequencing { .. } => (1..=self.image_count())
+                .map(|n| format!("{}: what happens in picture {}", n, n))
+                .collect(),
+        }
+    }
+
+    // Short description of the exercise used in the evaluation prompt
+    pub fn task_description(&self) -> String {
+        match self {
+            Activity::Describe => "describe an image".to_string(),
+            Activity::FindObject { target } => format!("find the {} in an image", target),
+            Activity::Emotions => "recognise how the characters in an image feel".to_string(),
+            Activity::Counting => "count the objects in an image".to_string(),
+            Activity::Sequencing { .. } => {
+                "put a series of images in order and tell the story".to_string()
+            }
+        }
+    }
+
+    // Heading for the checklist section of the evaluation prompt
+    pub fn checklist_heading(&self) -> &'static str {
+        match self {
+            Activity::Describe => "Key Details to Identify",
+            Activity::FindObject { .. } => "Answer Key (presence and location)",
+            Activity::Emotions => "Characters and Their Emotions",
+            Activity::Counting => "Objects and Their Counts",
+            Activity::Sequencing { .. } => "Story Events in the Correct Order",
+        }
+    }
+
+    // How the child's answer should be checked against the checklist
+    pub fn evaluation_instructions(&self) -> String {
+        match self {
+            Activity::Describe => "Evaluate the child's description compared to the key details list. Use simple, clear language.
+Praise specific correct observations. If something important is missing, provide a gentle hint
+that hasn't been given before.".to_string(),
+            Activity::FindObject { target } => format!(
+                "Check whether the child correctly said if the {0} is in the picture, and whether they described where it is.
+A yes/no answer only identifies the first item; the location item needs a place (for example \"next to the tree\").
+If the child hasn't found it, hint at the area of the picture to look in without naming the exact spot.",
+                target
+            ),
+            Activity::Emotions => "Check which characters' emotions the child named correctly. Accept close synonyms
+(for example \"glad\" for \"happy\"), but not a different emotion. If an emotion is missed, hint by pointing
+at the character's face or body language rather than naming the emotion.".to_string(),
+            Activity::Counting => "Check which counts the child got exactly right. A group only counts as identified
+when both the object and the number are correct. If a count is wrong, gently encourage the child to count
+again one by one rather than giving the answer.".to_string(),
+            Activity::Sequencing { .. } => "The pictures were shown to the child in a shuffled order. Check which story events the
+child placed in the correct position. An event only counts as identified when it is in the right place
+in the child's story. If the order is wrong, hint by asking what must happen first.".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequencing_generates_between_two_and_six_images() {
+        assert_eq!(Activity::Sequencing { length: 0 }.image_count(), 2);
+        assert_eq!(Activity::Sequencing { length: 4 }.image_count(), 4);
+        assert_eq!(Activity::Sequencing { length: 10 }.image_count(), 6);
+        assert_eq!(Activity::Describe.image_count(), 1);
+        assert_eq!(
+            Activity::FindObject {
+                target: "cat".to_string()
+            }
+            .image_count(),
+            1
+        );
+        assert_eq!(Activity::Emotions.image_count(), 1);
+        assert_eq!(Activity::Counting.image_count(), 1);
+    }
+
+    #[test]
+    fn tells_the_child_what_to_do() {
+        let find = Activity::FindObject {
+            target: "red kite".to_string(),
+        };
+        assert_eq!(
+            find.child_instructions(),
+            "Can you find the red kite? Where is it?"
+        );
+        assert!(Activity::Emotions.child_instructions().contains("feel"));
+        assert!(Activity::Counting.child_instructions().contains("count"));
+        assert!(
+            Activity::Sequencing { length: 3 }
+                .child_instructions()
+                .contains("order")
+        );
+        assert_eq!(
+            Activity::default().child_instructions(),
+            "Tell me what you see in the picture."
+        );
+    }
+
+    #[test]
+    fn falls_back_to_one_detail_per_picture_in_a_sequence() {
+        assert_eq!(
+            Activity::Sequencing { length: 9 }.fallback_details(),
+            (1..=6)
+                .map(|n| format!("{}: what happens in picture {}", n, n))
+                .collect::<Vec<_>>()
+        );
+        let find = Activity::FindObject {
+            target: "dog".to_string(),
+        };
+        assert_eq!(
+            find.fallback_details(),
+            ["the dog is in the picture", "where the dog is"]
+        );
+    }
+
+    #[test]
+    fn numbers_sequence_pictures_from_one() {
+        let sequencing = Activity::Sequencing { length: 3 };
+        assert!(
+            sequencing
+                .generation_guidance(0)
+                .starts_with("This is picture 1 of 3")
+        );
+        assert!(
+            sequencing
+                .generation_guidance(2)
+                .starts_with("This is picture 3 of 3")
+        );
+        assert!(Activity::Describe.generation_guidance(0).is_empty());
+    }
+
+    #[test]
+    fn activities_are_tagged_by_type() {
+        let json = serde_json::to_value(Activity::FindObject {
+            target: "cat".to_string(),
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "type": "find_object", "target": "cat" })
+        );
+
+        let parsed: Activity =
+            serde_json::from_str(r#"{ "type": "sequencing", "length": 4 }"#).unwrap();
+        assert_eq!(parsed, Activity::Sequencing { length: 4 });
+        let parsed: Activity = serde_json::from_str(r#"{ "type": "emotions" }"#).unwrap();
+        assert_eq!(parsed, Activity::Emotions);
+    }
+}
@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::provider::ModelReply;
+
+// Pipeline stage that made an upstream call
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    GeneratePrompt,
+    GenerateImage,
+    GenerateDescription,
+    ExtractKeyDetails,
+    CompareDetails,
+    SummarizeSession,
+    CompactHistory,
+    // Not a model call: a therapist changed the live session
+    TherapistOverride,
+}
+
+// What happened when the stage tried to use the model output
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ParseOutcome {
+    // The stage uses the output as free text
+    NotParsed,
+    Parsed,
+    // Output was present but not in the expected format
+    Failed,
+    // No output at all (error or empty response); the stage used its fallback
+    NoOutput,
+}
+
+// One upstream request/response, as stored in a session's audit log
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuditEntry {
+    pub timestamp_ms: u64,
+    pub stage: Stage,
+    pub provider: String,
+    pub model: String,
+    pub request: String,
+    // Whether the request also carried an image (not stored in the log)
+    pub image_input: bool,
+    pub response: Option<String>,
+    pub latency_ms: u64,
+    pub prompt_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+    pub parse_outcome: ParseOutcome,
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        stage: Stage,
+        provider: &str,
+        request: &str,
+        image_input: bool,
+        reply: &ModelReply,
+        parse_outcome: ParseOutcome,
+    ) -> Self {
+        Self {
+            timestamp_ms: now_ms(),
+            stage,
+            provider: provider.to_string(),
+            model: reply.model.clone(),
+            request: request.to_string(),
+            image_input,
+            response: reply.text.clone(),
+            latency_ms: reply.latency_ms,
+            prompt_tokens: reply.prompt_tokens,
+            output_tokens: reply.output_tokens,
+            parse_outcome,
+            error: reply.error.clone(),
+        }
+    }
+
+    // The therapist id goes in `model`, the override command in `request`
+    pub fn therapist_override(therapist: &str, command: &str, outcome: &str) -> Self {
+        Self {
+            timestamp_ms: now_ms(),
+            stage: Stage::TherapistOverride,
+            provider: "therapist".to_string(),
+            model: therapist.to_string(),
+            request: command.to_string(),
+            image_input: false,
+            response: Some(outcome.to_string()),
+            latency_ms: 0,
+            prompt_tokens: None,
+            output_tokens: None,
+            parse_outcome: ParseOutcome::NotParsed,
+            error: None,
+        }
+    }
+}
+
+// Append-only JSON Lines log, one file per session
+#[derive(Debug)]
+pub struct AuditLog {
+    dir: PathBuf,
+    write_lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    fn path(&self, session_id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", session_id))
+    }
+
+    pub async fn record(&self, session_id: Uuid, entry: AuditEntry) {
+        let mut line = serde_json::to_string(&entry).unwrap();
+        line.push('\n');
+
+        // Serialize writers so concurrent entries never interleave
+        let _guard = self.write_lock.lock().await;
+        let result = async {
+            fs::create_dir_all(&self.dir).await?;
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path(session_id))
+                .await?;
+            file.write_all(line.as_bytes()).await
+        }
+        .await;
+
+        if let Err(err) = result {
+            tracing::error!(%session_id, %err, "Failed to write audit log");
+        }
+    }
+
+    // Removes the session's log; false when there was none
+    pub async fn delete(&self, session_id: Uuid) -> bool {
+        let _guard = self.write_lock.lock().await;
+        fs::remove_file(self.path(session_id)).await.is_ok()
+    }
+
+    pub async fn read(&self, session_id: Uuid) -> Vec<AuditEntry> {
+        match fs::read_to_string(self.path(session_id)).await {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,482 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::sync::RwLock;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{AppState, audit::Stage};
+
+// Whether a cached output can stand in for a new one every time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reuse {
+    // Creative stages (prompts, images): reused at the configured ratio so
+    // children still see new pictures
+    Sometimes,
+    // Outputs derived from exact content (descriptions of an image, key
+    // details of a description)
+    Always,
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    // Chance that a creative stage reuses a cached output, from 0 to 1
+    pub reuse_ratio: f64,
+    // Different outputs kept per input; once full, lookups always reuse
+    pub max_variants: usize,
+    pub max_bytes: usize,
+}
+
+impl CacheConfig {
+    pub fn from_env() -> Self {
+        Self {
+            reuse_ratio: crate::quota::env_or("CACHE_REUSE_RATIO", 0.5_f64).clamp(0.0, 1.0),
+            max_variants: crate::quota::env_or("CACHE_MAX_VARIANTS", 3_usize).max(1),
+            max_bytes: crate::quota::env_or("CACHE_MAX_MB", 256_usize) * 1024 * 1024,
+        }
+    }
+}
+
+// Content hash of a stage's normalized input and the model that answers it
+#[derive(Clone, Debug)]
+pub struct CacheKey {
+    pub stage: Stage,
+    pub model: String,
+    pub hash: String,
+}
+
+impl CacheKey {
+    pub fn new(stage: Stage, model: &str, input: &str) -> Self {
+        let stage_name = serde_json::to_string(&stage).unwrap();
+        Self {
+            stage,
+            model: model.to_string(),
+            hash: content_hash(&format!("{}\0{}\0{}", stage_name, model, normalize(input))),
+        }
+    }
+}
+
+// Hex SHA-256, also used to key outputs on large inputs such as images
+pub fn content_hash(data: &str) -> String {
+    Sha256::digest(data.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Case and whitespace differences (the prompts are indented raw strings)
+// shouldn't defeat the cache
+fn normalize(input: &str) -> String {
+    input
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    stage: Stage,
+    model: String,
+    variants: Vec<String>,
+    hits: u64,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+    // Lookup order for eviction; timestamps can tie
+    last_used: u64,
+    // Sessions whose calls produced the variants, so a child's data can be deleted
+    sessions: HashSet<Uuid>,
+}
+
+impl CacheEntry {
+    fn bytes(&self) -> usize {
+        self.variants.iter().map(String::len).sum()
+    }
+}
+
+// In-memory cache of pipeline outputs, evicting the least recently used
+// entries once over its size budget
+#[derive(Debug)]
+pub struct ContentCache {
+    config: CacheConfig,
+    entries: RwLock<HashMap<String, CacheEntry>>,
+    // Counts uses, for the least recently used order
+    uses: AtomicU64,
+    rng: Mutex<StdRng>,
+}
+
+impl ContentCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self::with_rng(config, StdRng::from_entropy())
+    }
+
+    // Reuse decisions drawn from `rng`, so tests can seed them
+    fn with_rng(config: CacheConfig, rng: StdRng) -> Self {
+        Self {
+            config,
+            entries: RwLock::new(HashMap::new()),
+            uses: AtomicU64::new(0),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    fn next_use(&self) -> u64 {
+        self.uses.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(CacheConfig::from_env())
+    }
+
+    // A cached output for `key`, or None when the stage should call the model
+    // (and `put` the result)
+    pub async fn get(&self, key: &CacheKey, reuse: Reuse) -> Option<String> {
+        let mut entries = self.entries.write().await;
+        let entry = entries.get_mut(&key.hash)?;
+        let mut rng = self.rng.lock().unwrap();
+        let reuse = match reuse {
+            Reuse::Always => true,
+            Reuse::Sometimes => {
+                entry.variants.len() >= self.config.max_variants
+                    || rng.gen_bool(self.config.reuse_ratio)
+            }
+        };
+        if !reuse {
+            return None;
+        }
+        entry.hits += 1;
+        entry.last_used_at = Utc::now();
+        entry.last_used = self.next_use();
+        entry.variants.choose(&mut *rng).cloned()
+    }
+
+    // Stores an output produced for `session_id`
+    pub async fn put(&self, key: &CacheKey, value: String, session_id: Uuid) {
+        let mut entries = self.entries.write().await;
+        let now = Utc::now();
+        let entry = entries
+            .entry(key.hash.clone())
+            .or_insert_with(|| CacheEntry {
+                stage: key.stage,
+                model: key.model.clone(),
+                variants: Vec::new(),
+                hits: 0,
+                created_at: now,
+                last_used_at: now,
+                last_used: 0,
+                sessions: HashSet::new(),
+            });
+        entry.sessions.insert(session_id);
+        if !entry.variants.contains(&value) && entry.variants.len() < self.config.max_variants {
+            entry.variants.push(value);
+        }
+        entry.last_used_at = now;
+        entry.last_used = self.next_use();
+
+        let mut bytes: usize = entries.values().map(CacheEntry::bytes).sum();
+        while bytes > self.config.max_bytes {
+            let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(hash, _)| hash.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = entries.remove(&oldest) {
+                bytes -= evicted.bytes();
+            }
+        }
+    }
+
+    pub async fn report(&self, stage: Option<Stage>) -> CacheReport {
+        let entries = self.entries.read().await;
+        let mut summaries: Vec<CacheEntrySummary> = entries
+            .iter()
+            .filter(|(_, entry)| stage.is_none_or(|stage| entry.stage == stage))
+            .map(|(hash, entry)| CacheEntrySummary {
+                key: hash.clone(),
+                stage: entry.stage,
+                model: entry.model.clone(),
+                variants: entry.variants.len(),
+                bytes: entry.bytes(),
+                hits: entry.hits,
+                created_at: entry.created_at,
+                last_used_at: entry.last_used_at,
+                preview: preview(entry),
+            })
+            .collect();
+        summaries.sort_by_key(|summary| std::cmp::Reverse(summary.last_used_at));
+
+        CacheReport {
+            reuse_ratio: self.config.reuse_ratio,
+            max_variants: self.config.max_variants,
+            max_bytes: self.config.max_bytes,
+            bytes: entries.values().map(CacheEntry::bytes).sum(),
+            hits: entries.values().map(|entry| entry.hits).sum(),
+            entries: summaries,
+        }
+    }
+
+    // Drops every entry, or only those of one stage
+    pub async fn purge(&self, stage: Option<Stage>) -> usize {
+        let mut entries = self.entries.write().await;
+        let before = entries.len();
+        entries.retain(|_, entry| stage.is_some_and(|stage| entry.stage != stage));
+        before - entries.len()
+    }
+
+    pub async fn remove(&self, hash: &str) -> bool {
+        self.entries.write().await.remove(hash).is_some()
+    }
+
+    // Drops the entries any of `session_ids` contributed to, e.g. descriptions
+    // of a deleted child's photos. Returns how many were dropped.
+    pub async fn remove_sessions(&self, session_ids: &HashSet<Uuid>) -> usize {
+        let mut entries = self.entries.write().await;
+        let before = entries.len();
+        entries.retain(|_, entry| entry.sessions.is_disjoint(session_ids));
+        before - entries.len()
+    }
+
+    // Drops cached outputs equal to any of `values`, e.g. a deleted child's
+    // images. Returns how many were dropped.
+    pub async fn remove_values(&self, values: &HashSet<String>) -> usize {
+        let mut entries = self.entries.write().await;
+        let mut removed = 0;
+        for entry in entries.values_mut() {
+            let before = entry.variants.len();
+            entry.variants.retain(|variant| !values.contains(variant));
+            removed += before - entry.variants.len();
+        }
+        entries.retain(|_, entry| !entry.variants.is_empty());
+        removed
+    }
+}
+
+fn preview(entry: &CacheEntry) -> String {
+    let Some(value) = entry.variants.first() else {
+        return String::new();
+    };
+    if value.starts_with("data:") {
+        return format!("<image, {} bytes>", value.len());
+    }
+    value.chars().take(120).collect()
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CacheEntrySummary {
+    // Content hash of the normalized input
+    pub key: String,
+    pub stage: Stage,
+    pub model: String,
+    pub variants: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    // Start of the first variant; images are only described
+    pub preview: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CacheReport {
+    pub reuse_ratio: f64,
+    pub max_variants: usize,
+    pub max_bytes: usize,
+    pub bytes: usize,
+    // Model calls saved since the entries were created
+    pub hits: u64,
+    // Most recently used first
+    pub entries: Vec<CacheEntrySummary>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PurgeResponse {
+    pub purged: usize,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CacheFilter {
+    /// Only this pipeline stage, e.g. "generate_image"
+    pub stage: Option<Stage>,
+}
+
+// Cache inspection API endpoints
+#[utoipa::path(
+    get,
+    path = "/cache",
+    params(
+        CacheFilter,
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Cache settings and entries", body = CacheReport),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled")
+    )
+)]
+pub async fn cache_report_handler(
+    State(state): State<AppState>,
+    Query(filter): Query<CacheFilter>,
+) -> Json<CacheReport> {
+    Json(state.cache.report(filter.stage).await)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/cache",
+    params(
+        CacheFilter,
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Number of entries purged", body = PurgeResponse),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled")
+    )
+)]
+pub async fn purge_cache_handler(
+    State(state): State<AppState>,
+    Query(filter): Query<CacheFilter>,
+) -> Json<PurgeResponse> {
+    let purged = state.cache.purge(filter.stage).await;
+    tracing::info!(purged, stage = ?filter.stage, "Purged cache");
+    Json(PurgeResponse { purged })
+}
+
+#[utoipa::path(
+    delete,
+    path = "/cache/{key}",
+    params(
+        ("key" = String, Path, description = "Entry key from GET /cache"),
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 204, description = "Entry purged"),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "Unknown entry")
+    )
+)]
+pub async fn purge_cache_entry_handler(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> StatusCode {
+    if state.cache.remove(&key).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(reuse_ratio: f64, max_variants: usize, max_bytes: usize) -> ContentCache {
+        let config = CacheConfig {
+            reuse_ratio,
+            max_variants,
+            max_bytes,
+        };
+        ContentCache::with_rng(config, StdRng::seed_from_u64(7))
+    }
+
+    fn key(input: &str) -> CacheKey {
+        CacheKey::new(Stage::GeneratePrompt, "model", input)
+    }
+
+    #[tokio::test]
+    async fn creative_stages_reuse_at_the_configured_ratio() {
+        let key = key("a cat");
+        for (ratio, expected) in [(0.0, 0..=0), (1.0, 1000..=1000), (0.3, 240..=360)] {
+            let cache = cache(ratio, 3, usize::MAX);
+            cache.put(&key, "first".to_string(), Uuid::nil()).await;
+            let mut reused = 0;
+            for _ in 0..1000 {
+                if cache.get(&key, Reuse::Sometimes).await.is_some() {
+                    reused += 1;
+                }
+            }
+            assert!(expected.contains(&reused), "ratio {}: {}", ratio, reused);
+        }
+    }
+
+    #[tokio::test]
+    async fn exact_stages_always_reuse() {
+        let cache = cache(0.0, 3, usize::MAX);
+        let key = key("a cat");
+        assert_eq!(cache.get(&key, Reuse::Always).await, None);
+
+        cache
+            .put(&key, "description".to_string(), Uuid::nil())
+            .await;
+        assert_eq!(
+            cache.get(&key, Reuse::Always).await.as_deref(),
+            Some("description")
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_at_most_max_variants_then_always_reuses() {
+        let cache = cache(0.0, 2, usize::MAX);
+        let key = key("a cat");
+        for value in ["one", "two", "two", "three"] {
+            cache.put(&key, value.to_string(), Uuid::nil()).await;
+        }
+
+        let report = cache.report(None).await;
+        assert_eq!(report.entries[0].variants, 2);
+        for _ in 0..20 {
+            let value = cache.get(&key, Reuse::Sometimes).await.unwrap();
+            assert!(value == "one" || value == "two", "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_entry_over_budget() {
+        let cache = cache(1.0, 1, 10);
+        let (a, b, c) = (key("a"), key("b"), key("c"));
+        cache.put(&a, "aaaa".to_string(), Uuid::nil()).await;
+        cache.put(&b, "bbbb".to_string(), Uuid::nil()).await;
+        // Using `a` makes `b` the least recently used
+        cache.get(&a, Reuse::Always).await;
+        cache.put(&c, "cccc".to_string(), Uuid::nil()).await;
+
+        assert!(cache.get(&a, Reuse::Always).await.is_some());
+        assert!(cache.get(&b, Reuse::Always).await.is_none());
+        assert!(cache.get(&c, Reuse::Always).await.is_some());
+        assert_eq!(cache.report(None).await.bytes, 8);
+    }
+
+    #[tokio::test]
+    async fn removes_entries_a_session_contributed_to() {
+        let cache = cache(1.0, 3, usize::MAX);
+        let (child, other) = (Uuid::new_v4(), Uuid::new_v4());
+        cache
+            .put(&key("photo"), "their kitchen".to_string(), child)
+            .await;
+        cache.put(&key("shared"), "a cat".to_string(), other).await;
+        cache.put(&key("shared"), "a dog".to_string(), child).await;
+        cache.put(&key("other"), "a bus".to_string(), other).await;
+
+        assert_eq!(cache.remove_sessions(&HashSet::from([child])).await, 2);
+        assert!(cache.get(&key("photo"), Reuse::Always).await.is_none());
+        assert!(cache.get(&key("shared"), Reuse::Always).await.is_none());
+        assert!(cache.get(&key("other"), Reuse::Always).await.is_some());
+    }
+}
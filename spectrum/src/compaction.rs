@@ -0,0 +1,179 @@
+use crate::{
+    Session,
+    quota::{env_or, estimate_tokens},
+};
+
+// How much chat history the evaluation prompt may carry before older turns
+// are folded into a summary
+#[derive(Clone, Debug)]
+pub struct ContextBudget {
+    pub max_history_tokens: u64,
+    // Chat entries (child and teacher messages) always kept verbatim
+    pub keep_recent: usize,
+}
+
+impl ContextBudget {
+    pub fn from_env() -> Self {
+        Self {
+            max_history_tokens: env_or("CHAT_CONTEXT_BUDGET_TOKENS", 1500),
+            keep_recent: env_or("CHAT_KEEP_RECENT_MESSAGES", 6),
+        }
+    }
+
+    // Number of leading chat entries to fold into the summary, if the
+    // history is over budget
+    pub fn compaction_point(&self, session: &Session) -> Option<usize> {
+        let upto = session.chat.len().saturating_sub(self.keep_recent);
+        if upto <= session.compacted_messages {
+            return None;
+        }
+        if history_tokens(session) <= self.max_history_tokens {
+            return None;
+        }
+        Some(upto)
+    }
+}
+
+// Estimated size of the history section of the evaluation prompt
+pub fn history_tokens(session: &Session) -> u64 {
+    let summary = session.chat_summary.as_deref().map_or(0, estimate_tokens);
+    let turns: u64 = session.chat[session.compacted_messages..]
+        .iter()
+        .map(|(speaker, msg)| estimate_tokens(speaker) + estimate_tokens(msg) + 4)
+        .sum();
+    summary + turns
+}
+
+// Query asking Gemini to fold the turns before `upto` into the running summary
+pub fn compaction_query(session: &Session, upto: usize) -> String {
+    let turns = session.chat[session.compacted_messages..upto]
+        .iter()
+        .map(|(speaker, msg)| format!("{}: {}", speaker, msg))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        r#"
+        You are keeping notes on a conversation between a teacher and a child with autism who is describing an image.
+        Summary so far:
+        {}
+
+        New conversation turns:
+        {}
+
+        Write an updated summary in at most 120 words. It must keep:
+        1. Every detail of the image the child has already mentioned, in the child's own words where possible
+        2. Every hint the teacher has already given, so it is never repeated
+        3. Anything the child struggled with or seemed upset about
+        Reply with the summary text only.
+        "#,
+        session.chat_summary.as_deref().unwrap_or("(none yet)"),
+        turns
+    )
+}
+
+// Summary used when the model returns nothing: the covered details and hints
+// are listed explicitly, older messages are dropped. Rebuilt from the session
+// each time rather than appended to the previous summary, so repeated
+// fallbacks don't grow it.
+pub fn fallback_summary(session: &Session, upto: usize) -> String {
+    let child_turns = session.chat[..upto]
+        .iter()
+        .filter(|(speaker, _)| speaker == "Child")
+        .count();
+    format!(
+        "The child has taken {} turns so far. Details mentioned so far: {}. Hints already given: {}.",
+        child_turns,
+        if session.identified_details.is_empty() {
+            "none".to_string()
+        } else {
+            session.identified_details.join(", ")
+        },
+        if session.used_hints.is_empty() {
+            "none".to_string()
+        } else {
+            session.used_hints.join(" | ")
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_with_turns(turns: usize) -> Session {
+        Session {
+            chat: (0..turns)
+                .flat_map(|turn| {
+                    [
+                        ("Child".to_string(), format!("I see thing {}", turn)),
+                        ("Teacher".to_string(), "Well done!".to_string()),
+                    ]
+                })
+                .collect(),
+            identified_details: vec!["red ball".to_string()],
+            used_hints: vec!["Look at the floor".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fallback_summary_does_not_grow_with_repeated_compactions() {
+        let mut session = session_with_turns(20);
+        for upto in [10, 20, 30, 40] {
+            let summary = fallback_summary(&session, upto);
+            // Rebuilt from the session each time: only the turn count changes
+            assert_eq!(
+                summary,
+                format!(
+                    "The child has taken {} turns so far. Details mentioned so far: red ball. Hints already given: Look at the floor.",
+                    upto / 2
+                )
+            );
+            session.chat_summary = Some(summary);
+            session.compacted_messages = upto;
+        }
+    }
+
+    #[test]
+    fn history_tokens_count_the_summary_and_uncompacted_turns() {
+        // "Child" 2 + "I see thing 0" 4 + 4, "Teacher" 2 + "Well done!" 3 + 4
+        let mut session = session_with_turns(3);
+        assert_eq!(history_tokens(&session), 3 * 19);
+
+        session.compacted_messages = 2;
+        session.chat_summary = Some("12345678".to_string());
+        assert_eq!(history_tokens(&session), 2 + 2 * 19);
+    }
+
+    #[test]
+    fn compacts_only_once_over_budget() {
+        let session = session_with_turns(3);
+        let tokens = history_tokens(&session);
+        let budget = |max_history_tokens| ContextBudget {
+            max_history_tokens,
+            keep_recent: 2,
+        };
+
+        assert_eq!(budget(tokens).compaction_point(&session), None);
+        assert_eq!(budget(tokens - 1).compaction_point(&session), Some(4));
+    }
+
+    #[test]
+    fn nothing_is_compacted_when_only_recent_turns_are_left() {
+        let mut session = session_with_turns(3);
+        let budget = ContextBudget {
+            max_history_tokens: 0,
+            keep_recent: 6,
+        };
+        assert_eq!(budget.compaction_point(&session), None);
+
+        // Everything before the recent turns has already been folded in
+        let budget = ContextBudget {
+            max_history_tokens: 0,
+            keep_recent: 2,
+        };
+        session.compacted_messages = 4;
+        assert_eq!(budget.compaction_point(&session), None);
+    }
+}
@@ -0,0 +1,351 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{AppState, RoundRecord, activity::Activity};
+
+// What a child has to show on one image for it to count towards a topic
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct MasteryCriteria {
+    // Mastered images needed before moving on to the next topic
+    #[serde(default = "default_images_required")]
+    pub images_required: usize,
+    // Share of the image's key details the child has to find, from 0 to 1
+    #[serde(default = "default_min_detail_ratio")]
+    pub min_detail_ratio: f64,
+    // Most hints the child may need on a mastered image; unlimited when unset
+    #[serde(default)]
+    pub max_hints: Option<usize>,
+}
+
+fn default_images_required() -> usize {
+    3
+}
+
+fn default_min_detail_ratio() -> f64 {
+    0.8
+}
+
+impl Default for MasteryCriteria {
+    fn default() -> Self {
+        Self {
+            images_required: default_images_required(),
+            min_detail_ratio: default_min_detail_ratio(),
+            max_hints: None,
+        }
+    }
+}
+
+impl MasteryCriteria {
+    pub fn is_met_by(&self, round: &RoundRecord) -> bool {
+        if round.key_details.is_empty() {
+            return false;
+        }
+        let ratio = round.identified_details.len() as f64 / round.key_details.len() as f64;
+        ratio >= self.min_detail_ratio
+            && self
+                .max_hints
+                .is_none_or(|max_hints| round.used_hints.len() <= max_hints)
+    }
+}
+
+// One step of a lesson plan
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct LessonTopic {
+    pub topic_focus: String,
+    #[serde(default)]
+    pub activity: Activity,
+    #[serde(default)]
+    pub mastery: MasteryCriteria,
+}
+
+// Ordered topics a child works through, e.g. emotions -> facial expressions -> social scenes
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct LessonPlan {
+    pub id: Uuid,
+    pub name: String,
+    pub description: String,
+    pub topics: Vec<LessonTopic>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Lesson plan as authored by a therapist
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct LessonPlanRequest {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub topics: Vec<LessonTopic>,
+}
+
+impl LessonPlanRequest {
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Lesson plan needs a name".to_string());
+        }
+        if self.topics.is_empty() {
+            return Err("Lesson plan needs at least one topic".to_string());
+        }
+        for (idx, topic) in self.topics.iter().enumerate() {
+            if topic.topic_focus.trim().is_empty() {
+                return Err(format!("Topic {} has no topic_focus", idx + 1));
+            }
+            if topic.mastery.images_required == 0 {
+                return Err(format!(
+                    "Topic {} needs at least one image to master",
+                    idx + 1
+                ));
+            }
+            if !(0.0..=1.0).contains(&topic.mastery.min_detail_ratio) {
+                return Err(format!(
+                    "Topic {} min_detail_ratio must be between 0 and 1",
+                    idx + 1
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// A session's place in its lesson plan. The session keeps the version of the
+// plan it started with, so editing a plan doesn't move children mid-session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LessonProgress {
+    pub plan: LessonPlan,
+    pub topic_index: usize,
+    // Mastered images on the current topic
+    pub images_mastered: usize,
+    pub completed: bool,
+}
+
+// Where a session is in its lesson plan, as returned by the API
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct LessonStatus {
+    pub plan_id: Uuid,
+    pub plan_name: String,
+    // Zero-based position of the current topic in the plan
+    pub topic_index: usize,
+    pub topic_count: usize,
+    pub topic_focus: String,
+    pub images_mastered: usize,
+    pub images_required: usize,
+    pub completed: bool,
+}
+
+impl LessonProgress {
+    pub fn new(plan: LessonPlan) -> Self {
+        Self {
+            plan,
+            topic_index: 0,
+            images_mastered: 0,
+            completed: false,
+        }
+    }
+
+    pub fn topic(&self) -> &LessonTopic {
+        &self.plan.topics[self.topic_index]
+    }
+
+    // Counts a finished image towards the current topic. Returns the next
+    // topic when the child has just mastered the current one.
+    pub fn record_round(&mut self, round: &RoundRecord) -> Option<LessonTopic> {
+        if self.completed || !self.topic().mastery.is_met_by(round) {
+            return None;
+        }
+        self.images_mastered += 1;
+        if self.images_mastered < self.topic().mastery.images_required {
+            return None;
+        }
+
+        // The child stays on the last topic once the plan is finished
+        if self.topic_index + 1 == self.plan.topics.len() {
+            self.completed = true;
+            return None;
+        }
+        self.topic_index += 1;
+        self.images_mastered = 0;
+        Some(self.topic().clone())
+    }
+
+    pub fn status(&self) -> LessonStatus {
+        let topic = self.topic();
+        LessonStatus {
+            plan_id: self.plan.id,
+            plan_name: self.plan.name.clone(),
+            topic_index: self.topic_index,
+            topic_count: self.plan.topics.len(),
+            topic_focus: topic.topic_focus.clone(),
+            images_mastered: self.images_mastered,
+            images_required: topic.mastery.images_required,
+            completed: self.completed,
+        }
+    }
+}
+
+// Lesson plans are saved on every change; they are few and rarely edited
+async fn save_plans(state: &AppState, plans: &HashMap<Uuid, LessonPlan>) {
+    if let Err(err) = state.lesson_plan_store.save(plans).await {
+        tracing::error!(%err, "Failed to save lesson plans");
+    }
+}
+
+// Lesson plan API endpoints
+#[utoipa::path(
+    post,
+    path = "/lesson_plans",
+    request_body = LessonPlanRequest,
+    params(("x-therapist-token" = String, Header, description = "Therapist token")),
+    responses(
+        (status = 201, description = "Created lesson plan", body = LessonPlan),
+        (status = 400, description = "Invalid lesson plan"),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled")
+    )
+)]
+pub async fn create_lesson_plan_handler(
+    State(state): State<AppState>,
+    Json(request): Json<LessonPlanRequest>,
+) -> Result<(StatusCode, Json<LessonPlan>), (StatusCode, String)> {
+    request
+        .validate()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    let now = Utc::now();
+    let plan = LessonPlan {
+        id: Uuid::new_v4(),
+        name: request.name,
+        description: request.description,
+        topics: request.topics,
+        created_at: now,
+        updated_at: now,
+    };
+    let mut plans = state.lesson_plans.write().await;
+    plans.insert(plan.id, plan.clone());
+    save_plans(&state, &plans).await;
+    Ok((StatusCode::CREATED, Json(plan)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/lesson_plans",
+    responses((status = 200, description = "All lesson plans, by name", body = Vec<LessonPlan>))
+)]
+pub async fn list_lesson_plans_handler(State(state): State<AppState>) -> Json<Vec<LessonPlan>> {
+    let mut plans: Vec<LessonPlan> = state.lesson_plans.read().await.values().cloned().collect();
+    plans.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(plans)
+}
+
+#[utoipa::path(
+    get,
+    path = "/lesson_plans/{plan_id}",
+    params(("plan_id" = Uuid, Path, description = "Lesson plan")),
+    responses(
+        (status = 200, description = "The lesson plan", body = LessonPlan),
+        (status = 404, description = "Unknown lesson plan")
+    )
+)]
+pub async fn get_lesson_plan_handler(
+    State(state): State<AppState>,
+    Path(plan_id): Path<Uuid>,
+) -> Result<Json<LessonPlan>, StatusCode> {
+    state
+        .lesson_plans
+        .read()
+        .await
+        .get(&plan_id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[utoipa::path(
+    put,
+    path = "/lesson_plans/{plan_id}",
+    request_body = LessonPlanRequest,
+    params(
+        ("plan_id" = Uuid, Path, description = "Lesson plan to replace"),
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Updated lesson plan; running sessions keep the old version", body = LessonPlan),
+        (status = 400, description = "Invalid lesson plan"),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "Unknown lesson plan")
+    )
+)]
+pub async fn update_lesson_plan_handler(
+    State(state): State<AppState>,
+    Path(plan_id): Path<Uuid>,
+    Json(request): Json<LessonPlanRequest>,
+) -> Result<Json<LessonPlan>, (StatusCode, String)> {
+    request
+        .validate()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    let mut plans = state.lesson_plans.write().await;
+    let plan = plans
+        .get_mut(&plan_id)
+        .ok_or((StatusCode::NOT_FOUND, "Unknown lesson plan".to_string()))?;
+    plan.name = request.name;
+    plan.description = request.description;
+    plan.topics = request.topics;
+    plan.updated_at = Utc::now();
+    let plan = plan.clone();
+    save_plans(&state, &plans).await;
+    Ok(Json(plan))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/lesson_plans/{plan_id}",
+    params(
+        ("plan_id" = Uuid, Path, description = "Lesson plan to delete"),
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 204, description = "Deleted; running sessions keep their copy"),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "Unknown lesson plan")
+    )
+)]
+pub async fn delete_lesson_plan_handler(
+    State(state): State<AppState>,
+    Path(plan_id): Path<Uuid>,
+) -> StatusCode {
+    let mut plans = state.lesson_plans.write().await;
+    if plans.remove(&plan_id).is_none() {
+        return StatusCode::NOT_FOUND;
+    }
+    save_plans(&state, &plans).await;
+    StatusCode::NO_CONTENT
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions/{session_id}/lesson",
+    params(("session_id" = Uuid, Path, description = "Session following a lesson plan")),
+    responses(
+        (status = 200, description = "Progress through the lesson plan", body = LessonStatus),
+        (status = 404, description = "Unknown session or session without a lesson plan")
+    )
+)]
+pub async fn session_lesson_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<LessonStatus>, StatusCode> {
+    let sessions = state.sessions.read().await;
+    let session = sessions.get(&session_id).ok_or(StatusCode::NOT_FOUND)?;
+    let lesson = session.lesson.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(lesson.status()))
+}
@@ -0,0 +1,394 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    AppState, CallContext, Session,
+    activity::Activity,
+    audit::{ParseOutcome, Stage},
+    compact_chat_history, compare_details, parse_evaluation, parse_feedback,
+    provider::Provider,
+};
+
+const USAGE: &str = "usage: spectrum eval <dataset.jsonl> [--provider mock|gemini|local] [--base-url URL] [--model NAME] [--log-dir DIR] [--json]";
+
+// One labeled conversation: a reference image and the child's turns about it
+#[derive(Debug, Deserialize)]
+struct EvalCase {
+    id: String,
+    #[serde(default)]
+    prompt: String,
+    image_description: String,
+    key_details: Vec<String>,
+    #[serde(default = "default_difficulty")]
+    difficulty: String,
+    #[serde(default)]
+    activity: Activity,
+    turns: Vec<EvalTurn>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EvalTurn {
+    utterance: String,
+    // Checklist items the child newly identified in this turn
+    expected: Vec<String>,
+}
+
+fn default_difficulty() -> String {
+    "Very Simple".to_string()
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Metrics {
+    turns: usize,
+    predicted_details: usize,
+    expected_details: usize,
+    matched_details: usize,
+    hints: usize,
+    repeated_hints: usize,
+    parse_failures: usize,
+    no_output: usize,
+}
+
+impl Metrics {
+    fn add(&mut self, other: &Metrics) {
+        self.turns += other.turns;
+        self.predicted_details += other.predicted_details;
+        self.expected_details += other.expected_details;
+        self.matched_details += other.matched_details;
+        self.hints += other.hints;
+        self.repeated_hints += other.repeated_hints;
+        self.parse_failures += other.parse_failures;
+        self.no_output += other.no_output;
+    }
+
+    fn precision(&self) -> f64 {
+        ratio(self.matched_details, self.predicted_details)
+    }
+
+    fn recall(&self) -> f64 {
+        ratio(self.matched_details, self.expected_details)
+    }
+
+    fn hint_repetition_rate(&self) -> f64 {
+        ratio(self.repeated_hints, self.hints)
+    }
+
+    fn parse_failure_rate(&self) -> f64 {
+        ratio(self.parse_failures, self.turns)
+    }
+
+    fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "counts": self,
+            "precision": self.precision(),
+            "recall": self.recall(),
+            "hint_repetition_rate": self.hint_repetition_rate(),
+            "parse_failure_rate": self.parse_failure_rate(),
+        })
+    }
+}
+
+fn ratio(numerator: usize, denominator: usize) -> f64 {
+    if denominator == 0 {
+        0.0
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+struct Options {
+    dataset: String,
+    provider: Provider,
+    log_dir: String,
+    json: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut dataset = None;
+    let mut provider_name = "mock".to_string();
+    let mut base_url = "http://localhost:8080".to_string();
+    let mut model = "local".to_string();
+    let mut log_dir = "eval_logs".to_string();
+    let mut json = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--provider" => provider_name = value()?,
+            "--base-url" => base_url = value()?,
+            "--model" => model = value()?,
+            "--log-dir" => log_dir = value()?,
+            "--json" => json = true,
+            other if !other.starts_with("--") && dataset.is_none() => {
+                dataset = Some(other.to_string())
+            }
+            other => return Err(format!("unexpected argument {}\n{}", other, USAGE)),
+        }
+    }
+
+    let provider = match provider_name.as_str() {
+        "mock" => Provider::Mock,
+        "gemini" => Provider::Gemini,
+        "local" => Provider::OpenAiCompatible { base_url, model },
+        other => return Err(format!("unknown provider {}\n{}", other, USAGE)),
+    };
+
+    Ok(Options {
+        dataset: dataset.ok_or(USAGE.to_string())?,
+        provider,
+        log_dir,
+        json,
+    })
+}
+
+// Runs every case in the dataset through `compare_details` and
+// `parse_evaluation` and reports detail precision/recall, hint repetition and
+// JSON parse failures.
+pub async fn run(args: &[String]) -> Result<(), String> {
+    let options = parse_args(args)?;
+
+    let dataset = std::fs::read_to_string(&options.dataset)
+        .map_err(|err| format!("can't read {}: {}", options.dataset, err))?;
+    let cases = dataset
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            serde_json::from_str::<EvalCase>(line)
+                .map_err(|err| format!("{} line {}: {}", options.dataset, idx + 1, err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let google_api_key = match options.provider {
+        Provider::Gemini => std::env::var("GOOGLE_API_KEY")
+            .map_err(|_| "GOOGLE_API_KEY must be set for the gemini provider".to_string())?,
+        _ => String::new(),
+    };
+    let state = AppState::new(
+        String::new(),
+        google_api_key,
+        options.provider,
+        options.log_dir,
+    )?;
+
+    let mut total = Metrics::default();
+    let mut per_case = vec![];
+    for case in &cases {
+        let metrics = run_case(case, &state).await;
+        if !options.json {
+            println!(
+                "{:<24} turns {:>3}  precision {:.2}  recall {:.2}  hint repeats {:.2}  parse failures {:.2}",
+                case.id,
+                metrics.turns,
+                metrics.precision(),
+                metrics.recall(),
+                metrics.hint_repetition_rate(),
+                metrics.parse_failure_rate()
+            );
+        }
+        total.add(&metrics);
+        per_case.push((case.id.clone(), metrics));
+    }
+
+    if options.json {
+        let report = serde_json::json!({
+            "provider": state.provider.name(),
+            "cases": per_case
+                .iter()
+                .map(|(id, metrics)| (id.clone(), metrics.summary()))
+                .collect::<serde_json::Map<_, _>>(),
+            "total": total.summary(),
+        });
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        println!();
+        println!("Provider:              {}", state.provider.name());
+        println!("Cases / turns:         {} / {}", cases.len(), total.turns);
+        println!("Detail precision:      {:.3}", total.precision());
+        println!("Detail recall:         {:.3}", total.recall());
+        println!("Hint repetition rate:  {:.3}", total.hint_repetition_rate());
+        println!("JSON parse failures:   {:.3}", total.parse_failure_rate());
+        println!(
+            "Turns with no output:  {} (fallback feedback used)",
+            total.no_output
+        );
+    }
+
+    Ok(())
+}
+
+async fn run_case(case: &EvalCase, state: &AppState) -> Metrics {
+    let ctx = CallContext {
+        session_id: Uuid::new_v4(),
+        clinic_id: "eval".to_string(),
+    };
+    let mut session = Session {
+        prompt: Some(case.prompt.clone()),
+        image_description: Some(case.image_description.clone()),
+        key_details: case.key_details.clone(),
+        difficulty: case.difficulty.clone(),
+        activity: case.activity.clone(),
+        ..Default::default()
+    };
+
+    let mut metrics = Metrics::default();
+    let mut hints_given: Vec<String> = vec![];
+    for turn in &case.turns {
+        compact_chat_history(&mut session, &ctx, state).await;
+        let evaluation = compare_details(&turn.utterance, &session, &ctx, state).await;
+
+        // Hints are compared before parse_evaluation records them as used
+        if let Some(hint) = parse_feedback(&evaluation).map(|feedback| feedback.hint) {
+            let hint = normalize(&hint);
+            if !hint.is_empty() {
+                metrics.hints += 1;
+                if hints_given.contains(&hint) {
+                    metrics.repeated_hints += 1;
+                }
+                hints_given.push(hint);
+            }
+        }
+
+        let (feedback, _, _, newly_identified) = parse_evaluation(&evaluation, &mut session);
+
+        metrics.matched_details += matched_details(&turn.expected, &newly_identified);
+        metrics.turns += 1;
+        metrics.predicted_details += newly_identified.len();
+        metrics.expected_details += turn.expected.len();
+
+        // Keep the session moving the same way process_chat_handler does
+        for detail in newly_identified {
+            if !session.identified_details.contains(&detail) {
+                session.identified_details.push(detail);
+            }
+        }
+        session
+            .chat
+            .push(("Child".to_string(), turn.utterance.clone()));
+        session.chat.push(("Teacher".to_string(), feedback));
+    }
+
+    // Parse outcomes come from the audit log, which sees the raw model output
+    for entry in state.audit.read(ctx.session_id).await {
+        if entry.stage != Stage::CompareDetails {
+            continue;
+        }
+        match entry.parse_outcome {
+            ParseOutcome::Failed => metrics.parse_failures += 1,
+            ParseOutcome::NoOutput => metrics.no_output += 1,
+            _ => {}
+        }
+    }
+
+    metrics
+}
+
+// Matches predicted details one-to-one against the labels. The evaluator is
+// told to copy checklist items exactly, so only case, punctuation and spacing
+// may differ; fuzzy matching would inflate both precision and recall.
+fn matched_details(expected: &[String], predicted: &[String]) -> usize {
+    let mut unmatched: Vec<String> = expected.iter().map(|detail| normalize(detail)).collect();
+    let mut matched = 0;
+    for detail in predicted {
+        let detail = normalize(detail);
+        if let Some(pos) = unmatched.iter().position(|expected| *expected == detail) {
+            unmatched.remove(pos);
+            matched += 1;
+        }
+    }
+    matched
+}
+
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_details_exactly_after_normalizing() {
+        let expected = details(&["A red ball", "the dog's tail"]);
+
+        assert_eq!(
+            matched_details(&expected, &details(&["a red ball.", "The  dogs tail"])),
+            2
+        );
+        // Sharing a word is not a match
+        assert_eq!(matched_details(&expected, &details(&["a red kite"])), 0);
+        assert_eq!(matched_details(&expected, &details(&["ball"])), 0);
+    }
+
+    #[test]
+    fn each_label_matches_once() {
+        let expected = details(&["a red ball"]);
+        let predicted = details(&["a red ball", "A red ball"]);
+
+        assert_eq!(matched_details(&expected, &predicted), 1);
+    }
+
+    #[test]
+    fn computes_rates_from_counts() {
+        let metrics = Metrics {
+            turns: 4,
+            predicted_details: 5,
+            expected_details: 8,
+            matched_details: 4,
+            hints: 4,
+            repeated_hints: 1,
+            parse_failures: 2,
+            no_output: 0,
+        };
+
+        assert_eq!(metrics.precision(), 0.8);
+        assert_eq!(metrics.recall(), 0.5);
+        assert_eq!(metrics.hint_repetition_rate(), 0.25);
+        assert_eq!(metrics.parse_failure_rate(), 0.5);
+    }
+
+    #[test]
+    fn empty_counts_give_zero_rates() {
+        let metrics = Metrics::default();
+
+        assert_eq!(metrics.precision(), 0.0);
+        assert_eq!(metrics.recall(), 0.0);
+        assert_eq!(metrics.hint_repetition_rate(), 0.0);
+        assert_eq!(metrics.parse_failure_rate(), 0.0);
+    }
+
+    #[test]
+    fn totals_add_up_per_case_counts() {
+        let case = Metrics {
+            turns: 2,
+            predicted_details: 3,
+            expected_details: 4,
+            matched_details: 2,
+            hints: 2,
+            repeated_hints: 1,
+            parse_failures: 1,
+            no_output: 1,
+        };
+        let mut total = Metrics::default();
+        total.add(&case);
+        total.add(&case);
+
+        assert_eq!(total.turns, 4);
+        assert_eq!(total.matched_details, 4);
+        assert_eq!(total.no_output, 2);
+        // Rates are pooled over all turns, not averaged per case
+        assert_eq!(total.precision(), 4.0 / 6.0);
+        assert_eq!(total.recall(), 0.5);
+    }
+}
@@ -0,0 +1,484 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{AppState, DIFFICULTIES, Session};
+
+// When a child moves up a difficulty level
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProgressionPolicy {
+    // The evaluation model decides, as without an experiment
+    #[default]
+    Model,
+    // The child advances once they found this share of the image's details
+    // (0 to 1) with at most `max_hints` hints; the model's opinion is ignored
+    DetailRatio {
+        min_ratio: f64,
+        #[serde(default)]
+        max_hints: Option<usize>,
+    },
+}
+
+impl ProgressionPolicy {
+    pub fn should_advance(&self, model_advance: bool, session: &Session) -> bool {
+        match self {
+            ProgressionPolicy::Model => model_advance,
+            ProgressionPolicy::DetailRatio {
+                min_ratio,
+                max_hints,
+            } => {
+                if session.key_details.is_empty() {
+                    return false;
+                }
+                let ratio =
+                    session.identified_details.len() as f64 / session.key_details.len() as f64;
+                ratio >= *min_ratio
+                    && max_hints.is_none_or(|max_hints| session.used_hints.len() <= max_hints)
+            }
+        }
+    }
+}
+
+// One variant under test. Empty guidance leaves that prompt unchanged, so a
+// control arm is just a name.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct ExperimentArm {
+    pub name: String,
+    // Relative share of sessions assigned to the arm
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    // Added to the instructions for generating the image prompt
+    #[serde(default)]
+    pub prompt_guidance: Option<String>,
+    // Added to the instructions for evaluating the child's descriptions
+    #[serde(default)]
+    pub feedback_guidance: Option<String>,
+    #[serde(default)]
+    pub progression: ProgressionPolicy,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Experiment {
+    pub id: Uuid,
+    pub name: String,
+    pub description: String,
+    pub arms: Vec<ExperimentArm>,
+    // New sessions are only enrolled while the experiment is active
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+    pub stopped_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ExperimentRequest {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub arms: Vec<ExperimentArm>,
+}
+
+impl ExperimentRequest {
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Experiment needs a name".to_string());
+        }
+        if self.arms.len() < 2 {
+            return Err("Experiment needs at least two arms".to_string());
+        }
+        let mut names = HashSet::new();
+        for arm in &self.arms {
+            if arm.name.trim().is_empty() || !names.insert(arm.name.as_str()) {
+                return Err("Arms need unique, non-empty names".to_string());
+            }
+            if arm.weight == 0 {
+                return Err(format!("Arm \"{}\" needs a weight above 0", arm.name));
+            }
+            if let ProgressionPolicy::DetailRatio { min_ratio, .. } = arm.progression
+                && !(0.0..=1.0).contains(&min_ratio)
+            {
+                return Err(format!(
+                    "Arm \"{}\" min_ratio must be between 0 and 1",
+                    arm.name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The arm a session runs under. The session keeps its copy of the arm, like
+// lesson plans, so the experiment can't change under a running session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Enrollment {
+    pub experiment_id: Uuid,
+    pub arm: ExperimentArm,
+}
+
+impl Experiment {
+    // Picks an arm from a hash of the experiment and the child, so a child
+    // lands in the same arm in every session
+    pub fn assign(&self, child_key: &str) -> Enrollment {
+        let digest = Sha256::digest(format!("{}:{}", self.id, child_key).as_bytes());
+        let bucket = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        let total: u64 = self.arms.iter().map(|arm| arm.weight as u64).sum();
+        let mut point = bucket % total;
+        let arm = self
+            .arms
+            .iter()
+            .find(|arm| {
+                let inside = point < arm.weight as u64;
+                point = point.saturating_sub(arm.weight as u64);
+                inside
+            })
+            .unwrap_or(&self.arms[0]);
+        Enrollment {
+            experiment_id: self.id,
+            arm: arm.clone(),
+        }
+    }
+}
+
+// Enrolls a new session in the experiment it asked for. Enrollment is opt-in:
+// sessions that don't name an experiment run as they would without one.
+pub async fn enroll(
+    state: &AppState,
+    experiment_id: Option<Uuid>,
+    child_key: &str,
+) -> Result<Option<Enrollment>, StatusCode> {
+    let Some(experiment_id) = experiment_id else {
+        return Ok(None);
+    };
+    let experiments = state.experiments.read().await;
+    let experiment = experiments
+        .get(&experiment_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !experiment.active {
+        return Err(StatusCode::CONFLICT);
+    }
+    Ok(Some(experiment.assign(child_key)))
+}
+
+// Experiments are saved on every change, like lesson plans
+async fn save_experiments(state: &AppState, experiments: &HashMap<Uuid, Experiment>) {
+    if let Err(err) = state.experiment_store.save(experiments).await {
+        tracing::error!(%err, "Failed to save experiments");
+    }
+}
+
+// Outcomes of one arm over its sessions' finished images
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ArmOutcome {
+    pub arm: String,
+    pub sessions: usize,
+    pub images: usize,
+    // Mean share of each image's details the child found
+    pub mean_detail_ratio: Option<f64>,
+    pub mean_details_identified: Option<f64>,
+    pub mean_hints: Option<f64>,
+    // Moves up a difficulty level
+    pub advances: usize,
+    // Mean time from the session start or the previous level change to an advance
+    pub mean_seconds_to_advance: Option<f64>,
+    pub mean_images_to_advance: Option<f64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ExperimentReport {
+    pub experiment: Experiment,
+    pub arms: Vec<ArmOutcome>,
+    pub generated_at: DateTime<Utc>,
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+fn level(difficulty: &str) -> Option<usize> {
+    DIFFICULTIES.iter().position(|&d| d == difficulty)
+}
+
+fn arm_outcome(arm: &ExperimentArm, sessions: &[&Session]) -> ArmOutcome {
+    let mut ratios = vec![];
+    let mut identified = vec![];
+    let mut hints = vec![];
+    let mut seconds_to_advance = vec![];
+    let mut images_to_advance = vec![];
+
+    for session in sessions {
+        for round in &session.history {
+            if !round.key_details.is_empty() {
+                ratios.push(round.identified_details.len() as f64 / round.key_details.len() as f64);
+            }
+            identified.push(round.identified_details.len() as f64);
+            hints.push(round.used_hints.len() as f64);
+        }
+
+        let mut since = session.started_at;
+        let mut since_round = 0;
+        for change in &session.difficulty_changes {
+            if level(&change.to) > level(&change.from) {
+                if let Some(since) = since {
+                    seconds_to_advance.push((change.at - since).num_milliseconds() as f64 / 1000.0);
+                }
+                images_to_advance.push((change.round + 1 - since_round) as f64);
+            }
+            since = Some(change.at);
+            since_round = change.round + 1;
+        }
+    }
+
+    ArmOutcome {
+        arm: arm.name.clone(),
+        sessions: sessions.len(),
+        images: hints.len(),
+        mean_detail_ratio: mean(&ratios),
+        mean_details_identified: mean(&identified),
+        mean_hints: mean(&hints),
+        advances: images_to_advance.len(),
+        mean_seconds_to_advance: mean(&seconds_to_advance),
+        mean_images_to_advance: mean(&images_to_advance),
+    }
+}
+
+// Experiment API endpoints
+#[utoipa::path(
+    post,
+    path = "/experiments",
+    request_body = ExperimentRequest,
+    params(("x-therapist-token" = String, Header, description = "Therapist token")),
+    responses(
+        (status = 201, description = "Created and active experiment", body = Experiment),
+        (status = 400, description = "Invalid experiment"),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled")
+    )
+)]
+pub async fn create_experiment_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ExperimentRequest>,
+) -> Result<(StatusCode, Json<Experiment>), (StatusCode, String)> {
+    request
+        .validate()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    let experiment = Experiment {
+        id: Uuid::new_v4(),
+        name: request.name,
+        description: request.description,
+        arms: request.arms,
+        active: true,
+        created_at: Utc::now(),
+        stopped_at: None,
+    };
+    let mut experiments = state.experiments.write().await;
+    experiments.insert(experiment.id, experiment.clone());
+    save_experiments(&state, &experiments).await;
+    Ok((StatusCode::CREATED, Json(experiment)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/experiments",
+    responses((status = 200, description = "All experiments, newest first", body = Vec<Experiment>))
+)]
+pub async fn list_experiments_handler(State(state): State<AppState>) -> Json<Vec<Experiment>> {
+    let mut experiments: Vec<Experiment> =
+        state.experiments.read().await.values().cloned().collect();
+    experiments.sort_by_key(|experiment| std::cmp::Reverse(experiment.created_at));
+    Json(experiments)
+}
+
+#[utoipa::path(
+    post,
+    path = "/experiments/{experiment_id}/stop",
+    params(
+        ("experiment_id" = Uuid, Path, description = "Experiment to stop enrolling into"),
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Stopped experiment; enrolled sessions keep their arm", body = Experiment),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "Unknown experiment")
+    )
+)]
+pub async fn stop_experiment_handler(
+    State(state): State<AppState>,
+    Path(experiment_id): Path<Uuid>,
+) -> Result<Json<Experiment>, StatusCode> {
+    let mut experiments = state.experiments.write().await;
+    let experiment = experiments
+        .get_mut(&experiment_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if experiment.active {
+        experiment.active = false;
+        experiment.stopped_at = Some(Utc::now());
+    }
+    let experiment = experiment.clone();
+    save_experiments(&state, &experiments).await;
+    Ok(Json(experiment))
+}
+
+#[utoipa::path(
+    get,
+    path = "/experiments/{experiment_id}/report",
+    params(("experiment_id" = Uuid, Path, description = "Experiment")),
+    responses(
+        (status = 200, description = "Outcome metrics per arm", body = ExperimentReport),
+        (status = 404, description = "Unknown experiment")
+    )
+)]
+pub async fn experiment_report_handler(
+    State(state): State<AppState>,
+    Path(experiment_id): Path<Uuid>,
+) -> Result<Json<ExperimentReport>, StatusCode> {
+    let experiment = state
+        .experiments
+        .read()
+        .await
+        .get(&experiment_id)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let sessions = state.sessions.read().await;
+    let arms = experiment
+        .arms
+        .iter()
+        .map(|arm| {
+            let enrolled: Vec<&Session> = sessions
+                .values()
+                .filter(|session| {
+                    session.experiment.as_ref().is_some_and(|enrollment| {
+                        enrollment.experiment_id == experiment_id && enrollment.arm.name == arm.name
+                    })
+                })
+                .collect();
+            arm_outcome(arm, &enrolled)
+        })
+        .collect();
+
+    Ok(Json(ExperimentReport {
+        experiment,
+        arms,
+        generated_at: Utc::now(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DifficultyChange;
+    use chrono::Duration;
+
+    fn experiment(weights: &[u32]) -> Experiment {
+        Experiment {
+            id: Uuid::new_v4(),
+            name: "Hint wording".to_string(),
+            description: String::new(),
+            arms: weights
+                .iter()
+                .enumerate()
+                .map(|(idx, &weight)| ExperimentArm {
+                    name: format!("arm-{}", idx),
+                    weight,
+                    prompt_guidance: None,
+                    feedback_guidance: None,
+                    progression: ProgressionPolicy::Model,
+                })
+                .collect(),
+            active: true,
+            created_at: Utc::now(),
+            stopped_at: None,
+        }
+    }
+
+    #[test]
+    fn assigns_a_child_to_the_same_arm_every_time() {
+        let experiment = experiment(&[1, 1]);
+        for child in ["amy", "ben", "cal"] {
+            let first = experiment.assign(child);
+            assert_eq!(first.experiment_id, experiment.id);
+            assert_eq!(experiment.assign(child).arm, first.arm);
+        }
+    }
+
+    #[test]
+    fn assigns_children_in_proportion_to_the_weights() {
+        let experiment = experiment(&[3, 1, 0]);
+        let mut counts = [0; 3];
+        for child in 0..4000 {
+            let arm = experiment.assign(&format!("child-{}", child)).arm;
+            counts[experiment.arms.iter().position(|a| *a == arm).unwrap()] += 1;
+        }
+
+        assert_eq!(counts[2], 0);
+        let share = counts[0] as f64 / 4000.0;
+        assert!((0.72..0.78).contains(&share), "arm-0 got {}", share);
+    }
+
+    #[test]
+    fn reports_seconds_and_images_to_each_advance() {
+        let started = Utc::now();
+        let change = |from: &str, to: &str, minutes: i64, round: usize| DifficultyChange {
+            from: from.to_string(),
+            to: to.to_string(),
+            at: started + Duration::minutes(minutes),
+            round,
+        };
+        let round = |found: usize, hints: usize| {
+            Session {
+                key_details: vec!["a".into(), "b".into(), "c".into(), "d".into()],
+                identified_details: vec!["a".into(); found],
+                used_hints: vec!["hint".into(); hints],
+                ..Default::default()
+            }
+            .current_round()
+        };
+        let session = Session {
+            started_at: Some(started),
+            history: vec![round(4, 0), round(2, 2), round(3, 1), round(4, 0)],
+            difficulty_changes: vec![
+                // Up after the second image, two minutes in
+                change("Very Simple", "Simple", 2, 1),
+                // Down, which restarts the clock without counting
+                change("Simple", "Very Simple", 3, 2),
+                // Up again after one more image, three minutes later
+                change("Very Simple", "Simple", 6, 3),
+            ],
+            ..Default::default()
+        };
+
+        let outcome = arm_outcome(&experiment(&[1]).arms[0], &[&session]);
+        assert_eq!(outcome.sessions, 1);
+        assert_eq!(outcome.images, 4);
+        assert_eq!(outcome.mean_detail_ratio, Some(13.0 / 16.0));
+        assert_eq!(outcome.mean_hints, Some(0.75));
+        assert_eq!(outcome.advances, 2);
+        assert_eq!(outcome.mean_seconds_to_advance, Some(150.0));
+        assert_eq!(outcome.mean_images_to_advance, Some(1.5));
+    }
+
+    #[test]
+    fn arm_without_sessions_has_no_means() {
+        let outcome = arm_outcome(&experiment(&[1]).arms[0], &[]);
+        assert_eq!(
+            (outcome.sessions, outcome.images, outcome.advances),
+            (0, 0, 0)
+        );
+        assert!(outcome.mean_detail_ratio.is_none());
+        assert!(outcome.mean_seconds_to_advance.is_none());
+    }
+}
@@ -0,0 +1,774 @@
+use axum::{
+    Json,
+    extract::{Path, State, WebSocketUpgrade},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{sync::broadcast, task::JoinHandle};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    AppState, CallContext, GeneratedRound, RoundSpec, Session, activity::Activity,
+    compact_chat_history, compare_details, generate_round, handle_socket, parse_evaluation,
+    quota::Clinic, sensory::SensoryProfile, similar_details,
+};
+
+const MAX_PARTICIPANTS: usize = 8;
+// A child this many turns behind the most active child gets a turn-taking prompt
+const TURN_GAP: usize = 2;
+
+// A child taking part in a group session
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Participant {
+    pub id: Uuid,
+    pub name: String,
+    // Details this child found on the current image
+    pub identified_details: Vec<String>,
+    // Messages this child sent on the current image
+    pub turns: usize,
+    pub joined_at: DateTime<Utc>,
+    // Hints this child was given on the current image
+    #[serde(default)]
+    pub used_hints: Vec<String>,
+    // Whether the child got a turn-taking prompt since their last message
+    #[serde(default)]
+    pub prompted: bool,
+}
+
+impl Participant {
+    fn new(name: String) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            name,
+            identified_details: vec![],
+            turns: 0,
+            joined_at: Utc::now(),
+            used_hints: vec![],
+            prompted: false,
+        }
+    }
+}
+
+// Several children sharing one image. `shared` holds the image, the checklist
+// and the attributed chat; each participant is evaluated on their own details
+// and hints. Saved with the sessions; the event channel is recreated on restore.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupSession {
+    pub shared: Session,
+    pub participants: Vec<Participant>,
+    // Events pushed to every participant's WebSocket
+    #[serde(skip, default = "group_events")]
+    pub events: broadcast::Sender<String>,
+    // Set while one request generates the next image, so a burst of messages
+    // finishing the checklist doesn't generate several
+    #[serde(skip)]
+    advancing: bool,
+}
+
+fn group_events() -> broadcast::Sender<String> {
+    broadcast::channel(64).0
+}
+
+// Checklist item with the children who found it
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct GroupDetail {
+    pub id: usize,
+    pub detail: String,
+    pub found_by: Vec<String>,
+}
+
+impl GroupSession {
+    pub fn new(shared: Session, names: Vec<String>) -> Self {
+        Self {
+            shared,
+            participants: names.into_iter().map(Participant::new).collect(),
+            events: group_events(),
+            advancing: false,
+        }
+    }
+
+    fn participant_index(&self, participant_id: Uuid) -> Option<usize> {
+        self.participants
+            .iter()
+            .position(|participant| participant.id == participant_id)
+    }
+
+    // The shared session as the evaluator should see it for one child
+    fn view_for(&self, idx: usize) -> Session {
+        let participant = &self.participants[idx];
+        Session {
+            identified_details: participant.identified_details.clone(),
+            used_hints: participant.used_hints.clone(),
+            ..self.shared.clone()
+        }
+    }
+
+    pub fn checklist(&self) -> Vec<GroupDetail> {
+        self.shared
+            .key_details
+            .iter()
+            .enumerate()
+            .map(|(id, detail)| GroupDetail {
+                id,
+                detail: detail.clone(),
+                found_by: self
+                    .participants
+                    .iter()
+                    .filter(|participant| {
+                        participant
+                            .identified_details
+                            .iter()
+                            .any(|found| similar_details(found, detail))
+                    })
+                    .map(|participant| participant.name.clone())
+                    .collect(),
+            })
+            .collect()
+    }
+
+    // Credits a child's evaluated message to them, adds it to the shared chat
+    // and tells the group
+    fn record_message(
+        &mut self,
+        idx: usize,
+        message: String,
+        feedback: &str,
+        used_hints: Vec<String>,
+        newly_identified: Vec<String>,
+    ) {
+        let participant = &mut self.participants[idx];
+        for hint in used_hints {
+            if !participant.used_hints.contains(&hint) {
+                participant.used_hints.push(hint);
+            }
+        }
+        participant.turns += 1;
+        participant.prompted = false;
+        for detail in newly_identified {
+            if !participant.identified_details.contains(&detail) {
+                participant.identified_details.push(detail.clone());
+            }
+            if !self.shared.identified_details.contains(&detail) {
+                self.shared.identified_details.push(detail);
+            }
+        }
+
+        let child_entry = (format!("Child ({})", participant.name), message);
+        let teacher_entry = (
+            "Teacher".to_string(),
+            format!("{}: {}", participant.name, feedback),
+        );
+        let participant_id = participant.id;
+        self.broadcast(json!({
+            "type": "message",
+            "participant_id": participant_id,
+            "messages": [&child_entry, &teacher_entry],
+            "checklist": self.checklist(),
+        }));
+        self.shared.chat.push(child_entry);
+        self.shared.chat.push(teacher_entry);
+    }
+
+    // Starts the next image for the whole group and returns it
+    fn start_next_round(&mut self, round: GeneratedRound) -> String {
+        let finished = self.shared.current_round();
+        self.shared.history.push(finished);
+        let new_image = self.shared.start_round(round);
+        for participant in &mut self.participants {
+            participant.identified_details = vec![];
+            participant.used_hints = vec![];
+            participant.turns = 0;
+            participant.prompted = false;
+        }
+
+        let message = format!(
+            "Great teamwork, you found everything together! Here's a new image. {}",
+            self.shared.activity.child_instructions()
+        );
+        self.shared.chat.push(("System".to_string(), message));
+        self.broadcast(json!({
+            "type": "new_image",
+            "image": new_image,
+            "sequence": self.shared.sequence,
+            "checklist": self.checklist(),
+            "chat": self.shared.chat,
+        }));
+        new_image
+    }
+
+    // Takes a child out of the group along with their chat lines and the
+    // replies addressed to them, in every round. Returns false when the child
+    // isn't in the group.
+    pub fn remove_participant(&mut self, participant_id: Uuid) -> bool {
+        let Some(idx) = self.participant_index(participant_id) else {
+            return false;
+        };
+        let name = self.participants.remove(idx).name;
+        let child = format!("Child ({})", name);
+        let feedback = format!("{}: ", name);
+        let turn_prompt = format!("{}, it's your turn!", name);
+        let keep = |(speaker, text): &(String, String)| {
+            *speaker != child
+                && !(speaker == "Teacher" && text.starts_with(&feedback))
+                && !(speaker == "System" && text.starts_with(&turn_prompt))
+        };
+        self.shared.chat.retain(keep);
+        for round in &mut self.shared.history {
+            round.chat.retain(keep);
+        }
+        true
+    }
+
+    fn all_found(&self) -> bool {
+        self.checklist()
+            .iter()
+            .all(|detail| !detail.found_by.is_empty())
+    }
+
+    // Invites the quietest child to speak when they have fallen behind
+    fn turn_prompt(&mut self) -> Option<(Uuid, String)> {
+        let most_turns = self.participants.iter().map(|p| p.turns).max()?;
+        let quietest = self
+            .participants
+            .iter_mut()
+            .filter(|p| !p.prompted && most_turns - p.turns >= TURN_GAP)
+            .min_by_key(|p| (p.turns, p.identified_details.len()))?;
+        quietest.prompted = true;
+
+        let text = format!(
+            "{}, it's your turn! {}",
+            quietest.name,
+            self.shared.activity.child_instructions()
+        );
+        Some((quietest.id, text))
+    }
+
+    fn broadcast(&self, event: serde_json::Value) {
+        // No receivers just means nobody has the WebSocket open
+        let _ = self.events.send(event.to_string());
+    }
+}
+
+// Group session API endpoints
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateGroupRequest {
+    pub age: String,
+    pub autism_level: String,
+    pub topic_focus: String,
+    pub treatment_plan: String,
+    #[serde(default)]
+    pub activity: Activity,
+    #[serde(default)]
+    pub sensory_profile: SensoryProfile,
+    // Names of the children in the group
+    pub participants: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GroupResponse {
+    pub group_id: Uuid,
+    pub image: Option<String>,
+    pub sequence: Vec<String>,
+    pub activity: Activity,
+    pub instructions: String,
+    pub participants: Vec<Participant>,
+    pub checklist: Vec<GroupDetail>,
+    // (speaker, message) pairs for the current image
+    #[schema(value_type = Vec<Vec<String>>)]
+    pub chat: Vec<(String, String)>,
+}
+
+impl GroupResponse {
+    fn new(group_id: Uuid, group: &GroupSession) -> Self {
+        Self {
+            group_id,
+            image: group.shared.image.clone(),
+            sequence: group.shared.sequence.clone(),
+            activity: group.shared.activity.clone(),
+            instructions: group.shared.activity.child_instructions(),
+            participants: group.participants.clone(),
+            checklist: group.checklist(),
+            chat: group.shared.chat.clone(),
+        }
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), (StatusCode, String)> {
+    if name.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Participant names can't be empty".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Chat lines are attributed by name, so names have to tell children apart
+fn validate_unique_name<'a>(
+    name: &str,
+    taken: impl IntoIterator<Item = &'a str>,
+) -> Result<(), (StatusCode, String)> {
+    let name = name.trim();
+    if taken
+        .into_iter()
+        .any(|other| other.trim().eq_ignore_ascii_case(name))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("There is already a participant called {}", name),
+        ));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/groups",
+    request_body = CreateGroupRequest,
+    params(("x-api-key" = Option<String>, Header, nullable = false, description = "Clinic API key, required when clinic keys are configured")),
+    responses(
+        (status = 200, description = "New group session with its first image", body = GroupResponse),
+        (status = 400, description = "No participants, too many, or an empty or repeated name"),
+        (status = 401, description = "Missing or unknown API key"),
+        (status = 429, description = "Rate limit exceeded"),
+        (status = 502, description = "Image generation failed")
+    )
+)]
+pub async fn create_group_handler(
+    State(state): State<AppState>,
+    Clinic(clinic_id): Clinic,
+    Json(request): Json<CreateGroupRequest>,
+) -> Result<Json<GroupResponse>, (StatusCode, String)> {
+    if request.participants.is_empty() || request.participants.len() > MAX_PARTICIPANTS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "A group needs between 1 and {} participants",
+                MAX_PARTICIPANTS
+            ),
+        ));
+    }
+    for (idx, name) in request.participants.iter().enumerate() {
+        validate_name(name)?;
+        validate_unique_name(name, request.participants[..idx].iter().map(String::as_str))?;
+    }
+
+    let group_id = Uuid::new_v4();
+    let ctx = CallContext {
+        session_id: group_id,
+        clinic_id,
+    };
+    let spec = RoundSpec {
+        difficulty: "Very Simple",
+        age: &request.age,
+        autism_level: &request.autism_level,
+        topic_focus: &request.topic_focus,
+        treatment_plan: &request.treatment_plan,
+        activity: &request.activity,
+        sensory_profile: &request.sensory_profile,
+        prompt_guidance: None,
+    };
+    let round = generate_round(spec, &ctx, &state)
+        .await
+        .map_err(|status| (status, "Couldn't generate the first image".to_string()))?;
+
+    let group = GroupSession::new(
+        Session {
+            prompt: Some(round.prompt),
+            image: Some(round.image),
+            image_description: Some(round.description),
+            difficulty: "Very Simple".to_string(),
+            age: request.age,
+            autism_level: request.autism_level,
+            topic_focus: request.topic_focus,
+            treatment_plan: request.treatment_plan,
+            key_details: round.key_details,
+            activity: request.activity,
+            sensory_profile: request.sensory_profile,
+            sequence: round.sequence,
+            clinic_id: ctx.clinic_id,
+            started_at: Some(Utc::now()),
+            round_started_at: Some(Utc::now()),
+            ..Default::default()
+        },
+        request.participants,
+    );
+
+    let response = GroupResponse::new(group_id, &group);
+    state.groups.write().await.insert(group_id, group);
+    Ok(Json(response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/groups/{group_id}",
+    params(("group_id" = Uuid, Path, description = "Group session")),
+    responses(
+        (status = 200, description = "Current image, checklist and participants", body = GroupResponse),
+        (status = 404, description = "Unknown group session")
+    )
+)]
+pub async fn get_group_handler(
+    State(state): State<AppState>,
+    Path(group_id): Path<Uuid>,
+) -> Result<Json<GroupResponse>, StatusCode> {
+    let groups = state.groups.read().await;
+    let group = groups.get(&group_id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(GroupResponse::new(group_id, group)))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct JoinGroupRequest {
+    pub name: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/groups/{group_id}/participants",
+    request_body = JoinGroupRequest,
+    params(("group_id" = Uuid, Path, description = "Group session to join")),
+    responses(
+        (status = 200, description = "The new participant", body = Participant),
+        (status = 400, description = "Empty or taken name, or group is full"),
+        (status = 404, description = "Unknown group session")
+    )
+)]
+pub async fn join_group_handler(
+    State(state): State<AppState>,
+    Path(group_id): Path<Uuid>,
+    Json(request): Json<JoinGroupRequest>,
+) -> Result<Json<Participant>, (StatusCode, String)> {
+    validate_name(&request.name)?;
+
+    let mut groups = state.groups.write().await;
+    let group = groups
+        .get_mut(&group_id)
+        .ok_or((StatusCode::NOT_FOUND, "Unknown group session".to_string()))?;
+    if group.participants.len() >= MAX_PARTICIPANTS {
+        return Err((StatusCode::BAD_REQUEST, "The group is full".to_string()));
+    }
+    validate_unique_name(
+        &request.name,
+        group.participants.iter().map(|p| p.name.as_str()),
+    )?;
+
+    let participant = Participant::new(request.name);
+    group.participants.push(participant.clone());
+    group.broadcast(json!({
+        "type": "participant_joined",
+        "participant": participant,
+    }));
+    Ok(Json(participant))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct GroupMessageRequest {
+    pub participant_id: Uuid,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GroupMessageResponse {
+    #[schema(value_type = Vec<Vec<String>>)]
+    pub chat: Vec<(String, String)>,
+    pub checklist: Vec<GroupDetail>,
+    // Set when the group found every detail and moved on to a new image
+    pub new_image: Option<String>,
+    pub sequence: Option<Vec<String>>,
+    // Prompt inviting a quieter child to take a turn
+    pub turn_prompt: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/groups/{group_id}/messages",
+    request_body = GroupMessageRequest,
+    params(("group_id" = Uuid, Path, description = "Group session")),
+    responses(
+        (status = 200, description = "Teacher feedback for the child and the group checklist", body = GroupMessageResponse),
+        (status = 404, description = "Unknown group session or participant"),
+        (status = 409, description = "The group moved on to a new image while the message was evaluated"),
+        (status = 429, description = "Rate limit exceeded"),
+        (status = 502, description = "Image generation for the next image failed")
+    )
+)]
+pub async fn group_message_handler(
+    State(state): State<AppState>,
+    Path(group_id): Path<Uuid>,
+    Json(request): Json<GroupMessageRequest>,
+) -> Result<Json<GroupMessageResponse>, StatusCode> {
+    // 1. Take this child's view of the group. The model calls below run
+    // without the groups lock, so other groups and other children carry on.
+    let (ctx, mut view, round) = {
+        let groups = state.groups.read().await;
+        let group = groups.get(&group_id).ok_or(StatusCode::NOT_FOUND)?;
+        let idx = group
+            .participant_index(request.participant_id)
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let ctx = CallContext {
+            session_id: group_id,
+            clinic_id: group.shared.clinic_id.clone(),
+        };
+        (ctx, group.view_for(idx), group.shared.history.len())
+    };
+
+    // 2. Evaluate the message against this child's own progress
+    let message = state.redactor.redact(&request.message);
+    compact_chat_history(&mut view, &ctx, &state).await;
+    let evaluation = compare_details(&message, &view, &ctx, &state).await;
+    let (feedback, _, _, newly_identified) = parse_evaluation(&evaluation, &mut view);
+
+    // 3. Attribute what the child found, unless the group has moved on to a
+    // new image in the meantime
+    let mut groups = state.groups.write().await;
+    let group = groups.get_mut(&group_id).ok_or(StatusCode::NOT_FOUND)?;
+    if group.shared.history.len() != round {
+        return Err(StatusCode::CONFLICT);
+    }
+    let idx = group
+        .participant_index(request.participant_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    // The chat only grows within an image, so a newer summary still holds
+    if view.compacted_messages > group.shared.compacted_messages {
+        group.shared.chat_summary = view.chat_summary;
+        group.shared.compacted_messages = view.compacted_messages;
+    }
+    group.record_message(idx, message, &feedback, view.used_hints, newly_identified);
+
+    // 4. Move the whole group on once every detail has been found. The image
+    // is generated without the lock; only one request does it.
+    if group.all_found() && !group.advancing {
+        group.advancing = true;
+        let shared = group.shared.clone();
+        drop(groups);
+
+        return advance_group(state, group_id, ctx, shared)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map(Json);
+    }
+
+    // 5. Encourage quieter children to join in
+    let turn_prompt = group.turn_prompt().map(|(participant_id, text)| {
+        group.shared.chat.push(("System".to_string(), text.clone()));
+        group.broadcast(json!({
+            "type": "turn_prompt",
+            "participant_id": participant_id,
+            "message": text,
+        }));
+        text
+    });
+
+    Ok(Json(GroupMessageResponse {
+        chat: group.shared.chat.clone(),
+        checklist: group.checklist(),
+        new_image: None,
+        sequence: None,
+        turn_prompt,
+    }))
+}
+
+// Generates the group's next image from `shared` in its own task, which
+// always clears `advancing`, so a request dropped while it runs can't leave
+// the group stuck on a finished image
+fn advance_group(
+    state: AppState,
+    group_id: Uuid,
+    ctx: CallContext,
+    shared: Session,
+) -> JoinHandle<Result<GroupMessageResponse, StatusCode>> {
+    tokio::spawn(async move {
+        let round = generate_round(shared.round_spec(&shared.difficulty), &ctx, &state).await;
+
+        let mut groups = state.groups.write().await;
+        let group = groups.get_mut(&group_id).ok_or(StatusCode::NOT_FOUND)?;
+        group.advancing = false;
+        let new_image = group.start_next_round(round?);
+        Ok(GroupMessageResponse {
+            chat: group.shared.chat.clone(),
+            checklist: group.checklist(),
+            new_image: Some(new_image),
+            sequence: Some(group.shared.sequence.clone()),
+            turn_prompt: None,
+        })
+    })
+}
+
+// Live updates for everyone in the group: new messages, checklist changes,
+// turn-taking prompts and new images
+pub async fn group_ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path(group_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let events = state
+        .groups
+        .read()
+        .await
+        .get(&group_id)
+        .ok_or(StatusCode::NOT_FOUND)?
+        .events
+        .subscribe();
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, Some(events))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(names: &[&str]) -> GroupSession {
+        GroupSession::new(
+            Session {
+                key_details: vec!["red ball".to_string(), "blue cat".to_string()],
+                ..Default::default()
+            },
+            names.iter().map(|name| name.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn credits_details_to_the_child_who_found_them() {
+        let mut group = group(&["Ann", "Ben"]);
+        group.record_message(
+            0,
+            "a red ball".to_string(),
+            "Well done!",
+            vec!["Look at the floor".to_string()],
+            vec!["red ball".to_string()],
+        );
+
+        assert_eq!(group.participants[0].identified_details, vec!["red ball"]);
+        assert_eq!(group.participants[0].used_hints, vec!["Look at the floor"]);
+        assert_eq!(group.participants[0].turns, 1);
+        assert!(group.participants[1].identified_details.is_empty());
+        // Ben is still evaluated as if nothing was found
+        assert!(group.view_for(1).identified_details.is_empty());
+        assert!(group.view_for(1).used_hints.is_empty());
+
+        let checklist = group.checklist();
+        assert_eq!(checklist[0].found_by, vec!["Ann"]);
+        assert!(checklist[1].found_by.is_empty());
+        assert!(!group.all_found());
+
+        group.record_message(
+            1,
+            "a cat".to_string(),
+            "Yes!",
+            vec![],
+            vec!["blue cat".to_string()],
+        );
+        assert!(group.all_found());
+        assert_eq!(group.shared.chat.len(), 4);
+        assert_eq!(
+            group.shared.chat[1],
+            ("Teacher".to_string(), "Ann: Well done!".to_string())
+        );
+    }
+
+    #[test]
+    fn broadcasts_each_message_with_the_checklist() {
+        let mut group = group(&["Ann", "Ben"]);
+        let mut events = group.events.subscribe();
+        let ann = group.participants[0].id;
+
+        group.record_message(
+            0,
+            "a red ball".to_string(),
+            "Well done!",
+            vec![],
+            vec!["red ball".to_string()],
+        );
+
+        let event: serde_json::Value = serde_json::from_str(&events.try_recv().unwrap()).unwrap();
+        assert_eq!(event["type"], "message");
+        assert_eq!(event["participant_id"], ann.to_string());
+        assert_eq!(event["messages"][0][0], "Child (Ann)");
+        assert_eq!(event["checklist"][0]["found_by"][0], "Ann");
+    }
+
+    #[test]
+    fn prompts_the_quietest_child_once() {
+        let mut group = group(&["Ann", "Ben"]);
+        for _ in 0..TURN_GAP {
+            group.record_message(0, "hello".to_string(), "Hi!", vec![], vec![]);
+        }
+
+        let (participant_id, text) = group.turn_prompt().unwrap();
+        assert_eq!(participant_id, group.participants[1].id);
+        assert!(text.starts_with("Ben, it's your turn!"));
+        assert!(group.turn_prompt().is_none());
+    }
+
+    #[test]
+    fn removing_a_child_drops_their_chat_lines() {
+        let mut group = group(&["Ann", "Ben"]);
+        let ben = group.participants[1].id;
+        group.record_message(1, "my cat".to_string(), "Lovely!", vec![], vec![]);
+        group.shared.history.push(group.shared.current_round());
+        group.record_message(0, "a ball".to_string(), "Yes!", vec![], vec![]);
+        group.record_message(1, "blue".to_string(), "Good!", vec![], vec![]);
+        group.shared.chat.push((
+            "System".to_string(),
+            "Ben, it's your turn! Look".to_string(),
+        ));
+
+        assert!(group.remove_participant(ben));
+        assert!(!group.remove_participant(ben));
+        assert_eq!(group.participants.len(), 1);
+        assert_eq!(
+            group.shared.chat,
+            vec![
+                ("Child (Ann)".to_string(), "a ball".to_string()),
+                ("Teacher".to_string(), "Ann: Yes!".to_string()),
+            ]
+        );
+        assert!(group.shared.history[0].chat.is_empty());
+    }
+
+    #[test]
+    fn names_must_tell_children_apart() {
+        assert!(validate_unique_name("Ann", ["Ben"]).is_ok());
+        assert!(validate_unique_name(" ann ", ["Ben", "Ann"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn moves_on_and_clears_advancing_without_the_request() {
+        let dir = std::env::temp_dir().join(format!("spectrum-group-{}", std::process::id()));
+        let state = AppState::new(
+            "test".to_string(),
+            "test".to_string(),
+            crate::provider::Provider::Mock,
+            dir.to_string_lossy().into_owned(),
+        )
+        .unwrap()
+        .with_store_dir(&dir);
+        let mut finished = group(&["Ann"]);
+        finished.advancing = true;
+        let shared = finished.shared.clone();
+        let group_id = Uuid::new_v4();
+        state.groups.write().await.insert(group_id, finished);
+        let ctx = CallContext {
+            session_id: group_id,
+            clinic_id: "default".to_string(),
+        };
+
+        // The request goes away as soon as the image is being generated
+        drop(advance_group(state.clone(), group_id, ctx, shared));
+
+        for _ in 0..100 {
+            if let Some(group) = state.groups.read().await.get(&group_id)
+                && !group.advancing
+            {
+                assert_eq!(group.shared.history.len(), 1);
+                assert!(group.shared.image.is_some());
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("the group never moved on to a new image");
+    }
+}
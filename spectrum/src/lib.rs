@@ -0,0 +1,2186 @@
+use axum::{
+    Json, Router,
+    extract::{DefaultBodyLimit, Path, Query, State, WebSocketUpgrade},
+    http::{StatusCode, header},
+    middleware,
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post, put},
+};
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Utc};
+use rand::seq::SliceRandom;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, atomic::AtomicBool},
+};
+use tokio::sync::RwLock;
+use tower_http::services::ServeDir;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+mod activity;
+mod audit;
+mod cache;
+mod compaction;
+mod curriculum;
+pub mod eval;
+mod experiments;
+mod group;
+mod metrics;
+pub mod openapi;
+mod photos;
+mod privacy;
+pub mod provider;
+mod quota;
+mod rewards;
+mod sensory;
+pub mod shutdown;
+mod store;
+mod summary;
+mod therapist;
+mod timeline;
+
+use activity::Activity;
+use audit::{AuditEntry, AuditLog, ParseOutcome, Stage};
+use cache::{CacheKey, ContentCache, Reuse};
+use compaction::ContextBudget;
+use curriculum::{LessonPlan, LessonProgress, LessonStatus};
+use experiments::{Enrollment, Experiment};
+use group::GroupSession;
+use metrics::Metrics;
+use privacy::Redactor;
+use provider::{ModelReply, Provider};
+use quota::{Clinic, ClinicKeys, ClinicUsage, Ledger, RateLimits};
+use rewards::{RewardBook, RewardUpdate};
+use sensory::{ImageMetrics, SensoryProfile};
+use store::{SessionStore, Store};
+use summary::CaregiverSummary;
+
+// Session and state management structures
+#[derive(Clone, Debug, Serialize, Deserialize, Default, ToSchema)]
+struct Detail {
+    detail: String,
+    identified: bool,
+    id: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+struct Session {
+    prompt: Option<String>,
+    image: Option<String>,
+    image_description: Option<String>,
+    chat: Vec<(String, String)>,
+    // Summary standing in for `chat[..compacted_messages]` in evaluation prompts;
+    // `chat` itself is kept whole for the UI
+    chat_summary: Option<String>,
+    compacted_messages: usize,
+    treatment_plan: String,
+    topic_focus: String,
+    key_details: Vec<String>,
+    identified_details: Vec<String>,
+    used_hints: Vec<String>,
+    difficulty: String,
+    age: String,
+    autism_level: String,
+    activity: Activity,
+    sensory_profile: SensoryProfile,
+    sequence: Vec<String>,
+    clinic_id: String,
+    started_at: Option<DateTime<Utc>>,
+    round_started_at: Option<DateTime<Utc>>,
+    history: Vec<RoundRecord>,
+    difficulty_changes: Vec<DifficultyChange>,
+    ended_at: Option<DateTime<Utc>>,
+    summary: Option<CaregiverSummary>,
+    lesson: Option<LessonProgress>,
+    // Rewards carry over between sessions of the same child
+    child_id: Option<String>,
+    // Experiment arm the session runs under
+    experiment: Option<Enrollment>,
+}
+
+// A finished image round, kept after the session moves on to the next image
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RoundRecord {
+    prompt: Option<String>,
+    image: Option<String>,
+    sequence: Vec<String>,
+    difficulty: String,
+    topic_focus: String,
+    activity: Activity,
+    key_details: Vec<String>,
+    identified_details: Vec<String>,
+    used_hints: Vec<String>,
+    chat: Vec<(String, String)>,
+    started_at: Option<DateTime<Utc>>,
+    ended_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DifficultyChange {
+    from: String,
+    to: String,
+    at: DateTime<Utc>,
+    // Round in `history` that triggered the change
+    round: usize,
+}
+
+impl Session {
+    // Snapshot of the image round in progress
+    fn current_round(&self) -> RoundRecord {
+        RoundRecord {
+            prompt: self.prompt.clone(),
+            image: self.image.clone(),
+            sequence: self.sequence.clone(),
+            difficulty: self.difficulty.clone(),
+            topic_focus: self.topic_focus.clone(),
+            activity: self.activity.clone(),
+            key_details: self.key_details.clone(),
+            identified_details: self.identified_details.clone(),
+            used_hints: self.used_hints.clone(),
+            chat: self.chat.clone(),
+            started_at: self.round_started_at,
+            ended_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AppState {
+    sessions: Arc<RwLock<HashMap<Uuid, Session>>>,
+    clients: Arc<RwLock<HashMap<String, tokio::sync::mpsc::Sender<String>>>>,
+    huggingface_token: String,
+    google_api_key: String,
+    http_client: Client,
+    provider: Provider,
+    // Providers `/sessions/:id/replay` may use, by name
+    replay_providers: Arc<HashMap<String, Provider>>,
+    audit: Arc<AuditLog>,
+    rate_limits: Arc<RateLimits>,
+    clinic_keys: Arc<ClinicKeys>,
+    ledger: Arc<Ledger>,
+    image_library: Arc<RwLock<Vec<LibraryImage>>>,
+    cache: Arc<ContentCache>,
+    redactor: Arc<Redactor>,
+    metrics: Arc<Metrics>,
+    context_budget: ContextBudget,
+    lesson_plans: Arc<RwLock<HashMap<Uuid, LessonPlan>>>,
+    lesson_plan_store: Arc<Store<HashMap<Uuid, LessonPlan>>>,
+    experiments: Arc<RwLock<HashMap<Uuid, Experiment>>>,
+    experiment_store: Arc<Store<HashMap<Uuid, Experiment>>>,
+    groups: Arc<RwLock<HashMap<Uuid, GroupSession>>>,
+    group_store: Arc<Store<HashMap<Uuid, GroupSession>>>,
+    session_store: Arc<SessionStore>,
+    rewards: Arc<RewardBook>,
+    // Set once a shutdown has started; new requests are turned away
+    draining: Arc<AtomicBool>,
+    // Live updates for a single session's child and therapist sockets
+    session_events: Arc<RwLock<HashMap<Uuid, tokio::sync::broadcast::Sender<String>>>>,
+    // Therapist controls are disabled unless THERAPIST_TOKEN is set
+    therapist_token: Option<String>,
+}
+
+impl AppState {
+    // Fails on invalid configuration in the environment
+    pub fn new(
+        huggingface_token: String,
+        google_api_key: String,
+        provider: Provider,
+        audit_dir: String,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            huggingface_token,
+            google_api_key,
+            http_client: Client::new(),
+            replay_providers: Arc::new(provider::replay_providers(&provider)?),
+            provider,
+            audit: Arc::new(AuditLog::new(audit_dir)),
+            rate_limits: Arc::new(RateLimits::from_env()),
+            clinic_keys: Arc::new(ClinicKeys::from_env()?),
+            ledger: Arc::new(Ledger::from_env()?),
+            image_library: Arc::new(RwLock::new(Vec::new())),
+            cache: Arc::new(ContentCache::from_env()),
+            redactor: Arc::new(Redactor::from_env()),
+            metrics: Arc::new(Metrics::new()),
+            context_budget: ContextBudget::from_env(),
+            lesson_plans: Arc::new(RwLock::new(HashMap::new())),
+            lesson_plan_store: Arc::new(Store::from_env(
+                "LESSON_PLAN_STORE_PATH",
+                "lesson_plans.json",
+            )),
+            experiments: Arc::new(RwLock::new(HashMap::new())),
+            experiment_store: Arc::new(Store::from_env(
+                "EXPERIMENT_STORE_PATH",
+                "experiments.json",
+            )),
+            groups: Arc::new(RwLock::new(HashMap::new())),
+            group_store: Arc::new(Store::from_env("GROUP_STORE_PATH", "groups.json")),
+            session_store: Arc::new(SessionStore::from_env(
+                "SESSION_STORE_PATH",
+                "sessions.json",
+            )),
+            rewards: Arc::new(RewardBook::from_env()),
+            draining: Arc::new(AtomicBool::new(false)),
+            session_events: Arc::new(RwLock::new(HashMap::new())),
+            therapist_token: std::env::var("THERAPIST_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+        })
+    }
+
+    // Enables the therapist routes with `token`, whatever THERAPIST_TOKEN says
+    pub fn with_therapist_token(mut self, token: impl Into<String>) -> Self {
+        self.therapist_token = Some(token.into());
+        self
+    }
+
+    // Keeps every store in `dir`, whatever the *_STORE_PATH variables say
+    pub fn with_store_dir(mut self, dir: impl AsRef<std::path::Path>) -> Self {
+        let dir = dir.as_ref();
+        let cipher = privacy::StoreCipher::from_env;
+        self.session_store = Arc::new(Store::new(dir.join("sessions.json"), cipher()));
+        self.rewards = Arc::new(RewardBook::new(Store::new(
+            dir.join("rewards.json"),
+            cipher(),
+        )));
+        self.ledger = Arc::new(
+            self.ledger
+                .with_store(Store::new(dir.join("usage.json"), cipher())),
+        );
+        self.lesson_plan_store = Arc::new(Store::new(dir.join("lesson_plans.json"), cipher()));
+        self.experiment_store = Arc::new(Store::new(dir.join("experiments.json"), cipher()));
+        self.group_store = Arc::new(Store::new(dir.join("groups.json"), cipher()));
+        self
+    }
+
+    // Bills requests to the clinic whose key they present, whatever
+    // CLINIC_KEYS_FILE says; `keys` maps clinic ids to API keys
+    pub fn with_clinic_keys(mut self, keys: HashMap<String, String>) -> Self {
+        self.clinic_keys = Arc::new(ClinicKeys::new(keys));
+        self
+    }
+
+    // Channel for a session's live events, created on first use
+    async fn session_events(&self, session_id: Uuid) -> tokio::sync::broadcast::Sender<String> {
+        if let Some(events) = self.session_events.read().await.get(&session_id) {
+            return events.clone();
+        }
+        self.session_events
+            .write()
+            .await
+            .entry(session_id)
+            .or_insert_with(|| tokio::sync::broadcast::channel(32).0)
+            .clone()
+    }
+
+    // Picks up the sessions saved before the last shutdown or crash, the
+    // group sessions, the children's rewards, the clinics' usage, the lesson
+    // plans and the experiments
+    pub async fn restore(&self) {
+        match self.rewards.load().await {
+            Ok(children) => tracing::info!(children, "Restored rewards"),
+            Err(err) => tracing::error!(%err, "Failed to restore rewards, starting empty"),
+        }
+        match self.ledger.load().await {
+            Ok(clinics) => tracing::info!(clinics, "Restored clinic usage"),
+            Err(err) => tracing::error!(%err, "Failed to restore clinic usage, starting empty"),
+        }
+        match self.lesson_plan_store.load().await {
+            Ok(plans) => {
+                tracing::info!(plans = plans.len(), "Restored lesson plans");
+                *self.lesson_plans.write().await = plans;
+            }
+            Err(err) => tracing::error!(%err, "Failed to restore lesson plans, starting empty"),
+        }
+        match self.experiment_store.load().await {
+            Ok(experiments) => {
+                tracing::info!(experiments = experiments.len(), "Restored experiments");
+                *self.experiments.write().await = experiments;
+            }
+            Err(err) => tracing::error!(%err, "Failed to restore experiments, starting empty"),
+        }
+        match self.session_store.load().await {
+            Ok(sessions) => {
+                tracing::info!(sessions = sessions.len(), "Restored sessions");
+                set_active_sessions(&self.metrics, &sessions);
+                *self.sessions.write().await = sessions;
+            }
+            Err(err) => tracing::error!(%err, "Failed to restore sessions, starting empty"),
+        }
+        match self.group_store.load().await {
+            Ok(groups) => {
+                tracing::info!(groups = groups.len(), "Restored group sessions");
+                *self.groups.write().await = groups;
+            }
+            Err(err) => tracing::error!(%err, "Failed to restore group sessions, starting empty"),
+        }
+    }
+}
+
+// Identifies the session an upstream model call is made for
+#[derive(Clone, Debug)]
+struct CallContext {
+    session_id: Uuid,
+    clinic_id: String,
+}
+
+// API structures for external service communication
+#[derive(Debug, Serialize, Deserialize)]
+struct HuggingFaceRequest {
+    inputs: String,
+    parameters: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GoogleRequest {
+    contents: Vec<GoogleContent>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GoogleContent {
+    parts: Vec<GooglePart>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GooglePart {
+    text: Option<String>,
+    inline_data: Option<GoogleInlineData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GoogleInlineData {
+    mime_type: String,
+    data: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FeedbackResponse {
+    feedback: String,
+    newly_identified_details: Vec<String>,
+    hint: String,
+    score: f32,
+    advance_difficulty: bool,
+}
+
+// Builds the HTTP application. Kept separate from `main` so tests can drive
+// the routes with `tower::ServiceExt::oneshot` against a mock state.
+pub fn app(state: AppState) -> Router {
+    let therapist_routes = Router::new()
+        .route(
+            "/sessions/:session_id/overrides",
+            post(therapist::override_handler),
+        )
+        .route(
+            "/sessions/:session_id/therapist/ws",
+            get(therapist::therapist_ws_handler),
+        )
+        .route(
+            "/children/:child_id/reward_schedule",
+            put(rewards::set_reward_schedule_handler),
+        )
+        .route(
+            "/children/:child_id/export",
+            get(privacy::export_child_handler),
+        )
+        .route("/children/:child_id", delete(privacy::delete_child_handler))
+        .route(
+            "/sessions/:session_id/timeline",
+            get(timeline::session_timeline_handler),
+        )
+        .route("/sessions/:session_id/audit", get(session_audit_handler))
+        .route("/experiments", post(experiments::create_experiment_handler))
+        .route(
+            "/lesson_plans",
+            post(curriculum::create_lesson_plan_handler),
+        )
+        .route(
+            "/lesson_plans/:plan_id",
+            put(curriculum::update_lesson_plan_handler)
+                .delete(curriculum::delete_lesson_plan_handler),
+        )
+        .route(
+            "/experiments/:experiment_id/stop",
+            post(experiments::stop_experiment_handler),
+        )
+        .route(
+            "/sessions/:session_id/summary",
+            get(session_summary_handler),
+        )
+        .route("/sessions/:session_id/ws", get(session_ws_handler))
+        .route(
+            "/children/:child_id/rewards",
+            get(rewards::child_rewards_handler),
+        )
+        .route("/sessions/:session_id/replay", post(replay_session_handler))
+        .route(
+            "/cache",
+            get(cache::cache_report_handler).delete(cache::purge_cache_handler),
+        )
+        .route("/cache/:key", delete(cache::purge_cache_entry_handler))
+        .route(
+            "/photo_sessions",
+            post(photos::create_photo_session_handler)
+                .layer(DefaultBodyLimit::max(photos::max_upload_bytes()))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    quota::rate_limit,
+                )),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            therapist::require_therapist,
+        ));
+
+    Router::new()
+        .route("/generate_image", post(generate_image_handler))
+        .route("/process_chat", post(process_chat_handler))
+        .route("/sessions/:session_id/end", post(end_session_handler))
+        .route("/groups", post(group::create_group_handler))
+        .route(
+            "/groups/:group_id/messages",
+            post(group::group_message_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            quota::rate_limit,
+        ))
+        .merge(therapist_routes)
+        .route("/", get(index_handler))
+        .route("/ws", get(ws_handler))
+        .route("/groups/:group_id", get(group::get_group_handler))
+        .route(
+            "/groups/:group_id/participants",
+            post(group::join_group_handler),
+        )
+        .route("/groups/:group_id/ws", get(group::group_ws_handler))
+        .route("/clinics/:clinic_id/usage", get(clinic_usage_handler))
+        .route("/lesson_plans", get(curriculum::list_lesson_plans_handler))
+        .route(
+            "/lesson_plans/:plan_id",
+            get(curriculum::get_lesson_plan_handler),
+        )
+        .route("/experiments", get(experiments::list_experiments_handler))
+        .route(
+            "/experiments/:experiment_id/report",
+            get(experiments::experiment_report_handler),
+        )
+        .route(
+            "/sessions/:session_id/lesson",
+            get(curriculum::session_lesson_handler),
+        )
+        .route("/metrics", get(metrics::metrics_handler))
+        .route("/openapi.json", get(openapi::openapi_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_requests,
+        ))
+        .nest_service("/static", ServeDir::new("static"))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            shutdown::reject_while_draining,
+        ))
+        .with_state(state)
+}
+
+// Main page handler
+async fn index_handler() -> impl IntoResponse {
+    Html(include_str!("../templates/index.html"))
+}
+
+// WebSocket handler for real-time updates
+async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
+    ws.on_upgrade(|socket| handle_socket(socket, state, None))
+}
+
+// WebSocket for a single child's session, carrying therapist overrides live
+async fn session_ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !state.sessions.read().await.contains_key(&session_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let events = state.session_events(session_id).await.subscribe();
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, Some(events))))
+}
+
+// Forwards server events to the client: shutdown notices to everyone, and the
+// group's or session's events when the socket is subscribed to one
+async fn handle_socket(
+    mut socket: axum::extract::ws::WebSocket,
+    state: AppState,
+    mut group_events: Option<tokio::sync::broadcast::Receiver<String>>,
+) {
+    use axum::extract::ws::Message;
+
+    // Register the client so server-side events (e.g. shutdown) can reach it
+    let client_id = Uuid::new_v4().to_string();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<String>(16);
+    state
+        .clients
+        .write()
+        .await
+        .insert(client_id.clone(), sender);
+
+    loop {
+        tokio::select! {
+            Some(message) = receiver.recv() => {
+                if socket.send(Message::Text(message)).await.is_err() {
+                    break;
+                }
+                // The shutdown notice is the last message a client gets
+                if state.draining.load(std::sync::atomic::Ordering::SeqCst) {
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            }
+            Some(event) = next_group_event(&mut group_events) => {
+                if socket.send(Message::Text(event)).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    state.clients.write().await.remove(&client_id);
+}
+
+async fn next_group_event(
+    events: &mut Option<tokio::sync::broadcast::Receiver<String>>,
+) -> Option<String> {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let Some(events) = events else {
+        return std::future::pending().await;
+    };
+    loop {
+        match events.recv().await {
+            Ok(event) => return Some(event),
+            // A slow client skips what it missed; the next event carries the full checklist
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+// Generate image API endpoint
+#[derive(Debug, Deserialize, ToSchema)]
+struct GenerateImageRequest {
+    age: String,
+    autism_level: String,
+    // Required unless a lesson plan is given, which replaces it
+    #[serde(default)]
+    topic_focus: String,
+    treatment_plan: String,
+    #[serde(default)]
+    activity: Activity,
+    // Lesson plan to move the child through; its first topic replaces `topic_focus` and `activity`
+    #[serde(default)]
+    lesson_plan_id: Option<Uuid>,
+    #[serde(default)]
+    sensory_profile: SensoryProfile,
+    // Child the session's rewards are credited to; without one they only
+    // last for this session
+    #[serde(default)]
+    child_id: Option<String>,
+    // Experiment to enroll in; sessions without one aren't in any experiment
+    #[serde(default)]
+    experiment_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct GenerateImageResponse {
+    image: String,
+    // All pictures of a sequencing activity, in the order shown to the child
+    sequence: Vec<String>,
+    session_id: Uuid,
+    activity: Activity,
+    instructions: String,
+    checklist: Vec<Detail>,
+    lesson: Option<LessonStatus>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/generate_image",
+    request_body = GenerateImageRequest,
+    params(("x-api-key" = Option<String>, Header, nullable = false, description = "Clinic API key, required when clinic keys are configured")),
+    responses(
+        (status = 200, description = "New session with its first image", body = GenerateImageResponse),
+        (status = 400, description = "No topic_focus and no lesson plan"),
+        (status = 401, description = "Missing or unknown API key"),
+        (status = 404, description = "Unknown lesson plan or experiment"),
+        (status = 409, description = "Experiment is stopped"),
+        (status = 429, description = "Rate limit exceeded"),
+        (status = 502, description = "Image generation failed")
+    )
+)]
+async fn generate_image_handler(
+    State(state): State<AppState>,
+    Clinic(clinic_id): Clinic,
+    Json(mut request): Json<GenerateImageRequest>,
+) -> Result<Json<GenerateImageResponse>, StatusCode> {
+    let session_id = Uuid::new_v4();
+    let ctx = CallContext {
+        session_id,
+        clinic_id,
+    };
+
+    // 1. Start on the lesson plan's first topic, if the child follows one
+    let lesson = match request.lesson_plan_id {
+        Some(plan_id) => {
+            let plans = state.lesson_plans.read().await;
+            let plan = plans.get(&plan_id).ok_or(StatusCode::NOT_FOUND)?;
+            Some(LessonProgress::new(plan.clone()))
+        }
+        None if request.topic_focus.trim().is_empty() => return Err(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+    if let Some(lesson) = &lesson {
+        request.topic_focus = lesson.topic().topic_focus.clone();
+        request.activity = lesson.topic().activity.clone();
+    }
+
+    // 2. Assign an experiment arm, keyed on the child so they keep it
+    let child_key = request
+        .child_id
+        .clone()
+        .unwrap_or_else(|| session_id.to_string());
+    let experiment = experiments::enroll(&state, request.experiment_id, &child_key).await?;
+
+    // 3. Run the image pipeline for the requested activity
+    let spec = RoundSpec {
+        difficulty: "Very Simple",
+        age: &request.age,
+        autism_level: &request.autism_level,
+        topic_focus: &request.topic_focus,
+        treatment_plan: &request.treatment_plan,
+        activity: &request.activity,
+        sensory_profile: &request.sensory_profile,
+        prompt_guidance: experiment
+            .as_ref()
+            .and_then(|enrollment| enrollment.arm.prompt_guidance.as_deref()),
+    };
+    let round = generate_round(spec, &ctx, &state).await?;
+
+    // 4. Create new session
+    let session = Session {
+        prompt: Some(round.prompt),
+        image: Some(round.image),
+        image_description: Some(round.description),
+        difficulty: "Very Simple".to_string(),
+        age: request.age,
+        autism_level: request.autism_level,
+        topic_focus: request.topic_focus,
+        treatment_plan: request.treatment_plan,
+        key_details: round.key_details,
+        activity: request.activity,
+        sensory_profile: request.sensory_profile,
+        sequence: round.sequence,
+        clinic_id: ctx.clinic_id,
+        started_at: Some(Utc::now()),
+        round_started_at: Some(Utc::now()),
+        lesson,
+        child_id: request.child_id,
+        experiment,
+        ..Default::default()
+    };
+
+    // 5. Store session and return its first image
+    Ok(Json(open_session(&state, session_id, session).await))
+}
+
+// Updates the active sessions gauge; ended sessions stay in memory for their
+// summary but no longer count
+fn set_active_sessions(metrics: &Metrics, sessions: &HashMap<Uuid, Session>) {
+    let active = sessions
+        .values()
+        .filter(|session| session.ended_at.is_none())
+        .count();
+    metrics.active_sessions.set(active as i64);
+}
+
+// Stores a new session and builds the response that starts it on the client
+async fn open_session(
+    state: &AppState,
+    session_id: Uuid,
+    session: Session,
+) -> GenerateImageResponse {
+    let mut sessions = state.sessions.write().await;
+    sessions.insert(session_id, session.clone());
+    set_active_sessions(&state.metrics, &sessions);
+    drop(sessions);
+
+    GenerateImageResponse {
+        image: session.image.clone().unwrap_or_default(),
+        instructions: session.activity.child_instructions(),
+        checklist: session.checklist(),
+        lesson: session.lesson.as_ref().map(LessonProgress::status),
+        sequence: session.sequence,
+        session_id,
+        activity: session.activity,
+    }
+}
+
+// Process chat API endpoint
+#[derive(Debug, Deserialize, ToSchema)]
+struct ProcessChatRequest {
+    user_message: String,
+    session_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ProcessChatResponse {
+    // (speaker, message) pairs for the current image
+    #[schema(value_type = Vec<Vec<String>>)]
+    chat: Vec<(String, String)>,
+    checklist: Vec<Detail>,
+    // Set when the child finished the image or advanced a difficulty level
+    new_image: Option<String>,
+    sequence: Option<Vec<String>>,
+    // Set when the session follows a lesson plan
+    lesson: Option<LessonStatus>,
+    rewards: RewardUpdate,
+}
+
+#[utoipa::path(
+    post,
+    path = "/process_chat",
+    request_body = ProcessChatRequest,
+    responses(
+        (status = 200, description = "Teacher feedback and updated checklist", body = ProcessChatResponse),
+        (status = 400, description = "Invalid session id"),
+        (status = 404, description = "Unknown session"),
+        (status = 429, description = "Rate limit exceeded"),
+        (status = 502, description = "Image generation for the next round failed")
+    )
+)]
+async fn process_chat_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ProcessChatRequest>,
+) -> Result<Json<ProcessChatResponse>, StatusCode> {
+    let session_id = Uuid::parse_str(&request.session_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    // Names and places never reach the model or the stored chat
+    let user_message = state.redactor.redact(&request.user_message);
+
+    // 1. Get current session
+    let mut sessions = state.sessions.write().await;
+    let session = sessions.get_mut(&session_id).ok_or(StatusCode::NOT_FOUND)?;
+    let ctx = CallContext {
+        session_id,
+        clinic_id: session.clinic_id.clone(),
+    };
+
+    // 2. Keep the evaluation prompt within the context budget
+    compact_chat_history(session, &ctx, &state).await;
+
+    // 3. Evaluate the child's description
+    let evaluation = compare_details(&user_message, session, &ctx, &state).await;
+
+    // 4. Parse evaluation response
+    let (feedback, new_difficulty, model_advance, newly_identified) =
+        parse_evaluation(&evaluation, session);
+
+    // 5. Update session with identified details
+    let mut details_found = 0;
+    for detail in &newly_identified {
+        if !session.identified_details.contains(detail) {
+            session.identified_details.push(detail.clone());
+            details_found += 1;
+        }
+    }
+    let child_id = session.child_key(session_id);
+
+    // The session's experiment arm may override when the child moves up a level
+    let should_advance = match &session.experiment {
+        Some(enrollment) => enrollment
+            .arm
+            .progression
+            .should_advance(model_advance, session),
+        None => model_advance,
+    };
+    let new_difficulty = if should_advance && !model_advance {
+        next_difficulty(&session.difficulty)
+    } else {
+        new_difficulty
+    };
+
+    // 6. Add to chat history
+    session.chat.push(("Child".to_string(), user_message));
+    session.chat.push(("Teacher".to_string(), feedback.clone()));
+
+    // 7. Check if all items are identified
+    let all_identified = session.identified_details.len() >= session.key_details.len();
+
+    // 8. Handle difficulty advancement or completion
+    if should_advance || all_identified {
+        // Generate new image with updated difficulty
+        let difficulty = if should_advance {
+            new_difficulty
+        } else {
+            session.difficulty.clone()
+        };
+        let (new_image, rewards) = finish_round(
+            session,
+            &child_id,
+            difficulty,
+            should_advance,
+            details_found,
+            &ctx,
+            &state,
+        )
+        .await?;
+
+        // Return response with new image and updated session data
+        return Ok(Json(ProcessChatResponse {
+            chat: session.chat.clone(),
+            checklist: session.checklist(),
+            new_image: Some(new_image),
+            sequence: Some(session.sequence.clone()),
+            lesson: session.lesson.as_ref().map(LessonProgress::status),
+            rewards,
+        }));
+    }
+
+    // 9. Update checklist with newly identified items
+    let checklist = session.checklist();
+    let rewards = state.rewards.award(&child_id, details_found, None).await;
+
+    // 10. Return chat and updated checklist
+    Ok(Json(ProcessChatResponse {
+        chat: session.chat.clone(),
+        checklist,
+        new_image: None,
+        sequence: None,
+        lesson: session.lesson.as_ref().map(LessonProgress::status),
+        rewards,
+    }))
+}
+
+// Finishes the current image: counts it towards the lesson plan and the
+// child's rewards, starts the next image at `difficulty` and tells the child
+// why. Returns the new image and the rewards the child earned. The session is
+// left as it was if the next image can't be generated.
+async fn finish_round(
+    session: &mut Session,
+    child_id: &str,
+    difficulty: String,
+    advanced: bool,
+    details_found: usize,
+    ctx: &CallContext,
+    state: &AppState,
+) -> Result<(String, RewardUpdate), StatusCode> {
+    // Count the finished image towards the lesson plan, moving on to the
+    // next topic once the current one is mastered
+    let finished = session.current_round();
+    let lesson_completed = session
+        .lesson
+        .as_ref()
+        .is_some_and(|lesson| lesson.completed);
+    let mut lesson = session.lesson.clone();
+    let next_topic = lesson
+        .as_mut()
+        .and_then(|lesson| lesson.record_round(&finished));
+
+    let mut spec = session.round_spec(&difficulty);
+    if let Some(topic) = &next_topic {
+        spec.topic_focus = &topic.topic_focus;
+        spec.activity = &topic.activity;
+    }
+    let round = generate_round(spec, ctx, state).await?;
+
+    session.lesson = lesson;
+    if let Some(topic) = &next_topic {
+        session.topic_focus = topic.topic_focus.clone();
+        session.activity = topic.activity.clone();
+    }
+    let plan_finished = !lesson_completed
+        && session
+            .lesson
+            .as_ref()
+            .is_some_and(|lesson| lesson.completed);
+
+    let rewards = state
+        .rewards
+        .award(child_id, details_found, Some(&finished))
+        .await;
+
+    // Keep the finished round for summaries and reports
+    session.history.push(finished);
+    if difficulty != session.difficulty {
+        session.difficulty_changes.push(DifficultyChange {
+            from: session.difficulty.clone(),
+            to: difficulty.clone(),
+            at: Utc::now(),
+            round: session.history.len() - 1,
+        });
+    }
+
+    // Create new session
+    session.difficulty = difficulty;
+    let new_image = session.start_round(round);
+
+    // Create advancement message
+    let mut advancement_message = if let Some(topic) = &next_topic {
+        format!(
+            "Amazing work! You're ready for something new: {}. Here's a new image. {}",
+            topic.topic_focus,
+            session.activity.child_instructions()
+        )
+    } else if plan_finished {
+        format!(
+            "Wonderful! You've finished the whole lesson plan! Here's another image to practise with. {}",
+            session.activity.child_instructions()
+        )
+    } else if advanced {
+        format!(
+            "Congratulations! You've advanced to {} difficulty! Here's a new image. {}",
+            session.difficulty,
+            session.activity.child_instructions()
+        )
+    } else {
+        format!(
+            "Great job identifying all the details! Here's a new image at the same difficulty level. {}",
+            session.activity.child_instructions()
+        )
+    };
+
+    for sticker in &rewards.new_stickers {
+        advancement_message.push_str(&format!(" You unlocked a new sticker: {}!", sticker.name));
+    }
+    session
+        .chat
+        .push(("System".to_string(), advancement_message));
+
+    Ok((new_image, rewards))
+}
+
+// Everything the image pipeline produces for one round of an activity
+#[derive(Clone, Debug)]
+struct GeneratedRound {
+    prompt: String,
+    image: String,
+    description: String,
+    key_details: Vec<String>,
+    sequence: Vec<String>,
+}
+
+// Previously generated round, reused when a clinic is over its monthly budget
+#[derive(Clone, Debug)]
+struct LibraryImage {
+    // Session the round was generated for, so a child's data can be deleted
+    session_id: Uuid,
+    difficulty: String,
+    topic_focus: String,
+    activity: Activity,
+    sensory_profile: SensoryProfile,
+    round: GeneratedRound,
+}
+
+const IMAGE_LIBRARY_CAPACITY: usize = 500;
+
+// The child and settings a round is generated for
+#[derive(Clone, Copy, Debug)]
+struct RoundSpec<'a> {
+    difficulty: &'a str,
+    age: &'a str,
+    autism_level: &'a str,
+    topic_focus: &'a str,
+    treatment_plan: &'a str,
+    activity: &'a Activity,
+    sensory_profile: &'a SensoryProfile,
+    // Extra prompt instructions from the session's experiment arm
+    prompt_guidance: Option<&'a str>,
+}
+
+// Difficulty levels, from easiest to hardest
+const DIFFICULTIES: [&str; 5] = [
+    "Very Simple",
+    "Simple",
+    "Moderate",
+    "Detailed",
+    "Very Detailed",
+];
+
+impl Session {
+    // Replaces the current image with a freshly generated round and clears the
+    // per-image progress. Returns the image shown to the child.
+    fn start_round(&mut self, round: GeneratedRound) -> String {
+        self.round_started_at = Some(Utc::now());
+        self.prompt = Some(round.prompt);
+        self.image = Some(round.image.clone());
+        self.image_description = Some(round.description);
+        self.key_details = round.key_details;
+        self.sequence = round.sequence;
+        self.identified_details = vec![];
+        self.used_hints = vec![];
+        self.chat = vec![];
+        self.chat_summary = None;
+        self.compacted_messages = 0;
+        round.image
+    }
+
+    // Checklist for the current image
+    fn checklist(&self) -> Vec<Detail> {
+        self.key_details
+            .iter()
+            .enumerate()
+            .map(|(id, detail)| Detail {
+                detail: detail.clone(),
+                identified: self
+                    .identified_details
+                    .iter()
+                    .any(|identified| similar_details(identified, detail)),
+                id,
+            })
+            .collect()
+    }
+
+    // Whose rewards and records the session belongs to; anonymous sessions
+    // stand on their own
+    fn child_key(&self, session_id: Uuid) -> String {
+        self.child_id
+            .clone()
+            .unwrap_or_else(|| session_id.to_string())
+    }
+
+    // Every image shown in the session, including finished rounds
+    fn images(&self) -> Vec<String> {
+        let rounds = self
+            .history
+            .iter()
+            .map(|round| (&round.image, &round.sequence));
+        [(&self.image, &self.sequence)]
+            .into_iter()
+            .chain(rounds)
+            .flat_map(|(image, sequence)| image.iter().chain(sequence))
+            .cloned()
+            .collect()
+    }
+
+    // Settings for the session's next round at `difficulty`
+    fn round_spec<'a>(&'a self, difficulty: &'a str) -> RoundSpec<'a> {
+        RoundSpec {
+            difficulty,
+            age: &self.age,
+            autism_level: &self.autism_level,
+            topic_focus: &self.topic_focus,
+            treatment_plan: &self.treatment_plan,
+            activity: &self.activity,
+            sensory_profile: &self.sensory_profile,
+            prompt_guidance: self
+                .experiment
+                .as_ref()
+                .and_then(|enrollment| enrollment.arm.prompt_guidance.as_deref()),
+        }
+    }
+}
+
+// Produces the next round, from the image library once the clinic's budget
+// is spent. Fails with 502 when the image model does.
+async fn generate_round(
+    spec: RoundSpec<'_>,
+    ctx: &CallContext,
+    state: &AppState,
+) -> Result<GeneratedRound, StatusCode> {
+    if state.ledger.over_budget(&ctx.clinic_id) {
+        if let Some(round) = cached_round(spec, state).await {
+            return Ok(round);
+        }
+        tracing::warn!(
+            clinic = %ctx.clinic_id,
+            difficulty = spec.difficulty,
+            "Clinic is over its monthly budget but the image library has no image at this difficulty; generating a new one"
+        );
+    }
+
+    let round = run_image_pipeline(spec, ctx, state).await?;
+
+    let mut library = state.image_library.write().await;
+    if library.len() >= IMAGE_LIBRARY_CAPACITY {
+        library.remove(0);
+    }
+    library.push(LibraryImage {
+        session_id: ctx.session_id,
+        difficulty: spec.difficulty.to_string(),
+        topic_focus: spec.topic_focus.to_string(),
+        activity: spec.activity.clone(),
+        sensory_profile: spec.sensory_profile.clone(),
+        round: round.clone(),
+    });
+
+    Ok(round)
+}
+
+// Picks a cached round for the same activity, difficulty and sensory profile,
+// preferring the same topic
+async fn cached_round(spec: RoundSpec<'_>, state: &AppState) -> Option<GeneratedRound> {
+    let library = state.image_library.read().await;
+    let candidates: Vec<&LibraryImage> = library
+        .iter()
+        .filter(|entry| {
+            &entry.activity == spec.activity
+                && entry.difficulty == spec.difficulty
+                && &entry.sensory_profile == spec.sensory_profile
+        })
+        .collect();
+    let same_topic: Vec<&LibraryImage> = candidates
+        .iter()
+        .copied()
+        .filter(|entry| entry.topic_focus.eq_ignore_ascii_case(spec.topic_focus))
+        .collect();
+
+    let pool = if same_topic.is_empty() {
+        candidates
+    } else {
+        same_topic
+    };
+    pool.choose(&mut rand::thread_rng())
+        .map(|entry| entry.round.clone())
+}
+
+// Runs prompt -> image -> description -> key details for every image the activity needs
+#[tracing::instrument(skip_all, fields(session_id = %ctx.session_id, difficulty = %spec.difficulty, activity = ?spec.activity))]
+async fn run_image_pipeline(
+    spec: RoundSpec<'_>,
+    ctx: &CallContext,
+    state: &AppState,
+) -> Result<GeneratedRound, StatusCode> {
+    let RoundSpec {
+        difficulty,
+        topic_focus,
+        activity,
+        sensory_profile,
+        prompt_guidance,
+        ..
+    } = spec;
+    let mut prompts: Vec<String> = Vec::new();
+    let mut images = Vec::new();
+    let mut descriptions = Vec::new();
+
+    for step in 0..activity.image_count() {
+        let mut guidance = activity.generation_guidance(step);
+        guidance.push_str(&format!("\n        {}", sensory_profile.prompt_guidance()));
+        if let Some(extra) = prompt_guidance {
+            guidance.push_str(&format!("\n        {}", extra));
+        }
+        if let Some(previous) = prompts.last() {
+            guidance.push_str(&format!(
+                "\n        The previous picture in the story was generated from this prompt: \"{}\"",
+                previous
+            ));
+        }
+
+        let prompt = generate_prompt(spec, &guidance, ctx, state).await;
+        let image_data = generate_image(&prompt, sensory_profile, ctx, state).await?;
+        let description =
+            generate_description(&image_data, &prompt, difficulty, topic_focus, ctx, state).await;
+
+        prompts.push(prompt);
+        images.push(image_data);
+        descriptions.push(description);
+    }
+
+    if images.len() == 1 {
+        let description = descriptions.remove(0);
+        let key_details = extract_key_details(&description, activity, ctx, state).await;
+        return Ok(GeneratedRound {
+            prompt: prompts.remove(0),
+            image: images.remove(0),
+            description,
+            key_details,
+            sequence: vec![],
+        });
+    }
+
+    // Shuffle the pictures for the child; the description keeps the story order
+    let mut order: Vec<usize> = (0..images.len()).collect();
+    order.shuffle(&mut rand::thread_rng());
+
+    let description = descriptions
+        .iter()
+        .enumerate()
+        .map(|(step, text)| {
+            let shown_as = order.iter().position(|&i| i == step).unwrap() + 1;
+            format!(
+                "Story step {} (shown to the child as picture {}):\n{}",
+                step + 1,
+                shown_as,
+                text
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let key_details = extract_key_details(&description, activity, ctx, state).await;
+
+    Ok(GeneratedRound {
+        prompt: prompts.join("\n\n"),
+        image: images[order[0]].clone(),
+        description,
+        key_details,
+        sequence: order.iter().map(|&i| images[i].clone()).collect(),
+    })
+}
+
+// Session summary API endpoints
+#[utoipa::path(
+    post,
+    path = "/sessions/{session_id}/end",
+    params(("session_id" = Uuid, Path, description = "Session to end")),
+    responses(
+        (status = 200, description = "Caregiver summary for the session", body = CaregiverSummary),
+        (status = 404, description = "Unknown session")
+    )
+)]
+async fn end_session_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<CaregiverSummary>, StatusCode> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions.get_mut(&session_id).ok_or(StatusCode::NOT_FOUND)?;
+    let ctx = CallContext {
+        session_id,
+        clinic_id: session.clinic_id.clone(),
+    };
+
+    // Ask Gemini for the caregiver note, falling back to a template
+    let reply = call_model(
+        Stage::SummarizeSession,
+        "gemini-2.0-flash-lite",
+        &summary::summary_query(session),
+        None,
+        &ctx,
+        &state,
+    )
+    .await;
+    let summary = reply
+        .text
+        .as_deref()
+        .and_then(summary::parse_summary)
+        .unwrap_or_else(|| summary::fallback_summary(session));
+
+    session.ended_at = Some(Utc::now());
+    session.summary = Some(summary.clone());
+    set_active_sessions(&state.metrics, &sessions);
+    Ok(Json(summary))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SummaryFormat {
+    /// "json" (default), "html" or "pdf"
+    format: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions/{session_id}/summary",
+    params(
+        ("session_id" = Uuid, Path, description = "Ended session"),
+        SummaryFormat,
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Caregiver summary, as JSON unless another format is requested", body = CaregiverSummary),
+        (status = 400, description = "Unknown format"),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "Unknown session or session not ended")
+    )
+)]
+async fn session_summary_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+    Query(query): Query<SummaryFormat>,
+) -> Result<Response, StatusCode> {
+    let sessions = state.sessions.read().await;
+    let session = sessions.get(&session_id).ok_or(StatusCode::NOT_FOUND)?;
+    let summary = session.summary.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(match query.format.as_deref().unwrap_or("json") {
+        "json" => Json(summary.clone()).into_response(),
+        "html" => Html(summary::render_html(session, summary)).into_response(),
+        "pdf" => (
+            [
+                (header::CONTENT_TYPE, "application/pdf"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"session-summary.pdf\"",
+                ),
+            ],
+            summary::render_pdf(session, summary),
+        )
+            .into_response(),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    })
+}
+
+// Usage API endpoint
+#[derive(Debug, Serialize, ToSchema)]
+struct ClinicUsageResponse {
+    clinic_id: String,
+    usage: ClinicUsage,
+    monthly_budget_usd: Option<f64>,
+    over_budget: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/clinics/{clinic_id}/usage",
+    params(
+        ("clinic_id" = String, Path, description = "Clinic the API key belongs to"),
+        ("x-api-key" = Option<String>, Header, nullable = false, description = "The clinic's API key, required when clinic keys are configured")
+    ),
+    responses(
+        (status = 200, description = "Usage for the current month", body = ClinicUsageResponse),
+        (status = 401, description = "Missing or unknown API key"),
+        (status = 403, description = "The API key belongs to another clinic")
+    )
+)]
+async fn clinic_usage_handler(
+    State(state): State<AppState>,
+    Clinic(caller): Clinic,
+    Path(clinic_id): Path<String>,
+) -> Result<Json<ClinicUsageResponse>, StatusCode> {
+    // A clinic only sees its own spend
+    if caller != clinic_id {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(Json(ClinicUsageResponse {
+        usage: state.ledger.usage(&clinic_id),
+        monthly_budget_usd: state.ledger.budget(&clinic_id),
+        over_budget: state.ledger.over_budget(&clinic_id),
+        clinic_id,
+    }))
+}
+
+// Audit log API endpoints
+#[utoipa::path(
+    get,
+    path = "/sessions/{session_id}/audit",
+    params(
+        ("session_id" = Uuid, Path, description = "Session to inspect"),
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Every upstream call made for the session", body = Vec<AuditEntry>),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "No audit log for the session")
+    )
+)]
+async fn session_audit_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<Vec<AuditEntry>>, StatusCode> {
+    let entries = state.audit.read(session_id).await;
+    if entries.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(entries))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct ReplayRequest {
+    // Name of a provider configured on the server: its own provider
+    // ("gemini"), "mock", or one listed in REPLAY_PROVIDERS
+    provider: String,
+    // Stages to replay; all text stages when empty
+    #[serde(default)]
+    stages: Vec<Stage>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ReplayTurn {
+    stage: Stage,
+    request: String,
+    original_model: String,
+    original_response: Option<String>,
+    original_parse_outcome: ParseOutcome,
+    replay_model: String,
+    replay_response: Option<String>,
+    replay_parse_outcome: ParseOutcome,
+    replay_latency_ms: u64,
+    replay_error: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ReplayResponse {
+    session_id: Uuid,
+    provider: String,
+    turns: Vec<ReplayTurn>,
+    // Calls that needed an image and could not be replayed
+    skipped: usize,
+}
+
+// Re-sends a session's recorded model requests to another provider so prompt
+// and model changes can be compared offline. Replayed calls are not audited.
+#[utoipa::path(
+    post,
+    path = "/sessions/{session_id}/replay",
+    request_body = ReplayRequest,
+    params(
+        ("session_id" = Uuid, Path, description = "Session to replay"),
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Original and replayed output for each call", body = ReplayResponse),
+        (status = 400, description = "Provider is not configured on the server"),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "No audit log for the session")
+    )
+)]
+async fn replay_session_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+    Json(request): Json<ReplayRequest>,
+) -> Result<Json<ReplayResponse>, (StatusCode, String)> {
+    // Only providers the server was configured with: the recorded prompts
+    // carry the child's chat and treatment plan
+    let provider = state
+        .replay_providers
+        .get(&request.provider)
+        .ok_or_else(|| {
+            let mut known: Vec<&str> = state.replay_providers.keys().map(String::as_str).collect();
+            known.sort_unstable();
+            (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Unknown provider \"{}\", expected one of: {}",
+                    request.provider,
+                    known.join(", ")
+                ),
+            )
+        })?;
+    let entries = state.audit.read(session_id).await;
+    if entries.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "No audit log for the session".to_string(),
+        ));
+    }
+
+    let mut turns = vec![];
+    let mut skipped = 0;
+    for entry in entries {
+        if !request.stages.is_empty() && !request.stages.contains(&entry.stage) {
+            continue;
+        }
+        // Images are not kept in the log, so image stages can't be replayed.
+        // Therapist overrides never went to a model.
+        if matches!(entry.stage, Stage::GenerateImage | Stage::TherapistOverride)
+            || entry.image_input
+        {
+            skipped += 1;
+            continue;
+        }
+
+        let parts = vec![GooglePart {
+            text: Some(entry.request.clone()),
+            inline_data: None,
+        }];
+        let reply = provider.generate(&entry.model, parts, &state).await;
+
+        turns.push(ReplayTurn {
+            stage: entry.stage,
+            replay_parse_outcome: parse_outcome(entry.stage, reply.text.as_deref()),
+            request: entry.request,
+            original_model: entry.model,
+            original_response: entry.response,
+            original_parse_outcome: entry.parse_outcome,
+            replay_model: reply.model,
+            replay_response: reply.text,
+            replay_latency_ms: reply.latency_ms,
+            replay_error: reply.error,
+        });
+    }
+
+    Ok(Json(ReplayResponse {
+        session_id,
+        provider: request.provider,
+        turns,
+        skipped,
+    }))
+}
+
+// Helper functions for API integration
+async fn call_model(
+    stage: Stage,
+    model: &str,
+    query: &str,
+    image: Option<GoogleInlineData>,
+    ctx: &CallContext,
+    state: &AppState,
+) -> ModelReply {
+    let image_input = image.is_some();
+    let mut parts = vec![];
+    if let Some(inline_data) = image {
+        parts.push(GooglePart {
+            text: None,
+            inline_data: Some(inline_data),
+        });
+    }
+    parts.push(GooglePart {
+        text: Some(query.to_string()),
+        inline_data: None,
+    });
+
+    let reply = state.provider.generate(model, parts, state).await;
+    let outcome = parse_outcome(stage, reply.text.as_deref());
+    record_upstream_metrics(stage, state.provider.name(), &reply, outcome, state);
+
+    // Charge the clinic, estimating tokens when the provider doesn't report them
+    state.ledger.record_tokens(
+        &ctx.clinic_id,
+        reply
+            .prompt_tokens
+            .unwrap_or_else(|| quota::estimate_tokens(query)),
+        reply.output_tokens.unwrap_or_else(|| {
+            reply
+                .text
+                .as_deref()
+                .map(quota::estimate_tokens)
+                .unwrap_or(0)
+        }),
+    );
+
+    // Every upstream call ends up in the session's audit log
+    state
+        .audit
+        .record(
+            ctx.session_id,
+            AuditEntry::new(
+                stage,
+                state.provider.name(),
+                query,
+                image_input,
+                &reply,
+                outcome,
+            ),
+        )
+        .await;
+
+    reply
+}
+
+fn record_upstream_metrics(
+    stage: Stage,
+    provider: &str,
+    reply: &ModelReply,
+    outcome: ParseOutcome,
+    state: &AppState,
+) {
+    let stage = serde_json::to_value(stage).unwrap();
+    let stage = stage.as_str().unwrap_or_default();
+    state
+        .metrics
+        .upstream_latency
+        .with_label_values(&[stage, provider])
+        .observe(reply.latency_ms as f64 / 1000.0);
+
+    match outcome {
+        ParseOutcome::Failed | ParseOutcome::NoOutput => {
+            let outcome = serde_json::to_value(outcome).unwrap();
+            state
+                .metrics
+                .parse_failures
+                .with_label_values(&[stage, outcome.as_str().unwrap_or_default()])
+                .inc();
+            tracing::warn!(stage, error = ?reply.error, "model output unusable, using fallback");
+        }
+        _ => {
+            tracing::info!(stage, model = %reply.model, latency_ms = reply.latency_ms, "upstream call")
+        }
+    }
+}
+
+fn parse_outcome(stage: Stage, text: Option<&str>) -> ParseOutcome {
+    let Some(text) = text else {
+        return ParseOutcome::NoOutput;
+    };
+    let parsed = match stage {
+        Stage::ExtractKeyDetails => parse_key_details(text).is_some(),
+        Stage::CompareDetails => parse_feedback(text).is_some(),
+        Stage::SummarizeSession => summary::parse_summary(text).is_some(),
+        _ => return ParseOutcome::NotParsed,
+    };
+    if parsed {
+        ParseOutcome::Parsed
+    } else {
+        ParseOutcome::Failed
+    }
+}
+
+// Looks up a stage's cached output, counting the lookup in the metrics
+async fn cached_output(key: &CacheKey, reuse: Reuse, state: &AppState) -> Option<String> {
+    let output = state.cache.get(key, reuse).await;
+    let stage = serde_json::to_value(key.stage).unwrap();
+    state
+        .metrics
+        .cache_lookups
+        .with_label_values(&[
+            stage.as_str().unwrap_or_default(),
+            if output.is_some() { "hit" } else { "miss" },
+        ])
+        .inc();
+    output
+}
+
+#[tracing::instrument(skip_all, fields(session_id = %ctx.session_id, difficulty = %spec.difficulty, topic_focus = %spec.topic_focus))]
+async fn generate_prompt(
+    spec: RoundSpec<'_>,
+    activity_guidance: &str,
+    ctx: &CallContext,
+    state: &AppState,
+) -> String {
+    // Format prompt query for Gemini
+    let query = format!(
+        r#"
+        Follow the instructions below to generate an image generation prompt for an educational image intended for autistic children.
+        Consider the following parameters:
+          - Difficulty: {}
+          - Age: {}
+          - Autism Level: {}
+          - Topic Focus: {}
+          - Treatment Plan: {}
+        Emphasize that the image should be clear, calming, and support understanding and communication. The style should match the difficulty level: for example, "Very Simple" produces very basic visuals while "Very Detailed" produces rich visuals.
+        The image should specifically focus on the topic: "{}".
+        Please generate a prompt that instructs the image generation engine to produce an image with:
+        1. Clarity and simplicity (minimalist backgrounds, clear subject)
+        2. Literal representation with defined borders and consistent style
+        3. Soft, muted colors and reduced visual complexity
+        4. Positive, calm scenes
+        5. Clear focus on the specified topic
+        {}
+        Use descriptive and detailed language.
+        "#,
+        spec.difficulty,
+        spec.age,
+        spec.autism_level,
+        spec.topic_focus,
+        spec.treatment_plan,
+        spec.topic_focus,
+        activity_guidance
+    );
+
+    let model = "gemini-2.0-flash-lite";
+    let key = CacheKey::new(Stage::GeneratePrompt, model, &query);
+    if let Some(prompt) = cached_output(&key, Reuse::Sometimes, state).await {
+        return prompt;
+    }
+
+    // Call Google Gemini API
+    let reply = call_model(Stage::GeneratePrompt, model, &query, None, ctx, state).await;
+
+    // Extract prompt from response
+    match reply.text {
+        Some(prompt) => {
+            state.cache.put(&key, prompt.clone(), ctx.session_id).await;
+            prompt
+        }
+        None => "A simple, clear image of animals for autism education".to_string(),
+    }
+}
+
+// Images that break the sensory profile are regenerated up to this many times in total
+const SENSORY_MAX_ATTEMPTS: usize = 2;
+
+// Generates an image and checks it against the child's sensory profile,
+// regenerating with stricter guidance when it is too saturated or too busy.
+// Fails with 502 when no image could be generated.
+#[tracing::instrument(skip_all, fields(session_id = %ctx.session_id))]
+async fn generate_image(
+    prompt: &str,
+    sensory_profile: &SensoryProfile,
+    ctx: &CallContext,
+    state: &AppState,
+) -> Result<String, StatusCode> {
+    // Keyed on everything the image request is built from
+    let key = CacheKey::new(
+        Stage::GenerateImage,
+        state.provider.image_model(),
+        &format!(
+            "{}\n{}",
+            prompt,
+            serde_json::to_string(sensory_profile).unwrap()
+        ),
+    );
+    if let Some(image) = cached_output(&key, Reuse::Sometimes, state).await {
+        return Ok(image);
+    }
+
+    let mut best: Option<(Vec<u8>, usize)> = None;
+    let mut attempt_prompt = prompt.to_string();
+
+    for attempt in 1..=SENSORY_MAX_ATTEMPTS {
+        let Some(image) = request_image(&attempt_prompt, sensory_profile, ctx, state).await else {
+            // A retry that fails still leaves the first image
+            if best.is_some() {
+                break;
+            }
+            return Err(StatusCode::BAD_GATEWAY);
+        };
+
+        // Decoding is CPU bound, keep it off the async workers
+        let bytes = image.clone();
+        let metrics = tokio::task::spawn_blocking(move || ImageMetrics::from_bytes(&bytes))
+            .await
+            .unwrap_or(None);
+        let Some(metrics) = metrics else {
+            // Not an image we can decode; nothing to check
+            tracing::warn!(
+                attempt,
+                "Generated image could not be decoded for sensory checks"
+            );
+            best.get_or_insert((image, usize::MAX));
+            break;
+        };
+
+        let violations = sensory_profile.violations(&metrics);
+        tracing::info!(
+            attempt,
+            saturation = metrics.mean_saturation,
+            complexity = metrics.edge_density,
+            violations = violations.len(),
+            "Checked generated image against sensory profile"
+        );
+        for check in &violations {
+            state
+                .metrics
+                .sensory_rejections
+                .with_label_values(&[check.name()])
+                .inc();
+        }
+
+        if best
+            .as_ref()
+            .is_none_or(|(_, fewest)| violations.len() < *fewest)
+        {
+            best = Some((image, violations.len()));
+        }
+        if violations.is_empty() {
+            break;
+        }
+        attempt_prompt = format!("{}{}", prompt, sensory_profile.retry_guidance(&violations));
+    }
+
+    // Convert image bytes to base64
+    let (image, violations) = best.unwrap_or_default();
+    let base64_image = general_purpose::STANDARD.encode(&image);
+    let data_url = format!("data:image/png;base64,{}", base64_image);
+
+    // Only images that were checked are worth keeping
+    if violations != usize::MAX && !image.is_empty() {
+        state
+            .cache
+            .put(&key, data_url.clone(), ctx.session_id)
+            .await;
+    }
+    Ok(data_url)
+}
+
+// One call to the provider's text-to-image model, None if it failed
+async fn request_image(
+    prompt: &str,
+    sensory_profile: &SensoryProfile,
+    ctx: &CallContext,
+    state: &AppState,
+) -> Option<Vec<u8>> {
+    let (image, reply) = state
+        .provider
+        .generate_image(prompt, sensory_profile.generation_parameters(), state)
+        .await;
+    if let Some(err) = &reply.error {
+        tracing::error!(%err, "Image generation failed");
+    } else {
+        state.ledger.record_image(&ctx.clinic_id);
+    }
+
+    let backend = state.provider.image_backend();
+    record_upstream_metrics(
+        Stage::GenerateImage,
+        backend,
+        &reply,
+        ParseOutcome::NotParsed,
+        state,
+    );
+    state
+        .audit
+        .record(
+            ctx.session_id,
+            AuditEntry::new(
+                Stage::GenerateImage,
+                backend,
+                prompt,
+                false,
+                &reply,
+                ParseOutcome::NotParsed,
+            ),
+        )
+        .await;
+
+    image
+}
+
+#[tracing::instrument(skip_all, fields(session_id = %ctx.session_id, difficulty = %difficulty))]
+async fn generate_description(
+    image_data_url: &str,
+    prompt: &str,
+    difficulty: &str,
+    topic_focus: &str,
+    ctx: &CallContext,
+    state: &AppState,
+) -> String {
+    // Extract base64 image data
+    let base64_img = image_data_url.split(',').nth(1).unwrap();
+
+    // Format query for Gemini Vision
+    let query = format!(
+        r#"
+        You are an expert educator specializing in teaching children with autism.
+        Please provide a detailed description of this image that was generated based on the prompt:
+        "{}"
+        The image is intended for a child with autism, focusing on the topic: "{}" at a {} difficulty level.
+        In your description:
+        1. List all key objects, characters, and elements present in the image
+        2. Describe colors, shapes, positions, and relationships between elements
+        3. Note any emotions, actions, or interactions depicted
+        4. Highlight details that would be important for the child to notice
+        5. Organize your description in a structured, clear way
+        Your description will be used as a reference to evaluate the child's observations,
+        so please be comprehensive but focus on observable details rather than interpretations.
+        "#,
+        prompt, topic_focus, difficulty
+    );
+
+    // The same image described with the same query gets the cached description
+    let model = "gemini-2.0-flash-thinking-exp-01-21";
+    let key = CacheKey::new(
+        Stage::GenerateDescription,
+        model,
+        &format!("{}\n{}", query, cache::content_hash(base64_img)),
+    );
+    if let Some(description) = cached_output(&key, Reuse::Always, state).await {
+        return description;
+    }
+
+    // Call Google Gemini Vision API
+    let reply = call_model(
+        Stage::GenerateDescription,
+        model,
+        &query,
+        Some(GoogleInlineData {
+            mime_type: "image/png".to_string(),
+            data: base64_img.to_string(),
+        }),
+        ctx,
+        state,
+    )
+    .await;
+
+    // Extract description from response
+    match reply.text {
+        Some(description) => {
+            state
+                .cache
+                .put(&key, description.clone(), ctx.session_id)
+                .await;
+            description
+        }
+        None => "An image showing educational content".to_string(),
+    }
+}
+
+#[tracing::instrument(skip_all, fields(session_id = %ctx.session_id))]
+async fn extract_key_details(
+    description: &str,
+    activity: &Activity,
+    ctx: &CallContext,
+    state: &AppState,
+) -> Vec<String> {
+    // Format query to extract the activity's checklist items
+    let query = activity.key_details_query(description);
+
+    let model = "gemini-2.0-flash-lite";
+    let key = CacheKey::new(Stage::ExtractKeyDetails, model, &query);
+    if let Some(details) = cached_output(&key, Reuse::Always, state)
+        .await
+        .and_then(|cached| serde_json::from_str(&cached).ok())
+    {
+        return details;
+    }
+
+    // Call Google Gemini API
+    let reply = call_model(Stage::ExtractKeyDetails, model, &query, None, ctx, state).await;
+
+    // Extract and parse JSON array from response
+    if let Some(details) = reply.text.as_deref().and_then(parse_key_details) {
+        state
+            .cache
+            .put(
+                &key,
+                serde_json::to_string(&details).unwrap(),
+                ctx.session_id,
+            )
+            .await;
+        return details;
+    }
+
+    // Fallback default details
+    activity.fallback_details()
+}
+
+// Folds older chat turns into a running summary once the history would push
+// the evaluation prompt over its context budget
+async fn compact_chat_history(session: &mut Session, ctx: &CallContext, state: &AppState) {
+    let Some(upto) = state.context_budget.compaction_point(session) else {
+        return;
+    };
+
+    let reply = call_model(
+        Stage::CompactHistory,
+        "gemini-2.0-flash-lite",
+        &compaction::compaction_query(session, upto),
+        None,
+        ctx,
+        state,
+    )
+    .await;
+
+    let summary = reply
+        .text
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+        .unwrap_or_else(|| compaction::fallback_summary(session, upto));
+    session.chat_summary = Some(summary);
+    session.compacted_messages = upto;
+}
+
+#[tracing::instrument(skip_all, fields(session_id = %ctx.session_id, difficulty = %session.difficulty))]
+async fn compare_details(
+    user_details: &str,
+    session: &Session,
+    ctx: &CallContext,
+    state: &AppState,
+) -> String {
+    let image_description = session.image_description.as_deref().unwrap_or_default();
+    let mut instructions = session.activity.evaluation_instructions();
+    if let Some(extra) = session
+        .experiment
+        .as_ref()
+        .and_then(|enrollment| enrollment.arm.feedback_guidance.as_deref())
+    {
+        instructions.push_str(&format!("\n{}", extra));
+    }
+
+    // Format chat history
+    let mut history_text = String::new();
+    if let Some(summary) = &session.chat_summary {
+        history_text.push_str(&format!(
+            "\n\n### Summary of Earlier Conversation:\n{}\n",
+            summary
+        ));
+    }
+    let recent = &session.chat[session.compacted_messages..];
+    if !recent.is_empty() {
+        history_text.push_str("\n\n### Previous Conversation:\n");
+        for (idx, (speaker, msg)) in recent.iter().enumerate() {
+            history_text.push_str(&format!(
+                "Turn {}:\n{}: {}\n",
+                session.compacted_messages + idx + 1,
+                speaker,
+                msg
+            ));
+        }
+    }
+
+    // Format key details and other context
+    let key_details_text = format!(
+        "\n\n### {}:\n{}",
+        session.activity.checklist_heading(),
+        session
+            .key_details
+            .iter()
+            .map(|d| format!("- {}", d))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+
+    let identified_details_text = if !session.identified_details.is_empty() {
+        format!(
+            "\n\n### Previously Identified Details:\n{}",
+            session
+                .identified_details
+                .iter()
+                .map(|d| format!("- {}", d))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    } else {
+        String::new()
+    };
+
+    let used_hints_text = if !session.used_hints.is_empty() {
+        format!(
+            "\n\n### Previously Given Hints:\n{}",
+            session
+                .used_hints
+                .iter()
+                .map(|h| format!("- {}", h))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    } else {
+        String::new()
+    };
+
+    // Create evaluation query
+    let message_text = format!(
+        r#"You are a kind and encouraging teacher helping a child with autism {}.
+
+### Image Prompt:
+{}
+
+### Detailed Image Description (Reference):
+{}
+
+### Current Difficulty Level: {}
+{}{}{}{}
+
+### Child's Current Description:
+'{}'
+
+{}
+
+Follow these guidelines:
+1. DO NOT mention that you're evaluating or scoring the child.
+2. Keep feedback warm, positive, and encouraging.
+3. If giving a hint, make it specific but not too obvious.
+4. Never repeat hints that have already been given.
+5. Focus on details the child hasn't yet identified.
+6. Acknowledge the child's progress.
+
+Return your response as a JSON object with the following format:
+{{
+  "feedback": "Your encouraging response to the child",
+  "newly_identified_details": ["checklist items", "the child identified", "copied exactly from the list above"],
+  "hint": "A new hint about something not yet identified",
+  "score": <number from 0-100 based on how complete the description is>,
+  "advance_difficulty": <boolean indicating if child should advance>
+}}
+
+Ensure the JSON is valid and contains all fields."#,
+        session.activity.task_description(),
+        session.prompt.as_ref().unwrap_or(&String::new()),
+        image_description,
+        session.difficulty,
+        key_details_text,
+        history_text,
+        identified_details_text,
+        used_hints_text,
+        user_details,
+        instructions
+    );
+
+    // Call Google Gemini API
+    let reply = call_model(
+        Stage::CompareDetails,
+        "gemini-2.0-flash-thinking-exp-01-21",
+        &message_text,
+        None,
+        ctx,
+        state,
+    )
+    .await;
+
+    // Extract response
+    reply.text.unwrap_or_else(|| "{\"feedback\": \"Great effort! Keep describing what you see.\", \"newly_identified_details\": [], \"hint\": \"\", \"score\": 0, \"advance_difficulty\": false}".to_string())
+}
+
+fn parse_key_details(response_text: &str) -> Option<Vec<String>> {
+    // Find JSON array in text
+    let re = regex::Regex::new(r"\[.*\]").unwrap();
+    let json_match = re.find(response_text)?;
+    serde_json::from_str::<Vec<String>>(json_match.as_str()).ok()
+}
+
+fn parse_feedback(evaluation_text: &str) -> Option<FeedbackResponse> {
+    let re = regex::Regex::new(r"\{.*\}").unwrap();
+    let json_match = re.find(evaluation_text)?;
+    serde_json::from_str::<FeedbackResponse>(json_match.as_str()).ok()
+}
+
+fn parse_evaluation(
+    evaluation_text: &str,
+    session: &mut Session,
+) -> (String, String, bool, Vec<String>) {
+    // Find and parse JSON
+    if let Some(evaluation) = parse_feedback(evaluation_text) {
+        // Extract evaluation data
+        let feedback = evaluation.feedback;
+        let newly_identified_details = evaluation.newly_identified_details;
+        let hint = evaluation.hint;
+        let advance_difficulty = evaluation.advance_difficulty;
+
+        // Add hint to used hints
+        if !hint.is_empty() && !session.used_hints.contains(&hint) {
+            session.used_hints.push(hint.clone());
+        }
+
+        // Add hint to feedback if not already included
+        let enhanced_feedback = if !hint.is_empty() && !feedback.contains(&hint) {
+            format!("{}\n\n💡 Hint: {}", feedback, hint)
+        } else {
+            feedback
+        };
+
+        // Handle difficulty advancement
+        let new_difficulty = if advance_difficulty {
+            next_difficulty(&session.difficulty)
+        } else {
+            session.difficulty.clone()
+        };
+
+        return (
+            enhanced_feedback,
+            new_difficulty,
+            advance_difficulty,
+            newly_identified_details,
+        );
+    }
+
+    // Default return if parsing fails
+    (
+        "That's interesting! Can you tell me more about what you see?".to_string(),
+        session.difficulty.clone(),
+        false,
+        vec![],
+    )
+}
+
+// The level above `difficulty`; the hardest level stays where it is
+fn next_difficulty(difficulty: &str) -> String {
+    match DIFFICULTIES.iter().position(|&d| d == difficulty) {
+        Some(idx) if idx + 1 < DIFFICULTIES.len() => DIFFICULTIES[idx + 1].to_string(),
+        _ => difficulty.to_string(),
+    }
+}
+
+fn similar_details(detail1: &str, detail2: &str) -> bool {
+    // Simple similarity check - could be improved with NLP techniques
+    detail1.to_lowercase().contains(&detail2.to_lowercase())
+        || detail2.to_lowercase().contains(&detail1.to_lowercase())
+        || detail1
+            .split_whitespace()
+            .any(|word| word.len() > 3 && detail2.to_lowercase().contains(&word.to_lowercase()))
+}
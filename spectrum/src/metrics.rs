@@ -0,0 +1,136 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::AppState;
+
+// Prometheus collectors exposed on `/metrics`
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub upstream_latency: HistogramVec,
+    pub parse_failures: IntCounterVec,
+    pub sensory_rejections: IntCounterVec,
+    pub cache_lookups: IntCounterVec,
+    pub active_sessions: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new(
+                "spectrum_http_requests_total",
+                "HTTP requests by route and status",
+            ),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "spectrum_upstream_latency_seconds",
+                "Latency of upstream model calls by pipeline stage",
+            )
+            .buckets(vec![0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0]),
+            &["stage", "provider"],
+        )
+        .unwrap();
+        let parse_failures = IntCounterVec::new(
+            Opts::new(
+                "spectrum_parse_failures_total",
+                "Model outputs that could not be used and fell back to defaults",
+            ),
+            &["stage", "outcome"],
+        )
+        .unwrap();
+        let sensory_rejections = IntCounterVec::new(
+            Opts::new(
+                "spectrum_sensory_rejections_total",
+                "Generated images that broke a child's sensory profile, by failed check",
+            ),
+            &["check"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "spectrum_cache_lookups_total",
+                "Pipeline cache lookups by stage; a miss means the model was called",
+            ),
+            &["stage", "result"],
+        )
+        .unwrap();
+        let active_sessions = IntGauge::new(
+            "spectrum_active_sessions",
+            "Sessions started and not yet ended",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry
+            .register(Box::new(upstream_latency.clone()))
+            .unwrap();
+        registry.register(Box::new(parse_failures.clone())).unwrap();
+        registry
+            .register(Box::new(sensory_rejections.clone()))
+            .unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        registry
+            .register(Box::new(active_sessions.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            upstream_latency,
+            parse_failures,
+            sensory_rejections,
+            cache_lookups,
+            active_sessions,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+// Metrics endpoint in the Prometheus text format
+pub async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+// Counts requests by matched route so path parameters don't explode the label set
+pub async fn track_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().to_string();
+
+    let response = next.run(request).await;
+    state
+        .metrics
+        .http_requests
+        .with_label_values(&[&route, &method, response.status().as_str()])
+        .inc();
+    response
+}
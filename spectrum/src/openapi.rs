@@ -0,0 +1,131 @@
+use axum::Json;
+use utoipa::OpenApi;
+
+use crate::{
+    ClinicUsageResponse, Detail, GenerateImageRequest, GenerateImageResponse, ProcessChatRequest,
+    ProcessChatResponse, ReplayRequest, ReplayResponse, ReplayTurn,
+    activity::Activity,
+    audit::{AuditEntry, ParseOutcome, Stage},
+    cache::{CacheEntrySummary, CacheReport, PurgeResponse},
+    curriculum::{LessonPlan, LessonPlanRequest, LessonStatus, LessonTopic, MasteryCriteria},
+    experiments::{
+        ArmOutcome, Experiment, ExperimentArm, ExperimentReport, ExperimentRequest,
+        ProgressionPolicy,
+    },
+    group::{
+        CreateGroupRequest, GroupDetail, GroupMessageRequest, GroupMessageResponse, GroupResponse,
+        JoinGroupRequest, Participant,
+    },
+    photos::{PhotoSessionSettings, PhotoUpload},
+    privacy::{ChildDeletion, ChildExport, SessionExport},
+    quota::ClinicUsage,
+    rewards::{ChildRewards, RewardSchedule, RewardUpdate, Sticker, StickerTheme},
+    sensory::{Palette, SensoryProfile},
+    summary::CaregiverSummary,
+    therapist::{Override, OverrideResponse},
+};
+
+// OpenAPI description of the JSON API. `openapi.json` and the client crate in
+// `client/` are generated from this; rerun `spectrum openapi > openapi.json`
+// after changing a handler or one of these types.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Spectrum API",
+        description = "Image-based communication exercises for autistic children"
+    ),
+    paths(
+        crate::generate_image_handler,
+        crate::process_chat_handler,
+        crate::end_session_handler,
+        crate::session_summary_handler,
+        crate::clinic_usage_handler,
+        crate::session_audit_handler,
+        crate::replay_session_handler,
+        crate::curriculum::create_lesson_plan_handler,
+        crate::curriculum::list_lesson_plans_handler,
+        crate::curriculum::get_lesson_plan_handler,
+        crate::curriculum::update_lesson_plan_handler,
+        crate::curriculum::delete_lesson_plan_handler,
+        crate::curriculum::session_lesson_handler,
+        crate::experiments::create_experiment_handler,
+        crate::experiments::list_experiments_handler,
+        crate::experiments::stop_experiment_handler,
+        crate::experiments::experiment_report_handler,
+        crate::group::create_group_handler,
+        crate::group::get_group_handler,
+        crate::group::join_group_handler,
+        crate::group::group_message_handler,
+        crate::therapist::override_handler,
+        crate::rewards::child_rewards_handler,
+        crate::rewards::set_reward_schedule_handler,
+        crate::cache::cache_report_handler,
+        crate::cache::purge_cache_handler,
+        crate::cache::purge_cache_entry_handler,
+        crate::privacy::export_child_handler,
+        crate::privacy::delete_child_handler,
+        crate::photos::create_photo_session_handler,
+        crate::timeline::session_timeline_handler,
+    ),
+    components(schemas(
+        Activity,
+        ArmOutcome,
+        AuditEntry,
+        CacheEntrySummary,
+        CacheReport,
+        CaregiverSummary,
+        ChildDeletion,
+        ChildExport,
+        ChildRewards,
+        ClinicUsage,
+        ClinicUsageResponse,
+        CreateGroupRequest,
+        Detail,
+        Experiment,
+        ExperimentArm,
+        ExperimentReport,
+        ExperimentRequest,
+        GenerateImageRequest,
+        GenerateImageResponse,
+        GroupDetail,
+        GroupMessageRequest,
+        GroupMessageResponse,
+        GroupResponse,
+        JoinGroupRequest,
+        LessonPlan,
+        LessonPlanRequest,
+        LessonStatus,
+        LessonTopic,
+        MasteryCriteria,
+        Override,
+        OverrideResponse,
+        Palette,
+        Participant,
+        ParseOutcome,
+        PhotoSessionSettings,
+        PhotoUpload,
+        ProcessChatRequest,
+        ProcessChatResponse,
+        PurgeResponse,
+        ProgressionPolicy,
+        ReplayRequest,
+        ReplayResponse,
+        ReplayTurn,
+        RewardSchedule,
+        RewardUpdate,
+        SensoryProfile,
+        SessionExport,
+        Stage,
+        Sticker,
+        StickerTheme,
+    ))
+)]
+pub struct ApiDoc;
+
+pub fn document() -> String {
+    ApiDoc::openapi().to_pretty_json().unwrap()
+}
+
+pub async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
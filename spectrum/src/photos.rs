@@ -0,0 +1,309 @@
+use axum::{
+    Json,
+    extract::{Multipart, State},
+    http::StatusCode,
+};
+use base64::{Engine as _, engine::general_purpose};
+use chrono::Utc;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits, imageops::FilterType};
+use serde::Deserialize;
+use std::io::Cursor;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    AppState, CallContext, GenerateImageResponse, Session,
+    activity::Activity,
+    extract_key_details, generate_description, open_session,
+    quota::{self, Clinic},
+};
+
+// Largest upload accepted, before normalizing
+pub fn max_upload_bytes() -> usize {
+    quota::env_or("PHOTO_MAX_MB", 10_usize) * 1024 * 1024
+}
+
+// Photos are scaled down to fit this many pixels on their longest side
+const MAX_SIDE: u32 = 1024;
+// Smaller photos don't show enough for a checklist
+const MIN_SIDE: u32 = 128;
+// Larger photos are refused before decoding; a small compressed file can
+// claim dimensions that would take gigabytes to decode
+const MAX_DECODED_SIDE: u32 = 12_000;
+
+// Session settings sent alongside the photo
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PhotoSessionSettings {
+    pub age: String,
+    pub autism_level: String,
+    pub topic_focus: String,
+    pub treatment_plan: String,
+    // Single-picture activities only
+    #[serde(default)]
+    pub activity: Activity,
+    // What the photo shows, in the therapist's words; helps the description
+    #[serde(default)]
+    pub caption: Option<String>,
+    #[serde(default)]
+    pub child_id: Option<String>,
+}
+
+// multipart/form-data body of `POST /photo_sessions`, only used to describe
+// the form in the OpenAPI document
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct PhotoUpload {
+    // PNG or JPEG
+    #[schema(value_type = String, format = Binary)]
+    photo: Vec<u8>,
+    // JSON-encoded settings
+    settings: PhotoSessionSettings,
+}
+
+type PhotoError = (StatusCode, String);
+
+fn bad_request(message: impl Into<String>) -> PhotoError {
+    (StatusCode::BAD_REQUEST, message.into())
+}
+
+// Decodes an uploaded PNG or JPEG, turns it upright, scales it down and
+// re-encodes it as PNG. Re-encoding from pixels drops EXIF and every other
+// metadata block, including GPS positions from phone cameras.
+fn normalize_photo(bytes: &[u8]) -> Result<Vec<u8>, PhotoError> {
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|err| bad_request(err.to_string()))?;
+    if !matches!(reader.format(), Some(ImageFormat::Png | ImageFormat::Jpeg)) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Photos must be PNG or JPEG".to_string(),
+        ));
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DECODED_SIDE);
+    limits.max_image_height = Some(MAX_DECODED_SIDE);
+    reader.limits(limits);
+
+    let mut decoder = reader
+        .into_decoder()
+        .map_err(|err| bad_request(format!("Unreadable photo: {}", err)))?;
+    let orientation = decoder
+        .orientation()
+        .map_err(|err| bad_request(format!("Unreadable photo: {}", err)))?;
+    let mut photo = DynamicImage::from_decoder(decoder)
+        .map_err(|err| bad_request(format!("Unreadable photo: {}", err)))?;
+    photo.apply_orientation(orientation);
+
+    if photo.width().min(photo.height()) < MIN_SIDE {
+        return Err(bad_request(format!(
+            "Photo is too small, it needs at least {} pixels on each side",
+            MIN_SIDE
+        )));
+    }
+    if photo.width().max(photo.height()) > MAX_SIDE {
+        photo = photo.resize(MAX_SIDE, MAX_SIDE, FilterType::Lanczos3);
+    }
+
+    let mut png = Vec::new();
+    DynamicImage::ImageRgb8(photo.to_rgb8())
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(png)
+}
+
+// Photo session API endpoint
+#[utoipa::path(
+    post,
+    path = "/photo_sessions",
+    request_body(content = PhotoUpload, content_type = "multipart/form-data"),
+    params(
+        ("x-therapist-token" = String, Header, description = "Therapist token"),
+        ("x-api-key" = Option<String>, Header, nullable = false, description = "Clinic API key, required when clinic keys are configured")
+    ),
+    responses(
+        (status = 200, description = "New session on the uploaded photo", body = GenerateImageResponse),
+        (status = 400, description = "Missing or invalid photo or settings"),
+        (status = 401, description = "Missing or wrong therapist token, or unknown API key"),
+        (status = 402, description = "Clinic is over its monthly budget"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 413, description = "Photo is too large"),
+        (status = 415, description = "Photo is not PNG or JPEG"),
+        (status = 429, description = "Too many requests from the clinic")
+    )
+)]
+pub async fn create_photo_session_handler(
+    State(state): State<AppState>,
+    Clinic(clinic_id): Clinic,
+    mut multipart: Multipart,
+) -> Result<Json<GenerateImageResponse>, PhotoError> {
+    // A photo can't fall back to the image library like generated rounds do
+    if state.ledger.over_budget(&clinic_id) {
+        return Err((
+            StatusCode::PAYMENT_REQUIRED,
+            "The clinic is over its monthly budget".to_string(),
+        ));
+    }
+
+    // 1. Read the form
+    let mut photo = None;
+    let mut settings = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| (err.status(), err.body_text()))?
+    {
+        match field.name() {
+            Some("photo") => {
+                photo = Some(
+                    field
+                        .bytes()
+                        .await
+                        .map_err(|err| (err.status(), err.body_text()))?,
+                )
+            }
+            Some("settings") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|err| (err.status(), err.body_text()))?;
+                settings = Some(
+                    serde_json::from_str::<PhotoSessionSettings>(&text)
+                        .map_err(|err| bad_request(format!("Invalid settings: {}", err)))?,
+                );
+            }
+            _ => {}
+        }
+    }
+    let photo = photo.ok_or_else(|| bad_request("Missing the photo field"))?;
+    let settings = settings.ok_or_else(|| bad_request("Missing the settings field"))?;
+    if settings.activity.image_count() != 1 {
+        return Err(bad_request(
+            "Photo sessions support single-picture activities only",
+        ));
+    }
+
+    // 2. Normalize the photo; decoding is CPU bound
+    let png = tokio::task::spawn_blocking(move || normalize_photo(&photo))
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))??;
+    let image = format!(
+        "data:image/png;base64,{}",
+        general_purpose::STANDARD.encode(&png)
+    );
+
+    // 3. Describe the photo and extract its checklist. The prompt and image
+    // stages are skipped, and the photo never enters the image library, so it
+    // is never shown to another child.
+    let session_id = Uuid::new_v4();
+    let ctx = CallContext {
+        session_id,
+        clinic_id,
+    };
+    let caption = settings.caption.unwrap_or_else(|| {
+        format!(
+            "A real photo from the child's own environment, about {}",
+            settings.topic_focus
+        )
+    });
+    let difficulty = "Very Simple";
+    let description = generate_description(
+        &image,
+        &caption,
+        difficulty,
+        &settings.topic_focus,
+        &ctx,
+        &state,
+    )
+    .await;
+    let key_details = extract_key_details(&description, &settings.activity, &ctx, &state).await;
+    tracing::info!(%session_id, bytes = png.len(), details = key_details.len(), "Started photo session");
+
+    // 4. Create the session; later rounds use generated images as usual
+    let session = Session {
+        prompt: Some(caption),
+        image: Some(image),
+        image_description: Some(description),
+        difficulty: difficulty.to_string(),
+        age: settings.age,
+        autism_level: settings.autism_level,
+        topic_focus: settings.topic_focus,
+        treatment_plan: settings.treatment_plan,
+        key_details,
+        activity: settings.activity,
+        clinic_id: ctx.clinic_id,
+        started_at: Some(Utc::now()),
+        round_started_at: Some(Utc::now()),
+        child_id: settings.child_id,
+        ..Default::default()
+    };
+    Ok(Json(open_session(&state, session_id, session).await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageEncoder, Rgb, RgbImage, codecs::jpeg::JpegEncoder};
+
+    // Minimal little-endian TIFF block holding only an orientation tag
+    fn exif_orientation(orientation: u16) -> Vec<u8> {
+        let mut exif = b"II*\0".to_vec();
+        exif.extend(8_u32.to_le_bytes());
+        exif.extend(1_u16.to_le_bytes());
+        exif.extend(0x0112_u16.to_le_bytes());
+        exif.extend(3_u16.to_le_bytes());
+        exif.extend(1_u32.to_le_bytes());
+        exif.extend(orientation.to_le_bytes());
+        exif.extend([0, 0]);
+        exif.extend(0_u32.to_le_bytes());
+        exif
+    }
+
+    #[test]
+    fn rotated_jpeg_comes_out_upright_without_metadata() {
+        // Landscape pixels with a red top-left corner, tagged "rotate 90° clockwise"
+        let mut pixels = RgbImage::from_pixel(300, 200, Rgb([0, 0, 255]));
+        for x in 0..60 {
+            for y in 0..60 {
+                pixels.put_pixel(x, y, Rgb([255, 0, 0]));
+            }
+        }
+        let mut jpeg = Vec::new();
+        let mut encoder = JpegEncoder::new_with_quality(&mut jpeg, 95);
+        encoder.set_exif_metadata(exif_orientation(6)).unwrap();
+        encoder
+            .write_image(pixels.as_raw(), 300, 200, image::ExtendedColorType::Rgb8)
+            .unwrap();
+
+        let png = normalize_photo(&jpeg).unwrap();
+        let mut decoder = ImageReader::new(Cursor::new(&png))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+        assert!(decoder.exif_metadata().unwrap().is_none());
+        assert!(decoder.icc_profile().unwrap().is_none());
+        let upright = DynamicImage::from_decoder(decoder).unwrap().to_rgb8();
+
+        assert_eq!(upright.dimensions(), (200, 300));
+        // The red corner is now top-right
+        assert!(upright.get_pixel(190, 10)[0] > 200);
+        assert!(upright.get_pixel(10, 10)[2] > 200);
+    }
+
+    #[test]
+    fn refuses_huge_dimensions_before_decoding() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(MAX_DECODED_SIDE + 1, 1))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let (status, _) = normalize_photo(&png).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let (status, _) = normalize_photo(b"GIF89a\x01\x00\x01\x00\x00\x00\x00").unwrap_err();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
@@ -0,0 +1,556 @@
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Serialize;
+use std::{collections::HashSet, io};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    AppState, Session, audit::AuditEntry, group::GroupSession, rewards::ChildRewards,
+    set_active_sessions,
+};
+
+// Replaces names, places and contact details in chat text before it is stored
+// and sent to a model. Heuristic: it catches the ways children usually
+// mention them ("my name is Sam", "I live in Leeds"), not every name.
+#[derive(Debug)]
+pub struct Redactor {
+    enabled: bool,
+    patterns: Vec<(Regex, &'static str)>,
+    // Digit groups that look like a phone number; checked for length
+    // separately so a child counting "1 2 3 4 5" isn't redacted
+    phone: Regex,
+    // Always redacted, from PRIVACY_REDACT_TERMS
+    terms: Option<Regex>,
+}
+
+impl Redactor {
+    pub fn from_env() -> Self {
+        let enabled = std::env::var("PRIVACY_REDACTION").map_or(true, |value| value != "off");
+        let terms: Vec<String> = std::env::var("PRIVACY_REDACT_TERMS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|term| !term.is_empty())
+            .map(regex::escape)
+            .collect();
+        Self::new(enabled, &terms)
+    }
+
+    fn new(enabled: bool, terms: &[String]) -> Self {
+        let name = r"[A-Z][a-z'’-]+";
+        let place = r"[A-Z][A-Za-z'’-]*(?:\s+[A-Z][A-Za-z'’-]*)*";
+        let patterns = vec![
+            (
+                Regex::new(r"[\w.+-]+@[\w-]+(?:\.[\w-]+)+").unwrap(),
+                "[email]",
+            ),
+            (
+                Regex::new(r"(?i)\b\d+\s+(?:\w+\s+){0,3}(?:street|st|road|rd|avenue|ave|lane|ln|drive|dr|close|court|way|crescent)\b\.?").unwrap(),
+                "[address]",
+            ),
+            (
+                Regex::new(&format!(
+                    r"(?i:\b(?P<lead>my name is|my name's|i am called|i'm called|call me|my (?:mom|mum|dad|brother|sister|friend|teacher|grandma|grandpa|nan|cousin)(?:'s name)? is(?: called)?))\s+{}",
+                    name
+                ))
+                .unwrap(),
+                "${lead} [name]",
+            ),
+            (
+                Regex::new(&format!(
+                    r"(?i:\b(?P<lead>(?:i live|we live|my house is|my school is|i go to school|i go to|i'm from|i am from)(?:\s+(?:in|on|at|near))?))\s+{}",
+                    place
+                ))
+                .unwrap(),
+                "${lead} [place]",
+            ),
+            (
+                Regex::new(&format!(
+                    r"\b{}\s+(?:School|Primary|Academy|Nursery|Kindergarten|Hospital|Clinic|Street|Road|Avenue|Park)\b",
+                    place
+                ))
+                .unwrap(),
+                "[place]",
+            ),
+        ];
+        let terms = (!terms.is_empty())
+            .then(|| Regex::new(&format!(r"(?i)\b(?:{})\b", terms.join("|"))).unwrap());
+        let phone = Regex::new(
+            r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{2,5}\)[\s.-]?|\b\d{2,5}[\s.-])(?:\d{2,5}[\s.-]){0,3}\d{3,6}\b|\b\d{7,15}\b",
+        )
+        .unwrap();
+        Self {
+            enabled,
+            patterns,
+            phone,
+            terms,
+        }
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        if !self.enabled {
+            return text.to_string();
+        }
+        let mut redacted = text.to_string();
+        if let Some(terms) = &self.terms {
+            redacted = terms.replace_all(&redacted, "[redacted]").into_owned();
+        }
+        // Lead-ins like "my name is" are kept so the sentence still reads naturally
+        for (pattern, replacement) in &self.patterns {
+            redacted = pattern.replace_all(&redacted, *replacement).into_owned();
+        }
+        self.phone
+            .replace_all(&redacted, |caps: &regex::Captures| {
+                let digits = caps[0].chars().filter(char::is_ascii_digit).count();
+                if (7..=15).contains(&digits) {
+                    "[phone]".to_string()
+                } else {
+                    caps[0].to_string()
+                }
+            })
+            .into_owned()
+    }
+}
+
+const SEALED_MAGIC: &[u8] = b"spectrum-sealed-v1\n";
+const NONCE_LEN: usize = 12;
+
+// AES-256-GCM for the files the server persists. Enabled by setting
+// STORE_ENCRYPTION_KEY to 32 random bytes, base64 encoded.
+pub struct StoreCipher(Aes256Gcm);
+
+impl std::fmt::Debug for StoreCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StoreCipher(..)")
+    }
+}
+
+impl StoreCipher {
+    pub fn from_env() -> Option<Self> {
+        let key = std::env::var("STORE_ENCRYPTION_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())?;
+        let key: [u8; 32] = general_purpose::STANDARD
+            .decode(key.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .expect("STORE_ENCRYPTION_KEY must be 32 bytes, base64 encoded");
+        Some(Self::new(&key))
+    }
+
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        Ok([SEALED_MAGIC, nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn open(&self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "sealed file is truncated",
+            ));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "could not decrypt, wrong STORE_ENCRYPTION_KEY?",
+                )
+            })
+    }
+}
+
+// Encrypts `contents` when a cipher is configured
+pub fn seal(cipher: Option<&StoreCipher>, contents: Vec<u8>) -> io::Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.seal(&contents),
+        None => Ok(contents),
+    }
+}
+
+// Reads back what `seal` wrote. Plain files are still accepted so turning
+// encryption on doesn't lose the existing snapshot; they are sealed on the
+// next save.
+pub fn open(cipher: Option<&StoreCipher>, contents: Vec<u8>) -> io::Result<Vec<u8>> {
+    let Some(sealed) = contents.strip_prefix(SEALED_MAGIC) else {
+        return Ok(contents);
+    };
+    match cipher {
+        Some(cipher) => cipher.open(sealed),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "file is encrypted but STORE_ENCRYPTION_KEY is not set",
+        )),
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SessionExport {
+    pub session_id: Uuid,
+    // The full session record, including images and chat
+    #[schema(value_type = Object)]
+    pub session: Session,
+    pub audit: Vec<AuditEntry>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChildExport {
+    pub child_id: String,
+    pub exported_at: DateTime<Utc>,
+    pub sessions: Vec<SessionExport>,
+    pub rewards: Option<ChildRewards>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChildDeletion {
+    pub child_id: String,
+    pub sessions: usize,
+    // Group sessions deleted, or the child was removed from
+    pub groups: usize,
+    pub audit_logs: usize,
+    // Generated images dropped from the image library and the cache
+    pub images: usize,
+    // Cached outputs derived from the child's sessions, e.g. photo descriptions
+    pub cache_entries: usize,
+    pub rewards: bool,
+}
+
+// Child data API endpoints
+#[utoipa::path(
+    get,
+    path = "/children/{child_id}/export",
+    params(
+        ("child_id" = String, Path, description = "Child id sent with /generate_image, or the session id of an anonymous session"),
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Everything stored about the child", body = ChildExport),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "Nothing is stored about the child")
+    )
+)]
+pub async fn export_child_handler(
+    State(state): State<AppState>,
+    Path(child_id): Path<String>,
+) -> Result<Json<ChildExport>, StatusCode> {
+    let sessions: Vec<(Uuid, Session)> = state
+        .sessions
+        .read()
+        .await
+        .iter()
+        .filter(|(id, session)| session.child_key(**id) == child_id)
+        .map(|(id, session)| (*id, session.clone()))
+        .collect();
+    let rewards = state.rewards.get(&child_id).await;
+    if sessions.is_empty() && rewards.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let mut exports = Vec::new();
+    for (session_id, session) in sessions {
+        exports.push(SessionExport {
+            session_id,
+            session,
+            audit: state.audit.read(session_id).await,
+        });
+    }
+    exports.sort_by_key(|export| export.session.started_at);
+    tracing::info!(child_id, sessions = exports.len(), "Exported child data");
+
+    Ok(Json(ChildExport {
+        child_id,
+        exported_at: Utc::now(),
+        sessions: exports,
+        rewards,
+    }))
+}
+
+// Deletes the group `child_id` names, or takes the child out of every group
+// they joined, deleting the groups nobody is left in. Returns the deleted
+// groups and the number of groups that carry on without the child.
+async fn remove_from_groups(
+    state: &AppState,
+    child_id: &str,
+) -> (Vec<(Uuid, GroupSession)>, usize) {
+    let Ok(id) = child_id.parse::<Uuid>() else {
+        return (vec![], 0);
+    };
+    let mut groups = state.groups.write().await;
+    let mut deleted: Vec<(Uuid, GroupSession)> = groups
+        .remove(&id)
+        .map(|group| (id, group))
+        .into_iter()
+        .collect();
+    let left_by_child: Vec<Uuid> = groups
+        .iter_mut()
+        .filter_map(|(group_id, group)| group.remove_participant(id).then_some(*group_id))
+        .collect();
+    let mut left = 0;
+    for group_id in left_by_child {
+        if groups[&group_id].participants.is_empty() {
+            deleted.extend(groups.remove(&group_id).map(|group| (group_id, group)));
+        } else {
+            left += 1;
+        }
+    }
+    if (!deleted.is_empty() || left > 0)
+        && let Err(err) = state.group_store.save(&groups).await
+    {
+        tracing::error!(%err, "Failed to save groups after deleting a child");
+    }
+    (deleted, left)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/children/{child_id}",
+    params(
+        ("child_id" = String, Path, description = "Child id sent with /generate_image, the session id of an anonymous session, a group participant id or a group id"),
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "What was deleted", body = ChildDeletion),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "Nothing is stored about the child")
+    )
+)]
+pub async fn delete_child_handler(
+    State(state): State<AppState>,
+    Path(child_id): Path<String>,
+) -> Result<Json<ChildDeletion>, StatusCode> {
+    // 1. Drop the sessions and rewrite the store so the snapshot forgets them too
+    let mut sessions = state.sessions.write().await;
+    let session_ids: Vec<Uuid> = sessions
+        .iter()
+        .filter(|(id, session)| session.child_key(**id) == child_id)
+        .map(|(id, _)| *id)
+        .collect();
+    let removed: Vec<Session> = session_ids
+        .iter()
+        .filter_map(|id| sessions.remove(id))
+        .collect();
+    if !removed.is_empty() {
+        set_active_sessions(&state.metrics, &sessions);
+        if let Err(err) = state.session_store.save(&sessions).await {
+            tracing::error!(%err, "Failed to save sessions after deleting a child");
+        }
+    }
+    drop(sessions);
+
+    // 2. Group sessions: the whole group when `child_id` is its id, otherwise
+    // the child's participant record and messages. A group left empty goes too.
+    let (deleted_groups, groups_left) = remove_from_groups(&state, &child_id).await;
+    let groups = deleted_groups.len() + groups_left;
+    let mut session_ids: HashSet<Uuid> = session_ids.into_iter().collect();
+    session_ids.extend(deleted_groups.iter().map(|(id, _)| *id));
+
+    // 3. Rewards
+    let rewards = state.rewards.remove(&child_id).await;
+    if removed.is_empty() && groups == 0 && !rewards {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // 4. Audit logs and live event channels
+    let mut audit_logs = 0;
+    for session_id in &session_ids {
+        if state.audit.delete(*session_id).await {
+            audit_logs += 1;
+        }
+        state.session_events.write().await.remove(session_id);
+    }
+
+    // 5. Images generated for the child's sessions, and every cached output
+    // their calls produced
+    let images: HashSet<String> = removed
+        .iter()
+        .chain(deleted_groups.iter().map(|(_, group)| &group.shared))
+        .flat_map(Session::images)
+        .collect();
+    let mut library = state.image_library.write().await;
+    let library_before = library.len();
+    library.retain(|entry| !session_ids.contains(&entry.session_id));
+    let library_removed = library_before - library.len();
+    drop(library);
+    let cache_images = state.cache.remove_values(&images).await;
+    let cache_entries = state.cache.remove_sessions(&session_ids).await;
+
+    tracing::info!(
+        child_id,
+        sessions = removed.len(),
+        groups,
+        audit_logs,
+        "Deleted child data"
+    );
+    Ok(Json(ChildDeletion {
+        child_id,
+        sessions: removed.len(),
+        groups,
+        audit_logs,
+        images: library_removed + cache_images,
+        cache_entries,
+        rewards,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audit::Stage, cache::CacheKey};
+
+    fn redactor() -> Redactor {
+        Redactor::new(true, &["Oakfield".to_string()])
+    }
+
+    #[test]
+    fn leaves_counting_and_small_numbers_alone() {
+        let redactor = redactor();
+        for text in [
+            "I count 1 2 3 4 5 ducks",
+            "there are 10 20 30 birds",
+            "I am 6 and my brother is 12",
+        ] {
+            assert_eq!(redactor.redact(text), text);
+        }
+    }
+
+    #[test]
+    fn redacts_phone_numbers() {
+        let redactor = redactor();
+        assert_eq!(redactor.redact("call 07700 900123 now"), "call [phone] now");
+        assert_eq!(redactor.redact("it's +44 20 7946 0958"), "it's [phone]");
+        assert_eq!(redactor.redact("(555) 123-4567"), "[phone]");
+        assert_eq!(redactor.redact("mum is 5551234567"), "mum is [phone]");
+    }
+
+    #[test]
+    fn redacts_names_places_and_contact_details() {
+        let redactor = redactor();
+        assert_eq!(
+            redactor.redact("my name is Sam and I live in Leeds"),
+            "my name is [name] and I live in [place]"
+        );
+        assert_eq!(
+            redactor.redact("my mum's name is Priya"),
+            "my mum's name is [name]"
+        );
+        assert_eq!(
+            redactor.redact("I go to Hillside Primary"),
+            "I go to [place]"
+        );
+        assert_eq!(redactor.redact("mail sam.b@example.com"), "mail [email]");
+        assert_eq!(
+            redactor.redact("we are at 12 Elm Street"),
+            "we are at [address]"
+        );
+        assert_eq!(
+            redactor.redact("we went to oakfield"),
+            "we went to [redacted]"
+        );
+        assert_eq!(redactor.redact("a red ball"), "a red ball");
+    }
+
+    #[test]
+    fn disabled_redactor_keeps_the_text() {
+        let redactor = Redactor::new(false, &["Oakfield".to_string()]);
+        assert_eq!(redactor.redact("my name is Sam"), "my name is Sam");
+    }
+
+    #[test]
+    fn sealed_contents_open_with_the_same_key() {
+        let cipher = StoreCipher::new(&[7; 32]);
+        let sealed = seal(Some(&cipher), b"{\"a\":1}".to_vec()).unwrap();
+        assert!(sealed.starts_with(SEALED_MAGIC));
+        assert_eq!(open(Some(&cipher), sealed).unwrap(), b"{\"a\":1}");
+    }
+
+    #[test]
+    fn plain_contents_are_read_once_encryption_is_on() {
+        let cipher = StoreCipher::new(&[7; 32]);
+        assert_eq!(open(Some(&cipher), b"{}".to_vec()).unwrap(), b"{}");
+        assert_eq!(seal(None, b"{}".to_vec()).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn sealed_contents_need_the_right_key() {
+        let sealed = seal(Some(&StoreCipher::new(&[7; 32])), b"{}".to_vec()).unwrap();
+
+        let err = open(Some(&StoreCipher::new(&[8; 32])), sealed.clone()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = open(None, sealed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let truncated = [SEALED_MAGIC, b"short"].concat();
+        let err = open(Some(&StoreCipher::new(&[7; 32])), truncated).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_child_takes_them_out_of_their_groups() {
+        let dir = std::env::temp_dir().join(format!("spectrum-privacy-{}", std::process::id()));
+        let state = AppState::new(
+            "test".to_string(),
+            "test".to_string(),
+            crate::provider::Provider::Mock,
+            dir.to_string_lossy().into_owned(),
+        )
+        .unwrap()
+        .with_store_dir(&dir);
+        let group = GroupSession::new(
+            Session::default(),
+            vec!["Ann".to_string(), "Ben".to_string()],
+        );
+        let (ann, ben) = (group.participants[0].id, group.participants[1].id);
+        let group_id = Uuid::new_v4();
+        state.groups.write().await.insert(group_id, group);
+        state
+            .cache
+            .put(
+                &CacheKey::new(Stage::GenerateDescription, "model", "a cat"),
+                "A cat".to_string(),
+                group_id,
+            )
+            .await;
+
+        let Json(deletion) = delete_child_handler(State(state.clone()), Path(ben.to_string()))
+            .await
+            .unwrap();
+        assert_eq!((deletion.groups, deletion.cache_entries), (1, 0));
+        assert_eq!(state.groups.read().await[&group_id].participants.len(), 1);
+
+        // The last child leaving takes the group and what it cached with them
+        let Json(deletion) = delete_child_handler(State(state.clone()), Path(ann.to_string()))
+            .await
+            .unwrap();
+        assert_eq!((deletion.groups, deletion.cache_entries), (1, 1));
+        assert!(state.groups.read().await.is_empty());
+        assert_eq!(
+            delete_child_handler(State(state), Path(ann.to_string()))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
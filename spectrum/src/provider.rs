@@ -0,0 +1,282 @@
+use image::{ImageFormat, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::HashMap, io::Cursor, time::Instant};
+
+use crate::{AppState, GoogleContent, GooglePart, GoogleRequest, HuggingFaceRequest};
+
+// Text-to-image model on Hugging Face, used with every provider but the mock
+const IMAGE_MODEL: &str = "stabilityai/stable-diffusion-3.5-large-turbo";
+// Side of the mock's placeholder image, in pixels
+const MOCK_IMAGE_SIDE: u32 = 64;
+
+// Text/vision model backend used for the Gemini stages of the pipeline.
+// `Gemini` is what the server runs with; the others exist so sessions can be
+// replayed offline against a local model or a deterministic mock.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Provider {
+    #[default]
+    Gemini,
+    // Any server exposing the OpenAI chat completions API (llama.cpp, vLLM, Ollama, ...)
+    OpenAiCompatible {
+        base_url: String,
+        model: String,
+    },
+    // Returns no output, so every stage falls back to its default response
+    Mock,
+}
+
+// One upstream model response together with the data the audit log needs
+#[derive(Clone, Debug)]
+pub struct ModelReply {
+    pub text: Option<String>,
+    pub model: String,
+    pub latency_ms: u64,
+    pub prompt_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl Provider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Gemini => "gemini",
+            Provider::OpenAiCompatible { .. } => "openai_compatible",
+            Provider::Mock => "mock",
+        }
+    }
+
+    // Sends the parts to the provider. `model` is the Gemini model id the stage
+    // normally uses; other providers substitute their own.
+    pub(crate) async fn generate(
+        &self,
+        model: &str,
+        parts: Vec<GooglePart>,
+        state: &AppState,
+    ) -> ModelReply {
+        let started = Instant::now();
+        let result = match self {
+            Provider::Gemini => generate_gemini(model, parts, state).await,
+            Provider::OpenAiCompatible { base_url, model } => {
+                generate_openai_compatible(base_url, model, parts, state).await
+            }
+            Provider::Mock => Ok((None, None, None)),
+        };
+
+        let model = match self {
+            Provider::Gemini => model.to_string(),
+            Provider::OpenAiCompatible { model, .. } => model.clone(),
+            Provider::Mock => "mock".to_string(),
+        };
+        let latency_ms = started.elapsed().as_millis() as u64;
+
+        match result {
+            Ok((text, prompt_tokens, output_tokens)) => ModelReply {
+                text,
+                model,
+                latency_ms,
+                prompt_tokens,
+                output_tokens,
+                error: None,
+            },
+            Err(err) => ModelReply {
+                text: None,
+                model,
+                latency_ms,
+                prompt_tokens: None,
+                output_tokens: None,
+                error: Some(err.to_string()),
+            },
+        }
+    }
+
+    // Where images come from with this provider, for metrics and the audit log
+    pub fn image_backend(&self) -> &'static str {
+        match self {
+            Provider::Gemini | Provider::OpenAiCompatible { .. } => "huggingface",
+            Provider::Mock => "mock",
+        }
+    }
+
+    pub fn image_model(&self) -> &'static str {
+        match self {
+            Provider::Gemini | Provider::OpenAiCompatible { .. } => IMAGE_MODEL,
+            Provider::Mock => "mock",
+        }
+    }
+
+    // Generates one PNG for the prompt. The mock draws a plain placeholder so
+    // sessions can run offline. The reply describes the image, which is too
+    // large for the audit log.
+    pub(crate) async fn generate_image(
+        &self,
+        prompt: &str,
+        parameters: HashMap<String, serde_json::Value>,
+        state: &AppState,
+    ) -> (Option<Vec<u8>>, ModelReply) {
+        let started = Instant::now();
+        let result = match self {
+            Provider::Gemini | Provider::OpenAiCompatible { .. } => {
+                generate_huggingface_image(prompt, parameters, state).await
+            }
+            Provider::Mock => Ok(mock_image()),
+        };
+
+        let mut reply = ModelReply {
+            text: None,
+            model: self.image_model().to_string(),
+            latency_ms: started.elapsed().as_millis() as u64,
+            prompt_tokens: None,
+            output_tokens: None,
+            error: None,
+        };
+        match result {
+            Ok(image) => {
+                reply.text = Some(format!("<image/png, {} bytes>", image.len()));
+                (Some(image), reply)
+            }
+            Err(err) => {
+                reply.error = Some(err);
+                (None, reply)
+            }
+        }
+    }
+}
+
+// Providers a recorded session can be replayed against: the server's own, the
+// mock, and those named in REPLAY_PROVIDERS, a JSON object such as
+// {"local": {"type": "openai_compatible", "base_url": "http://localhost:8080", "model": "llama"}}.
+// Replay requests pick one by name and can't point the server at another host.
+pub fn replay_providers(server: &Provider) -> Result<HashMap<String, Provider>, String> {
+    let mut providers: HashMap<String, Provider> = match std::env::var("REPLAY_PROVIDERS") {
+        Ok(config) if !config.trim().is_empty() => serde_json::from_str(&config)
+            .map_err(|err| format!("Invalid REPLAY_PROVIDERS: {}", err))?,
+        _ => HashMap::new(),
+    };
+    providers.insert(server.name().to_string(), server.clone());
+    providers.insert(Provider::Mock.name().to_string(), Provider::Mock);
+    Ok(providers)
+}
+
+type Generated = (Option<String>, Option<u64>, Option<u64>);
+
+async fn generate_gemini(
+    model: &str,
+    parts: Vec<GooglePart>,
+    state: &AppState,
+) -> Result<Generated, reqwest::Error> {
+    let request = GoogleRequest {
+        contents: vec![GoogleContent { parts }],
+    };
+
+    let response = state
+        .http_client
+        .post(format!(
+            "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent",
+            model
+        ))
+        .query(&[("key", &state.google_api_key)])
+        .json(&request)
+        .send()
+        .await?
+        .json::<serde_json::Value>()
+        .await?;
+
+    Ok((
+        response["candidates"][0]["content"]["parts"][0]["text"]
+            .as_str()
+            .map(str::to_string),
+        response["usageMetadata"]["promptTokenCount"].as_u64(),
+        response["usageMetadata"]["candidatesTokenCount"].as_u64(),
+    ))
+}
+
+async fn generate_huggingface_image(
+    prompt: &str,
+    parameters: HashMap<String, serde_json::Value>,
+    state: &AppState,
+) -> Result<Vec<u8>, String> {
+    let request = HuggingFaceRequest {
+        inputs: prompt.to_string(),
+        parameters,
+    };
+
+    let response = state
+        .http_client
+        .post(format!(
+            "https://api-inference.huggingface.co/models/{}",
+            IMAGE_MODEL
+        ))
+        .header(
+            "Authorization",
+            format!("Bearer {}", state.huggingface_token),
+        )
+        .json(&request)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    // Failures come back as JSON error bodies, not images
+    let status = response.status();
+    let body = response.bytes().await.map_err(|err| err.to_string())?;
+    if !status.is_success() {
+        return Err(format!(
+            "{}: {}",
+            status,
+            String::from_utf8_lossy(&body).trim()
+        ));
+    }
+    Ok(body.to_vec())
+}
+
+fn mock_image() -> Vec<u8> {
+    let image = RgbImage::from_pixel(MOCK_IMAGE_SIDE, MOCK_IMAGE_SIDE, Rgb([200, 210, 220]));
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .expect("encoding a PNG in memory can't fail");
+    png
+}
+
+async fn generate_openai_compatible(
+    base_url: &str,
+    model: &str,
+    parts: Vec<GooglePart>,
+    state: &AppState,
+) -> Result<Generated, reqwest::Error> {
+    // Translate Gemini parts into chat completion content parts
+    let content: Vec<serde_json::Value> = parts
+        .into_iter()
+        .map(|part| match (part.text, part.inline_data) {
+            (_, Some(data)) => json!({
+                "type": "image_url",
+                "image_url": { "url": format!("data:{};base64,{}", data.mime_type, data.data) }
+            }),
+            (text, None) => json!({ "type": "text", "text": text.unwrap_or_default() }),
+        })
+        .collect();
+
+    let response = state
+        .http_client
+        .post(format!(
+            "{}/v1/chat/completions",
+            base_url.trim_end_matches('/')
+        ))
+        .json(&json!({
+            "model": model,
+            "messages": [{ "role": "user", "content": content }]
+        }))
+        .send()
+        .await?
+        .json::<serde_json::Value>()
+        .await?;
+
+    Ok((
+        response["choices"][0]["message"]["content"]
+            .as_str()
+            .map(str::to_string),
+        response["usage"]["prompt_tokens"].as_u64(),
+        response["usage"]["completion_tokens"].as_u64(),
+    ))
+}
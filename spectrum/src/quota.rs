@@ -0,0 +1,416 @@
+use axum::{
+    RequestPartsExt, async_trait,
+    body::{Body, to_bytes},
+    extract::{FromRequestParts, Path, Request, State},
+    http::{HeaderMap, StatusCode, header, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
+
+use crate::{AppState, store::Store};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+pub const DEFAULT_CLINIC: &str = "default";
+
+// Largest body the rate limiter reads to find the session a request is for
+const MAX_INSPECTED_BODY: usize = 64 * 1024;
+
+// API keys by the clinic they belong to, loaded from the JSON file in
+// `CLINIC_KEYS_FILE`: {"clinic-a": "key-a", "clinic-b": "key-b"}. Without the
+// file every request is billed to the default clinic.
+#[derive(Clone, Debug, Default)]
+pub struct ClinicKeys {
+    clinics_by_key: HashMap<String, String>,
+}
+
+impl ClinicKeys {
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(path) = std::env::var("CLINIC_KEYS_FILE") else {
+            return Ok(Self::default());
+        };
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Can't read clinic keys file {}: {}", path, err))?;
+        let keys: HashMap<String, String> = serde_json::from_str(&contents)
+            .map_err(|err| format!("Invalid clinic keys file {}: {}", path, err))?;
+        Ok(Self::new(keys))
+    }
+
+    pub fn new(keys_by_clinic: HashMap<String, String>) -> Self {
+        Self {
+            clinics_by_key: keys_by_clinic
+                .into_iter()
+                .map(|(clinic, key)| (key, clinic))
+                .collect(),
+        }
+    }
+
+    // Tenant a request is billed to, from the API key it presents
+    pub fn clinic(&self, headers: &HeaderMap) -> Result<String, (StatusCode, &'static str)> {
+        if self.clinics_by_key.is_empty() {
+            return Ok(DEFAULT_CLINIC.to_string());
+        }
+        headers
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|key| self.clinics_by_key.get(key))
+            .cloned()
+            .ok_or((StatusCode::UNAUTHORIZED, "Missing or unknown API key."))
+    }
+}
+
+// Extracts the clinic a request is billed to
+pub struct Clinic(pub String);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Clinic {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        state.clinic_keys.clinic(&parts.headers).map(Clinic)
+    }
+}
+
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// Token bucket per key, refilled continuously
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn per_minute(limit: u32) -> Self {
+        Self {
+            capacity: limit as f64,
+            refill_per_sec: limit as f64 / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a token for `key`, or returns how long until one is available
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let (tokens, last) = buckets
+            .entry(key.to_string())
+            .or_insert((self.capacity, now));
+
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.refill_per_sec)
+            .min(self.capacity);
+        *last = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - *tokens) / self.refill_per_sec,
+            ))
+        }
+    }
+}
+
+// Rate limits for the routes that trigger paid upstream calls
+#[derive(Debug)]
+pub struct RateLimits {
+    pub per_session: RateLimiter,
+    pub per_clinic: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn from_env() -> Self {
+        Self {
+            per_session: RateLimiter::per_minute(env_or("SESSION_RATE_LIMIT_PER_MIN", 20)),
+            per_clinic: RateLimiter::per_minute(env_or("CLINIC_RATE_LIMIT_PER_MIN", 120)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SessionRef {
+    session_id: Option<String>,
+}
+
+// Session or group a paid request is made for, from the `session_id` or
+// `group_id` path parameter or the `session_id` field of a JSON body. The
+// body is read here and handed back to the route untouched; other bodies,
+// like photo uploads, are left unread.
+async fn session_key(request: Request) -> Result<(Request, Option<String>), Response> {
+    let (mut parts, body) = request.into_parts();
+    if let Ok(Path(params)) = parts.extract::<Path<HashMap<String, String>>>().await
+        && let Some(id) = params.get("session_id").or(params.get("group_id"))
+    {
+        return Ok((Request::from_parts(parts, body), Some(id.clone())));
+    }
+    let is_json = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return Ok((Request::from_parts(parts, body), None));
+    }
+
+    let bytes = to_bytes(body, MAX_INSPECTED_BODY).await.map_err(|_| {
+        (StatusCode::PAYLOAD_TOO_LARGE, "Request body is too large.").into_response()
+    })?;
+    let session = serde_json::from_slice::<SessionRef>(&bytes)
+        .ok()
+        .and_then(|body| body.session_id);
+    Ok((Request::from_parts(parts, Body::from(bytes)), session))
+}
+
+// Tower middleware applied to the paid routes. The clinic comes from the API
+// key; requests that don't belong to a session yet, like the one starting it,
+// are only limited per clinic.
+pub async fn rate_limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let clinic = match state.clinic_keys.clinic(request.headers()) {
+        Ok(clinic) => clinic,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let (request, session) = match session_key(request).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let mut result = state.rate_limits.per_clinic.check(&clinic);
+    if let (Ok(()), Some(session)) = (&result, &session) {
+        result = state.rate_limits.per_session.check(session);
+    }
+
+    match result {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [("retry-after", (retry_after.as_secs() + 1).to_string())],
+            "Too many requests, please slow down.",
+        )
+            .into_response(),
+    }
+}
+
+// Estimated prices in USD used for the cost ledger
+#[derive(Clone, Debug, Serialize)]
+pub struct Pricing {
+    pub per_1k_prompt_tokens: f64,
+    pub per_1k_output_tokens: f64,
+    pub per_image: f64,
+}
+
+impl Pricing {
+    pub fn from_env() -> Self {
+        Self {
+            per_1k_prompt_tokens: env_or("COST_PER_1K_PROMPT_TOKENS", 0.000075),
+            per_1k_output_tokens: env_or("COST_PER_1K_OUTPUT_TOKENS", 0.0003),
+            per_image: env_or("COST_PER_IMAGE", 0.04),
+        }
+    }
+}
+
+// Running usage for one clinic in one calendar month
+#[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct ClinicUsage {
+    pub month: String,
+    pub model_calls: u64,
+    pub prompt_tokens: u64,
+    pub output_tokens: u64,
+    pub image_generations: u64,
+    pub estimated_cost_usd: f64,
+}
+
+// Monthly budgets in USD, loaded from the JSON file in `CLINIC_BUDGETS_FILE`:
+// {"default": 50.0, "clinics": {"clinic-a": 200.0}}
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Budgets {
+    pub default: Option<f64>,
+    #[serde(default)]
+    pub clinics: HashMap<String, f64>,
+}
+
+impl Budgets {
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(path) = std::env::var("CLINIC_BUDGETS_FILE") else {
+            return Ok(Self::default());
+        };
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Can't read budgets file {}: {}", path, err))?;
+        serde_json::from_str(&contents)
+            .map_err(|err| format!("Invalid budgets file {}: {}", path, err))
+    }
+
+    pub fn for_clinic(&self, clinic: &str) -> Option<f64> {
+        self.clinics.get(clinic).copied().or(self.default)
+    }
+}
+
+// Per-clinic cost accounting for upstream calls, saved with the sessions so a
+// restart doesn't reset a clinic's spend for the month
+#[derive(Debug)]
+pub struct Ledger {
+    pricing: Pricing,
+    budgets: Budgets,
+    usage: Mutex<HashMap<String, ClinicUsage>>,
+    store: Store<HashMap<String, ClinicUsage>>,
+}
+
+impl Ledger {
+    pub fn new(
+        pricing: Pricing,
+        budgets: Budgets,
+        store: Store<HashMap<String, ClinicUsage>>,
+    ) -> Self {
+        Self {
+            pricing,
+            budgets,
+            usage: Mutex::new(HashMap::new()),
+            store,
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Self::new(
+            Pricing::from_env(),
+            Budgets::from_env()?,
+            Store::from_env("USAGE_STORE_PATH", "usage.json"),
+        ))
+    }
+
+    // Same pricing and budgets, empty usage kept in `store`
+    pub fn with_store(&self, store: Store<HashMap<String, ClinicUsage>>) -> Self {
+        Self::new(self.pricing.clone(), self.budgets.clone(), store)
+    }
+
+    pub async fn load(&self) -> io::Result<usize> {
+        let usage = self.store.load().await?;
+        let count = usage.len();
+        *self.usage.lock().unwrap() = usage;
+        Ok(count)
+    }
+
+    pub async fn save(&self) -> io::Result<()> {
+        let usage = self.usage.lock().unwrap().clone();
+        self.store.save(&usage).await
+    }
+
+    fn with_usage<T>(&self, clinic: &str, f: impl FnOnce(&mut ClinicUsage) -> T) -> T {
+        let month = current_month();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(clinic.to_string()).or_default();
+        // A new month starts a fresh ledger
+        if entry.month != month {
+            *entry = ClinicUsage {
+                month,
+                ..Default::default()
+            };
+        }
+        f(entry)
+    }
+
+    pub fn record_tokens(&self, clinic: &str, prompt_tokens: u64, output_tokens: u64) {
+        let cost = prompt_tokens as f64 / 1000.0 * self.pricing.per_1k_prompt_tokens
+            + output_tokens as f64 / 1000.0 * self.pricing.per_1k_output_tokens;
+        self.with_usage(clinic, |usage| {
+            usage.model_calls += 1;
+            usage.prompt_tokens += prompt_tokens;
+            usage.output_tokens += output_tokens;
+            usage.estimated_cost_usd += cost;
+        });
+    }
+
+    pub fn record_image(&self, clinic: &str) {
+        self.with_usage(clinic, |usage| {
+            usage.image_generations += 1;
+            usage.estimated_cost_usd += self.pricing.per_image;
+        });
+    }
+
+    // Reading doesn't add the clinic, so only clinics that spent are saved
+    pub fn usage(&self, clinic: &str) -> ClinicUsage {
+        let month = current_month();
+        match self.usage.lock().unwrap().get(clinic) {
+            Some(usage) if usage.month == month => usage.clone(),
+            _ => ClinicUsage {
+                month,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn budget(&self, clinic: &str) -> Option<f64> {
+        self.budgets.for_clinic(clinic)
+    }
+
+    pub fn over_budget(&self, clinic: &str) -> bool {
+        match self.budget(clinic) {
+            Some(budget) => self.usage(clinic).estimated_cost_usd >= budget,
+            None => false,
+        }
+    }
+}
+
+fn current_month() -> String {
+    chrono::Utc::now().format("%Y-%m").to_string()
+}
+
+// Rough token count for providers that don't report usage
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ledger(budgets: Budgets) -> Ledger {
+        let path = std::env::temp_dir().join(format!("spectrum-usage-{}.json", std::process::id()));
+        Ledger::new(Pricing::from_env(), budgets, Store::new(path, None))
+    }
+
+    #[test]
+    fn reading_usage_adds_no_clinic() {
+        let ledger = ledger(Budgets {
+            default: Some(1.0),
+            clinics: HashMap::new(),
+        });
+
+        let usage = ledger.usage("unknown");
+        assert_eq!(usage.model_calls, 0);
+        assert_eq!(usage.month, current_month());
+        assert!(!ledger.over_budget("unknown"));
+        assert!(ledger.usage.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn spending_is_billed_and_checked_against_the_budget() {
+        let ledger = ledger(Budgets {
+            default: None,
+            clinics: [("clinic-a".to_string(), 0.01)].into(),
+        });
+
+        ledger.record_tokens("clinic-a", 1000, 0);
+        assert!(!ledger.over_budget("clinic-a"));
+        for _ in 0..200 {
+            ledger.record_tokens("clinic-a", 1000, 1000);
+        }
+        assert_eq!(ledger.usage("clinic-a").model_calls, 201);
+        assert!(ledger.over_budget("clinic-a"));
+        assert!(!ledger.over_budget("clinic-b"));
+    }
+}
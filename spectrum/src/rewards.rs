@@ -0,0 +1,487 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io};
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+
+use crate::{AppState, RoundRecord, store::Store};
+
+// Sticker collection a child unlocks stickers from
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StickerTheme {
+    #[default]
+    Animals,
+    Space,
+    Dinosaurs,
+    Vehicles,
+    Ocean,
+}
+
+impl StickerTheme {
+    // Stickers in the order they are unlocked
+    fn stickers(self) -> &'static [&'static str] {
+        match self {
+            StickerTheme::Animals => &[
+                "Happy Puppy",
+                "Sleepy Cat",
+                "Wise Owl",
+                "Busy Bee",
+                "Friendly Elephant",
+                "Jumping Kangaroo",
+                "Proud Lion",
+                "Gentle Giraffe",
+            ],
+            StickerTheme::Space => &[
+                "Shining Star",
+                "Crescent Moon",
+                "Red Planet",
+                "Rocket Ship",
+                "Ringed Planet",
+                "Astronaut",
+                "Comet",
+                "Space Station",
+            ],
+            StickerTheme::Dinosaurs => &[
+                "Baby Dino",
+                "Stegosaurus",
+                "Triceratops",
+                "Pterodactyl",
+                "Brachiosaurus",
+                "Dino Egg",
+                "Ankylosaurus",
+                "T. Rex",
+            ],
+            StickerTheme::Vehicles => &[
+                "Red Car",
+                "School Bus",
+                "Fire Truck",
+                "Steam Train",
+                "Sailboat",
+                "Helicopter",
+                "Tractor",
+                "Hot Air Balloon",
+            ],
+            StickerTheme::Ocean => &[
+                "Clownfish",
+                "Starfish",
+                "Sea Turtle",
+                "Octopus",
+                "Seahorse",
+                "Dolphin",
+                "Whale",
+                "Treasure Chest",
+            ],
+        }
+    }
+}
+
+// How a child earns tokens, set per child by the therapist
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct RewardSchedule {
+    // Tokens for each checklist item the child finds
+    pub tokens_per_detail: u32,
+    // Tokens for finishing an image
+    pub image_bonus: u32,
+    // Images in a row finished without hints that make up a streak
+    pub streak_length: u32,
+    // Extra tokens each time a streak is completed
+    pub streak_bonus: u32,
+    // Lifetime tokens needed for each sticker
+    pub tokens_per_sticker: u32,
+    pub theme: StickerTheme,
+}
+
+impl Default for RewardSchedule {
+    fn default() -> Self {
+        Self {
+            tokens_per_detail: 1,
+            image_bonus: 3,
+            streak_length: 3,
+            streak_bonus: 5,
+            tokens_per_sticker: 10,
+            theme: StickerTheme::default(),
+        }
+    }
+}
+
+// Largest token amount a schedule may set for any one event
+const MAX_TOKENS_PER_EVENT: u32 = 1000;
+
+impl RewardSchedule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.streak_length == 0 {
+            return Err("streak_length must be at least 1".to_string());
+        }
+        if self.tokens_per_sticker == 0 {
+            return Err("tokens_per_sticker must be at least 1".to_string());
+        }
+        for (name, value) in [
+            ("tokens_per_detail", self.tokens_per_detail),
+            ("image_bonus", self.image_bonus),
+            ("streak_bonus", self.streak_bonus),
+            ("tokens_per_sticker", self.tokens_per_sticker),
+        ] {
+            if value > MAX_TOKENS_PER_EVENT {
+                return Err(format!("{} must be at most {}", name, MAX_TOKENS_PER_EVENT));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Sticker {
+    pub theme: StickerTheme,
+    pub name: String,
+    pub unlocked_at: DateTime<Utc>,
+}
+
+// Everything a child has earned, kept across sessions
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChildRewards {
+    pub child_id: String,
+    pub schedule: RewardSchedule,
+    // Lifetime tokens; stickers are unlocked from this, not spent
+    pub tokens: u32,
+    // Images in a row finished without hints
+    pub streak: u32,
+    pub best_streak: u32,
+    pub stickers: Vec<Sticker>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// What one chat turn earned, for the UI to celebrate
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct RewardUpdate {
+    pub tokens_earned: u32,
+    pub tokens: u32,
+    pub streak: u32,
+    // Set when this turn completed a streak
+    pub streak_completed: bool,
+    pub new_stickers: Vec<Sticker>,
+}
+
+impl ChildRewards {
+    fn new(child_id: &str) -> Self {
+        Self {
+            child_id: child_id.to_string(),
+            schedule: RewardSchedule::default(),
+            tokens: 0,
+            streak: 0,
+            best_streak: 0,
+            stickers: Vec::new(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    // Awards tokens for newly found details and, when the image was finished,
+    // the image and streak bonuses. Totals saturate rather than overflow.
+    pub fn award(&mut self, details_found: usize, finished: Option<&RoundRecord>) -> RewardUpdate {
+        let schedule = &self.schedule;
+        let mut earned = schedule
+            .tokens_per_detail
+            .saturating_mul(u32::try_from(details_found).unwrap_or(u32::MAX));
+        let mut streak_completed = false;
+
+        if let Some(round) = finished {
+            earned = earned.saturating_add(schedule.image_bonus);
+            if round.used_hints.is_empty() {
+                self.streak = self.streak.saturating_add(1);
+                self.best_streak = self.best_streak.max(self.streak);
+                if self.streak.is_multiple_of(schedule.streak_length) {
+                    earned = earned.saturating_add(schedule.streak_bonus);
+                    streak_completed = true;
+                }
+            } else {
+                self.streak = 0;
+            }
+        }
+        self.tokens = self.tokens.saturating_add(earned);
+        self.updated_at = Utc::now();
+
+        RewardUpdate {
+            tokens_earned: earned,
+            tokens: self.tokens,
+            streak: self.streak,
+            streak_completed,
+            new_stickers: self.unlock_stickers(),
+        }
+    }
+
+    // Unlocks a sticker from the current theme for every `tokens_per_sticker`
+    // tokens earned, until the theme runs out
+    fn unlock_stickers(&mut self) -> Vec<Sticker> {
+        let theme = self.schedule.theme;
+        let earned = (self.tokens / self.schedule.tokens_per_sticker) as usize;
+        let mut unlocked = Vec::new();
+        while self.stickers.len() < earned {
+            let Some(name) = theme.stickers().iter().find(|name| {
+                !self
+                    .stickers
+                    .iter()
+                    .any(|s| s.theme == theme && s.name == **name)
+            }) else {
+                break;
+            };
+            let sticker = Sticker {
+                theme,
+                name: name.to_string(),
+                unlocked_at: Utc::now(),
+            };
+            self.stickers.push(sticker.clone());
+            unlocked.push(sticker);
+        }
+        unlocked
+    }
+}
+
+// Rewards of every child, written to disk after each change and encrypted
+// like the session store
+#[derive(Debug)]
+pub struct RewardBook {
+    children: RwLock<HashMap<String, ChildRewards>>,
+    store: Store<HashMap<String, ChildRewards>>,
+}
+
+impl RewardBook {
+    pub fn new(store: Store<HashMap<String, ChildRewards>>) -> Self {
+        Self {
+            children: RwLock::new(HashMap::new()),
+            store,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(Store::from_env("REWARDS_STORE_PATH", "rewards.json"))
+    }
+
+    pub async fn load(&self) -> io::Result<usize> {
+        let children = self.store.load().await?;
+        let count = children.len();
+        *self.children.write().await = children;
+        Ok(count)
+    }
+
+    pub async fn get(&self, child_id: &str) -> Option<ChildRewards> {
+        self.children.read().await.get(child_id).cloned()
+    }
+
+    pub async fn award(
+        &self,
+        child_id: &str,
+        details_found: usize,
+        finished: Option<&RoundRecord>,
+    ) -> RewardUpdate {
+        let mut children = self.children.write().await;
+        let rewards = children
+            .entry(child_id.to_string())
+            .or_insert_with(|| ChildRewards::new(child_id));
+        let update = rewards.award(details_found, finished);
+        if update.tokens_earned > 0 || finished.is_some() {
+            self.save(&children).await;
+        }
+        update
+    }
+
+    pub async fn set_schedule(&self, child_id: &str, schedule: RewardSchedule) -> ChildRewards {
+        let mut children = self.children.write().await;
+        let rewards = children
+            .entry(child_id.to_string())
+            .or_insert_with(|| ChildRewards::new(child_id));
+        rewards.schedule = schedule;
+        rewards.updated_at = Utc::now();
+        let rewards = rewards.clone();
+        self.save(&children).await;
+        rewards
+    }
+
+    // Forgets the child; false when nothing was stored
+    pub async fn remove(&self, child_id: &str) -> bool {
+        let mut children = self.children.write().await;
+        let removed = children.remove(child_id).is_some();
+        if removed {
+            self.save(&children).await;
+        }
+        removed
+    }
+
+    // Called with the write lock held so saves never race each other. A
+    // failed save is logged; the in-memory rewards stay authoritative.
+    async fn save(&self, children: &HashMap<String, ChildRewards>) {
+        if let Err(err) = self.store.save(children).await {
+            tracing::error!(%err, "Failed to save rewards");
+        }
+    }
+}
+
+// Reward API endpoints
+#[utoipa::path(
+    get,
+    path = "/children/{child_id}/rewards",
+    params(
+        ("child_id" = String, Path, description = "Child id sent with /generate_image"),
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Tokens, streak and stickers earned so far", body = ChildRewards),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "Child has no rewards yet")
+    )
+)]
+pub async fn child_rewards_handler(
+    State(state): State<AppState>,
+    Path(child_id): Path<String>,
+) -> Result<Json<ChildRewards>, StatusCode> {
+    state
+        .rewards
+        .get(&child_id)
+        .await
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[utoipa::path(
+    put,
+    path = "/children/{child_id}/reward_schedule",
+    request_body = RewardSchedule,
+    params(
+        ("child_id" = String, Path, description = "Child id sent with /generate_image"),
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Child's rewards with the new schedule", body = ChildRewards),
+        (status = 400, description = "Invalid schedule"),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled")
+    )
+)]
+pub async fn set_reward_schedule_handler(
+    State(state): State<AppState>,
+    Path(child_id): Path<String>,
+    Json(schedule): Json<RewardSchedule>,
+) -> Result<Json<ChildRewards>, (StatusCode, String)> {
+    schedule
+        .validate()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    Ok(Json(state.rewards.set_schedule(&child_id, schedule).await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::Activity;
+
+    fn round(hints: usize) -> RoundRecord {
+        RoundRecord {
+            prompt: None,
+            image: None,
+            sequence: vec![],
+            difficulty: "Very Simple".to_string(),
+            topic_focus: "animals".to_string(),
+            activity: Activity::default(),
+            key_details: vec![],
+            identified_details: vec![],
+            used_hints: vec!["Look closer".to_string(); hints],
+            chat: vec![],
+            started_at: None,
+            ended_at: Utc::now(),
+        }
+    }
+
+    fn sticker_names(rewards: &ChildRewards, theme: StickerTheme) -> Vec<&str> {
+        rewards
+            .stickers
+            .iter()
+            .filter(|sticker| sticker.theme == theme)
+            .map(|sticker| sticker.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn awards_details_image_and_streak_bonuses() {
+        let mut rewards = ChildRewards::new("child");
+
+        let update = rewards.award(2, None);
+        assert_eq!(update.tokens_earned, 2);
+
+        // Default schedule: 3 per image, 5 more every third hint-free image
+        assert_eq!(rewards.award(0, Some(&round(0))).tokens_earned, 3);
+        assert_eq!(rewards.award(0, Some(&round(0))).tokens_earned, 3);
+        let update = rewards.award(1, Some(&round(0)));
+        assert_eq!(update.tokens_earned, 1 + 3 + 5);
+        assert!(update.streak_completed);
+        assert_eq!(update.streak, 3);
+
+        // A hint breaks the streak but keeps the best one
+        let update = rewards.award(0, Some(&round(1)));
+        assert_eq!(update.tokens_earned, 3);
+        assert_eq!(update.streak, 0);
+        assert_eq!(rewards.best_streak, 3);
+        assert_eq!(rewards.tokens, 2 + 3 + 3 + 9 + 3);
+    }
+
+    #[test]
+    fn saturates_instead_of_overflowing() {
+        let mut rewards = ChildRewards::new("child");
+        rewards.schedule.tokens_per_detail = MAX_TOKENS_PER_EVENT;
+        rewards.tokens = u32::MAX - 1;
+
+        let update = rewards.award(usize::MAX, Some(&round(0)));
+        assert_eq!(update.tokens_earned, u32::MAX);
+        assert_eq!(rewards.tokens, u32::MAX);
+    }
+
+    #[test]
+    fn rejects_schedules_that_could_overflow() {
+        let schedule = RewardSchedule {
+            image_bonus: u32::MAX,
+            ..Default::default()
+        };
+        assert!(schedule.validate().is_err());
+        assert!(RewardSchedule::default().validate().is_ok());
+    }
+
+    #[test]
+    fn unlocks_stickers_in_order_until_the_theme_runs_out() {
+        let mut rewards = ChildRewards::new("child");
+
+        let update = rewards.award(25, None);
+        let names: Vec<&str> = update
+            .new_stickers
+            .iter()
+            .map(|sticker| sticker.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Happy Puppy", "Sleepy Cat"]);
+
+        // Enough tokens for twelve stickers, but the theme only has eight
+        rewards.award(95, None);
+        assert_eq!(sticker_names(&rewards, StickerTheme::Animals).len(), 8);
+        assert!(rewards.award(10, None).new_stickers.is_empty());
+    }
+
+    #[test]
+    fn switching_theme_unlocks_the_stickers_owed() {
+        let mut rewards = ChildRewards::new("child");
+        rewards.award(100, None);
+        assert_eq!(rewards.stickers.len(), 8);
+
+        rewards.schedule.theme = StickerTheme::Space;
+        let update = rewards.award(10, None);
+
+        // 110 tokens earn eleven stickers: the eight animals and three from space
+        assert_eq!(update.new_stickers.len(), 3);
+        assert_eq!(
+            sticker_names(&rewards, StickerTheme::Space),
+            vec!["Shining Star", "Crescent Moon", "Red Planet"]
+        );
+        assert_eq!(sticker_names(&rewards, StickerTheme::Animals).len(), 8);
+    }
+}
@@ -0,0 +1,332 @@
+use image::{DynamicImage, imageops::FilterType};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+// Negative prompt used for every child; the profile adds to it
+const BASE_NEGATIVE_PROMPT: &str = "ugly, blurry, poorly drawn hands, lewd, nude, deformed, missing limbs, missing eyes, missing arms, missing legs";
+
+// Side of the thumbnail the image metrics are computed on
+const METRICS_SIZE: u32 = 256;
+// Luma gradient (0-255) above which a pixel counts as an edge
+const EDGE_THRESHOLD: f32 = 48.0;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Palette {
+    // No colour restriction
+    #[default]
+    Any,
+    Muted,
+    Pastel,
+    Monochrome,
+    Warm,
+    Cool,
+}
+
+impl Palette {
+    fn prompt_text(&self) -> Option<&'static str> {
+        match self {
+            Palette::Any => None,
+            Palette::Muted => Some("Use only soft, muted, low-saturation colors."),
+            Palette::Pastel => Some("Use only light pastel colors."),
+            Palette::Monochrome => {
+                Some("Use a monochrome palette: shades of a single gentle color or greyscale.")
+            }
+            Palette::Warm => Some("Use a calm, warm palette of soft browns, creams and oranges."),
+            Palette::Cool => Some("Use a calm, cool palette of soft blues, greens and greys."),
+        }
+    }
+
+    fn negative_prompt(&self) -> Option<&'static str> {
+        match self {
+            Palette::Any => None,
+            Palette::Monochrome => Some("colorful, multicolored, rainbow, neon, saturated colors"),
+            _ => Some("neon, fluorescent, vivid saturated colors, high contrast"),
+        }
+    }
+
+    // Highest mean HSV saturation accepted for the palette
+    fn max_saturation(&self) -> Option<f64> {
+        match self {
+            Palette::Any => None,
+            Palette::Muted => Some(0.45),
+            Palette::Pastel => Some(0.35),
+            Palette::Monochrome => Some(0.15),
+            Palette::Warm | Palette::Cool => Some(0.55),
+        }
+    }
+}
+
+// Per-child sensory settings, compiled into the image prompt, the image
+// generation parameters and the checks run on the generated image. The
+// default restricts and checks nothing; therapists opt a child into each
+// setting.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default, ToSchema)]
+pub struct SensoryProfile {
+    #[serde(default)]
+    pub palette: Palette,
+    // Most people, animals or main objects in one picture
+    #[serde(default)]
+    pub max_subjects: Option<u32>,
+    #[serde(default)]
+    pub avoid_crowds: bool,
+    // No busy, noisy-looking scenes (traffic, parties, fireworks, ...)
+    #[serde(default)]
+    pub avoid_loud_scenes: bool,
+    // e.g. "flat vector illustration" or "soft watercolor"
+    #[serde(default)]
+    pub art_style: Option<String>,
+    // Overrides the palette's saturation limit (mean HSV saturation, 0-1)
+    #[serde(default)]
+    pub max_saturation: Option<f64>,
+    // Highest share of edge pixels accepted, a proxy for visual clutter (0-1)
+    #[serde(default)]
+    pub max_complexity: Option<f64>,
+}
+
+impl SensoryProfile {
+    // Extra guidance appended to the image prompt query in `generate_prompt`
+    pub fn prompt_guidance(&self) -> String {
+        let mut lines = vec![];
+        if let Some(text) = self.palette.prompt_text() {
+            lines.push(text.to_string());
+        }
+        if let Some(max) = self.max_subjects {
+            lines.push(format!(
+                "Show at most {} {} in the picture.",
+                max,
+                if max == 1 {
+                    "main subject"
+                } else {
+                    "main subjects"
+                }
+            ));
+        }
+        if self.avoid_crowds {
+            lines.push("Never show crowds or groups of many people.".to_string());
+        }
+        if self.avoid_loud_scenes {
+            lines.push(
+                "The scene must look quiet: no traffic, parties, fireworks, machines, shouting or anything that suggests loud noise.".to_string(),
+            );
+        }
+        if let Some(style) = &self.art_style {
+            lines.push(format!("Draw everything in this art style: {}.", style));
+        }
+
+        if lines.is_empty() {
+            return String::new();
+        }
+        format!(
+            "The child has these sensory needs, which the prompt must respect:\n        - {}",
+            lines.join("\n        - ")
+        )
+    }
+
+    pub fn negative_prompt(&self) -> String {
+        let mut parts = vec![BASE_NEGATIVE_PROMPT];
+        if let Some(text) = self.palette.negative_prompt() {
+            parts.push(text);
+        }
+        if self.avoid_crowds {
+            parts.push("crowd, many people, busy street");
+        }
+        if self.avoid_loud_scenes {
+            parts.push("explosion, fireworks, traffic, party, screaming, chaotic scene");
+        }
+        if self.max_subjects.is_some() {
+            parts.push("cluttered, too many objects");
+        }
+        parts.join(", ")
+    }
+
+    // Parameters sent to the Hugging Face text-to-image endpoint
+    pub fn generation_parameters(&self) -> HashMap<String, serde_json::Value> {
+        let mut map = HashMap::new();
+        // Follow the prompt more closely when it carries restrictions
+        let restricted = self.palette != Palette::Any || self.max_subjects.is_some();
+        map.insert(
+            "guidance_scale".to_string(),
+            json!(if restricted { 8.5 } else { 7.5 }),
+        );
+        map.insert("negative_prompt".to_string(), json!(self.negative_prompt()));
+        map.insert("num_inference_steps".to_string(), json!(50));
+        map
+    }
+
+    pub fn saturation_limit(&self) -> Option<f64> {
+        self.max_saturation.or(self.palette.max_saturation())
+    }
+
+    // Checks the image fails; empty when it fits the profile
+    pub fn violations(&self, metrics: &ImageMetrics) -> Vec<Check> {
+        let mut violations = vec![];
+        if self
+            .saturation_limit()
+            .is_some_and(|limit| metrics.mean_saturation > limit)
+        {
+            violations.push(Check::Saturation);
+        }
+        if self
+            .max_complexity
+            .is_some_and(|limit| metrics.edge_density > limit)
+        {
+            violations.push(Check::Complexity);
+        }
+        violations
+    }
+
+    // Added to the prompt when an image is regenerated after failing the checks
+    pub fn retry_guidance(&self, violations: &[Check]) -> String {
+        let mut text = String::from(" The picture must be calm and simple");
+        if violations.contains(&Check::Saturation) {
+            text.push_str(", with gentle, desaturated colors");
+        }
+        if violations.contains(&Check::Complexity) {
+            text.push_str(", a plain background and very few objects");
+        }
+        text.push('.');
+        text
+    }
+}
+
+// Check run on every generated image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    Saturation,
+    Complexity,
+}
+
+impl Check {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Check::Saturation => "saturation",
+            Check::Complexity => "complexity",
+        }
+    }
+}
+
+// Image statistics used to check a generated image against a profile
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ImageMetrics {
+    // Mean HSV saturation, 0 (grey) to 1 (fully saturated)
+    pub mean_saturation: f64,
+    // Share of pixels on a strong luma edge, 0 to 1
+    pub edge_density: f64,
+}
+
+impl ImageMetrics {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let image = image::load_from_memory(bytes).ok()?;
+        Some(Self::from_image(&image))
+    }
+
+    pub fn from_image(image: &DynamicImage) -> Self {
+        let rgb = image
+            .resize(METRICS_SIZE, METRICS_SIZE, FilterType::Triangle)
+            .to_rgb8();
+        let (width, height) = rgb.dimensions();
+
+        let mut saturation = 0.0;
+        let mut luma = Vec::with_capacity((width * height) as usize);
+        for pixel in rgb.pixels() {
+            let [r, g, b] = pixel.0.map(f32::from);
+            let max = r.max(g).max(b);
+            let min = r.min(g).min(b);
+            if max > 0.0 {
+                saturation += ((max - min) / max) as f64;
+            }
+            luma.push(0.299 * r + 0.587 * g + 0.114 * b);
+        }
+
+        // Central-difference gradient magnitude on the luma channel
+        let mut edges = 0usize;
+        let mut interior = 0usize;
+        let at = |x: u32, y: u32| luma[(y * width + x) as usize];
+        for y in 1..height.saturating_sub(1) {
+            for x in 1..width.saturating_sub(1) {
+                let dx = at(x + 1, y) - at(x - 1, y);
+                let dy = at(x, y + 1) - at(x, y - 1);
+                if (dx * dx + dy * dy).sqrt() / 2.0 > EDGE_THRESHOLD {
+                    edges += 1;
+                }
+                interior += 1;
+            }
+        }
+
+        let pixels = (width * height).max(1) as f64;
+        Self {
+            mean_saturation: saturation / pixels,
+            edge_density: edges as f64 / interior.max(1) as f64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn metrics(pixel: impl Fn(u32, u32) -> [u8; 3]) -> ImageMetrics {
+        let image = RgbImage::from_fn(METRICS_SIZE, METRICS_SIZE, |x, y| Rgb(pixel(x, y)));
+        ImageMetrics::from_image(&DynamicImage::ImageRgb8(image))
+    }
+
+    #[test]
+    fn flat_grey_image_has_no_saturation_or_edges() {
+        let metrics = metrics(|_, _| [128, 128, 128]);
+        assert_eq!(metrics.mean_saturation, 0.0);
+        assert_eq!(metrics.edge_density, 0.0);
+    }
+
+    #[test]
+    fn pure_red_image_is_fully_saturated() {
+        let metrics = metrics(|_, _| [255, 0, 0]);
+        assert!((metrics.mean_saturation - 1.0).abs() < 1e-6);
+        assert_eq!(metrics.edge_density, 0.0);
+    }
+
+    #[test]
+    fn stripes_are_busier_than_a_single_edge() {
+        let stripes = metrics(|x, _| if (x / 4) % 2 == 0 { [0; 3] } else { [255; 3] });
+        let halves = metrics(|x, _| {
+            if x < METRICS_SIZE / 2 {
+                [0; 3]
+            } else {
+                [255; 3]
+            }
+        });
+
+        assert!(stripes.edge_density > 0.4, "{:?}", stripes);
+        assert!(halves.edge_density < 0.02, "{:?}", halves);
+    }
+
+    #[test]
+    fn default_profile_checks_nothing() {
+        let profile = SensoryProfile::default();
+        let busy = ImageMetrics {
+            mean_saturation: 1.0,
+            edge_density: 1.0,
+        };
+
+        assert!(profile.violations(&busy).is_empty());
+        assert!(profile.prompt_guidance().is_empty());
+        assert_eq!(profile.negative_prompt(), BASE_NEGATIVE_PROMPT);
+        let parsed: SensoryProfile = serde_json::from_str("{}").unwrap();
+        assert_eq!(parsed, profile);
+    }
+
+    #[test]
+    fn muted_profile_rejects_saturated_images() {
+        let profile = SensoryProfile {
+            palette: Palette::Muted,
+            max_complexity: Some(0.2),
+            ..Default::default()
+        };
+        let red = metrics(|_, _| [255, 0, 0]);
+
+        assert_eq!(profile.violations(&red), vec![Check::Saturation]);
+    }
+}
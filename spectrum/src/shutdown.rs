@@ -0,0 +1,215 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::{sync::atomic::Ordering, time::Duration};
+
+use crate::AppState;
+
+// How long in-flight requests get to finish once a shutdown starts
+pub fn grace_period() -> Duration {
+    Duration::from_secs(crate::quota::env_or("SHUTDOWN_GRACE_SECS", 30))
+}
+
+// Resolves on Ctrl+C or SIGTERM
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// Stops new work and tells connected WebSocket clients the server is going away
+pub async fn begin(state: &AppState) {
+    state.draining.store(true, Ordering::SeqCst);
+    tracing::info!(
+        grace_secs = grace_period().as_secs(),
+        "Shutting down, draining in-flight requests"
+    );
+
+    let message = json!({
+        "type": "server_shutdown",
+        "message": "The server is restarting. Your progress has been saved.",
+    })
+    .to_string();
+    for sender in state.clients.read().await.values() {
+        let _ = sender.try_send(message.clone());
+    }
+}
+
+// How often the sessions, groups and usage are written out while the server runs,
+// bounding what a crash or SIGKILL can lose
+pub fn save_interval() -> Duration {
+    Duration::from_secs(crate::quota::env_or("STORE_SAVE_INTERVAL_SECS", 30).max(1))
+}
+
+// Saves the sessions, groups and usage every `save_interval` until the shutdown starts; the
+// final save is left to `flush_sessions`
+pub async fn save_periodically(state: AppState) {
+    let mut interval = tokio::time::interval(save_interval());
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately, right after the restore
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if state.draining.load(Ordering::SeqCst) {
+            break;
+        }
+        match save_sessions(&state).await {
+            Ok(sessions) => tracing::debug!(sessions, "Saved sessions"),
+            Err(err) => tracing::error!(%err, "Failed to save sessions"),
+        }
+        save_groups(&state).await;
+        save_usage(&state).await;
+    }
+}
+
+// Writes every session to the session store, and the groups and usage.
+// In-flight requests that outlived the grace period may still hold the
+// session lock, so waiting is bounded.
+pub async fn flush_sessions(state: &AppState) {
+    match save_sessions(state).await {
+        Ok(sessions) => tracing::info!(sessions, "Saved sessions"),
+        Err(err) => tracing::error!(%err, "Failed to save sessions"),
+    }
+    save_groups(state).await;
+    save_usage(state).await;
+}
+
+async fn save_groups(state: &AppState) {
+    let groups = state.groups.read().await.clone();
+    if let Err(err) = state.group_store.save(&groups).await {
+        tracing::error!(%err, "Failed to save group sessions");
+    }
+}
+
+async fn save_usage(state: &AppState) {
+    if let Err(err) = state.ledger.save().await {
+        tracing::error!(%err, "Failed to save clinic usage");
+    }
+}
+
+async fn save_sessions(state: &AppState) -> Result<usize, String> {
+    // Snapshot under the lock, write without it so requests aren't held up
+    let sessions = match tokio::time::timeout(Duration::from_secs(5), state.sessions.read()).await {
+        Ok(sessions) => sessions.clone(),
+        Err(_) => return Err("sessions still locked by an in-flight request".to_string()),
+    };
+    state
+        .session_store
+        .save(&sessions)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(sessions.len())
+}
+
+// Rejects requests that arrive on open connections after the shutdown started
+pub async fn reject_while_draining(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.draining.load(Ordering::SeqCst) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [("retry-after", "30")],
+            "Server is restarting, please retry shortly.",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Session, group::GroupSession, provider::Provider};
+    use uuid::Uuid;
+
+    fn state(dir: &std::path::Path) -> AppState {
+        AppState::new(
+            "test".to_string(),
+            "test".to_string(),
+            Provider::Mock,
+            dir.to_string_lossy().into_owned(),
+        )
+        .unwrap()
+        .with_store_dir(dir)
+    }
+
+    #[tokio::test]
+    async fn warns_clients_and_turns_requests_away_once_draining() {
+        let dir = std::env::temp_dir().join(format!("spectrum-drain-{}", std::process::id()));
+        let state = state(&dir);
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        state
+            .clients
+            .write()
+            .await
+            .insert("child".to_string(), sender);
+
+        begin(&state).await;
+
+        assert!(state.draining.load(Ordering::SeqCst));
+        let message: serde_json::Value =
+            serde_json::from_str(&receiver.try_recv().unwrap()).unwrap();
+        assert_eq!(message["type"], "server_shutdown");
+
+        let response = tower::ServiceExt::oneshot(
+            crate::app(state),
+            axum::http::Request::get("/metrics")
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn flushed_sessions_and_groups_are_restored() {
+        let dir = std::env::temp_dir().join(format!("spectrum-flush-{}", std::process::id()));
+        let state = state(&dir);
+        let session_id = Uuid::new_v4();
+        let session = Session {
+            topic_focus: "animals".to_string(),
+            ..Default::default()
+        };
+        state.sessions.write().await.insert(session_id, session);
+        let group_id = Uuid::new_v4();
+        let group = GroupSession::new(Session::default(), vec!["Ann".to_string()]);
+        state.groups.write().await.insert(group_id, group);
+        state.ledger.record_tokens("clinic-a", 100, 10);
+
+        flush_sessions(&state).await;
+
+        let restarted = self::state(&dir);
+        restarted.restore().await;
+        let sessions = restarted.sessions.read().await;
+        assert_eq!(sessions[&session_id].topic_focus, "animals");
+        assert_eq!(
+            restarted.groups.read().await[&group_id].participants[0].name,
+            "Ann"
+        );
+        assert_eq!(restarted.ledger.usage("clinic-a").model_calls, 1);
+    }
+}
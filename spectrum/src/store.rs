@@ -0,0 +1,132 @@
+use serde::{Serialize, de::DeserializeOwned};
+use std::{collections::HashMap, io, marker::PhantomData, path::PathBuf};
+use tokio::{fs, sync::Mutex};
+use uuid::Uuid;
+
+use crate::{
+    Session,
+    privacy::{self, StoreCipher},
+};
+
+// JSON snapshot of one in-memory store, read back on startup so a deploy or a
+// crash doesn't lose children's progress. Encrypted when STORE_ENCRYPTION_KEY
+// is set.
+#[derive(Debug)]
+pub struct Store<T> {
+    path: PathBuf,
+    cipher: Option<StoreCipher>,
+    // Periodic and shutdown saves share the temporary file
+    write_lock: Mutex<()>,
+    _data: PhantomData<fn() -> T>,
+}
+
+// Every session, saved periodically and on shutdown
+pub type SessionStore = Store<HashMap<Uuid, Session>>;
+
+impl<T: Serialize + DeserializeOwned + Default> Store<T> {
+    pub fn new(path: impl Into<PathBuf>, cipher: Option<StoreCipher>) -> Self {
+        Self {
+            path: path.into(),
+            cipher,
+            write_lock: Mutex::new(()),
+            _data: PhantomData,
+        }
+    }
+
+    // Path from the `var` environment variable, `default_path` otherwise
+    pub fn from_env(var: &str, default_path: &str) -> Self {
+        Self::new(
+            std::env::var(var).unwrap_or_else(|_| default_path.to_string()),
+            StoreCipher::from_env(),
+        )
+    }
+
+    pub async fn load(&self) -> io::Result<T> {
+        match fs::read(&self.path).await {
+            Ok(contents) => serde_json::from_slice(&privacy::open(self.cipher.as_ref(), contents)?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Writes to a temporary file first so a crash mid-write keeps the old snapshot
+    pub async fn save(&self, value: &T) -> io::Result<()> {
+        let contents = privacy::seal(self.cipher.as_ref(), serde_json::to_vec(value)?)?;
+        let _guard = self.write_lock.lock().await;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).await?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, contents).await?;
+        fs::rename(&tmp, &self.path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "spectrum-store-{}-{}/{}.json",
+            std::process::id(),
+            name,
+            name
+        ))
+    }
+
+    fn plans() -> HashMap<String, u32> {
+        [("feelings".to_string(), 3), ("animals".to_string(), 5)].into()
+    }
+
+    #[tokio::test]
+    async fn loads_what_it_saved() {
+        let store = Store::new(path("plain"), None);
+        store.save(&plans()).await.unwrap();
+
+        assert_eq!(store.load().await.unwrap(), plans());
+        // Nothing is left behind from the write
+        assert!(!path("plain").with_extension("json.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn encrypts_what_it_saves() {
+        let store = Store::new(path("sealed"), Some(StoreCipher::new(&[3; 32])));
+        store.save(&plans()).await.unwrap();
+
+        let contents = std::fs::read(path("sealed")).unwrap();
+        assert!(!String::from_utf8_lossy(&contents).contains("feelings"));
+        assert_eq!(store.load().await.unwrap(), plans());
+
+        // Another key, or none, can't read it
+        let other: Store<HashMap<String, u32>> =
+            Store::new(path("sealed"), Some(StoreCipher::new(&[4; 32])));
+        let err = other.load().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let plain: Store<HashMap<String, u32>> = Store::new(path("sealed"), None);
+        assert_eq!(
+            plain.load().await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test]
+    async fn starts_empty_without_a_snapshot() {
+        let store: Store<HashMap<String, u32>> = Store::new(path("missing"), None);
+        assert!(store.load().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_corrupt_snapshot() {
+        let path = path("corrupt");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"{\"feelings\": 3").unwrap();
+
+        let store: Store<HashMap<String, u32>> = Store::new(path, None);
+        assert_eq!(
+            store.load().await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use chrono::{DateTime, Utc};
+use printpdf::{BuiltinFont, Mm, PdfDocument};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{RoundRecord, Session};
+
+// Plain-language end-of-session report for parents and caregivers
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct CaregiverSummary {
+    pub summary: String,
+    pub home_activities: Vec<String>,
+    pub generated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SummaryResponse {
+    summary: String,
+    home_activities: Vec<String>,
+}
+
+// Query asking Gemini for the caregiver summary
+pub fn summary_query(session: &Session) -> String {
+    let current = session.current_round();
+    let mut rounds_text = String::new();
+    for (idx, round) in session.history.iter().chain([&current]).enumerate() {
+        rounds_text.push_str(&format!(
+            "\nImage {} about \"{}\" ({} difficulty): found {} of {} details.\n",
+            idx + 1,
+            round.topic_focus,
+            round.difficulty,
+            round.identified_details.len(),
+            round.key_details.len()
+        ));
+        if !round.identified_details.is_empty() {
+            rounds_text.push_str(&format!(
+                "  Found: {}\n",
+                round.identified_details.join(", ")
+            ));
+        }
+        if !round.used_hints.is_empty() {
+            rounds_text.push_str(&format!(
+                "  Hints needed: {}\n",
+                round.used_hints.join(" | ")
+            ));
+        }
+        for (speaker, msg) in round.chat.iter().filter(|(speaker, _)| speaker == "Child") {
+            rounds_text.push_str(&format!("  {}: {}\n", speaker, msg));
+        }
+    }
+
+    let changes_text = if session.difficulty_changes.is_empty() {
+        "The difficulty level did not change.".to_string()
+    } else {
+        session
+            .difficulty_changes
+            .iter()
+            .map(|change| format!("Moved from {} to {}.", change.from, change.to))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    format!(
+        r#"
+        You are writing a short note for the parents of a child with autism after a picture-description therapy session.
+        Child's age: {}
+        Topic focus: {}
+        Treatment plan: {}
+        Session activity: {}
+
+        What happened in the session:
+        {}
+        Difficulty: {}
+
+        Write a warm, plain-language summary of 3-5 sentences. Avoid clinical jargon and scores, describe what the child
+        did well and what they are still practising. Then suggest 2-4 simple activities the family can do at home this week
+        that practise the topic "{}" using everyday objects.
+
+        Return your response as a JSON object with the following format:
+        {{
+          "summary": "The note for the parents",
+          "home_activities": ["activity one", "activity two"]
+        }}
+        "#,
+        session.age,
+        session.topic_focus,
+        session.treatment_plan,
+        session.activity.task_description(),
+        rounds_text,
+        changes_text,
+        session.topic_focus
+    )
+}
+
+pub fn parse_summary(text: &str) -> Option<CaregiverSummary> {
+    let re = regex::Regex::new(r"(?s)\{.*\}").unwrap();
+    let json_match = re.find(text)?;
+    let response = serde_json::from_str::<SummaryResponse>(json_match.as_str()).ok()?;
+    Some(CaregiverSummary {
+        summary: response.summary,
+        home_activities: response.home_activities,
+        generated_at: Utc::now(),
+    })
+}
+
+// Summary built from the session alone, used when the model gives nothing usable
+pub fn fallback_summary(session: &Session) -> CaregiverSummary {
+    let current = session.current_round();
+    let rounds: Vec<&RoundRecord> = session.history.iter().chain([&current]).collect();
+    let found: usize = rounds
+        .iter()
+        .map(|round| round.identified_details.len())
+        .sum();
+    let total: usize = rounds.iter().map(|round| round.key_details.len()).sum();
+
+    let mut summary = format!(
+        "Today your child looked at {} picture{} about \"{}\" and found {} of {} details.",
+        rounds.len(),
+        if rounds.len() == 1 { "" } else { "s" },
+        session.topic_focus,
+        found,
+        total
+    );
+    if let Some(change) = session.difficulty_changes.last() {
+        summary.push_str(&format!(
+            " They were ready to move up to the {} level.",
+            change.to
+        ));
+    }
+
+    CaregiverSummary {
+        summary,
+        home_activities: vec![
+            format!(
+                "Look at a picture book together and talk about anything to do with \"{}\".",
+                session.topic_focus
+            ),
+            "Take turns pointing at something in a room and describing its colour and shape."
+                .to_string(),
+        ],
+        generated_at: Utc::now(),
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+pub fn render_html(session: &Session, summary: &CaregiverSummary) -> String {
+    let activities = summary
+        .home_activities
+        .iter()
+        .map(|activity| format!("      <li>{}</li>", escape_html(activity)))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>Session summary</title>
+  <style>
+    body {{ font-family: sans-serif; max-width: 40em; margin: 2em auto; line-height: 1.5; color: #333; }}
+    h1 {{ font-size: 1.4em; }}
+    .meta {{ color: #777; font-size: 0.9em; }}
+  </style>
+</head>
+<body>
+  <h1>Session summary: {}</h1>
+  <p class="meta">{}</p>
+  <p>{}</p>
+  <h2>Ideas to try at home</h2>
+  <ul>
+{}
+  </ul>
+</body>
+</html>
+"#,
+        escape_html(&session.topic_focus),
+        summary.generated_at.format("%B %-d, %Y"),
+        escape_html(&summary.summary),
+        activities
+    )
+}
+
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 20.0;
+const LINE_HEIGHT: f32 = 6.0;
+const CHARS_PER_LINE: usize = 85;
+
+pub fn render_pdf(session: &Session, summary: &CaregiverSummary) -> Vec<u8> {
+    let (doc, page, layer) = PdfDocument::new(
+        "Session summary",
+        Mm(PAGE_WIDTH),
+        Mm(PAGE_HEIGHT),
+        "Layer 1",
+    );
+    let regular = doc.add_builtin_font(BuiltinFont::Helvetica).unwrap();
+    let bold = doc.add_builtin_font(BuiltinFont::HelveticaBold).unwrap();
+
+    let mut lines: Vec<(String, f32, bool)> = vec![
+        (
+            format!("Session summary: {}", session.topic_focus),
+            16.0,
+            true,
+        ),
+        (
+            summary.generated_at.format("%B %-d, %Y").to_string(),
+            10.0,
+            false,
+        ),
+        (String::new(), 11.0, false),
+    ];
+    lines.extend(
+        wrap(&summary.summary, CHARS_PER_LINE)
+            .into_iter()
+            .map(|line| (line, 11.0, false)),
+    );
+    lines.push((String::new(), 11.0, false));
+    lines.push(("Ideas to try at home".to_string(), 13.0, true));
+    for activity in &summary.home_activities {
+        for (idx, line) in wrap(activity, CHARS_PER_LINE - 4).into_iter().enumerate() {
+            let bullet = if idx == 0 { "-  " } else { "   " };
+            lines.push((format!("{}{}", bullet, line), 11.0, false));
+        }
+    }
+
+    let mut current = doc.get_page(page).get_layer(layer);
+    let mut y = PAGE_HEIGHT - MARGIN;
+    for (text, size, is_bold) in lines {
+        if y < MARGIN {
+            let (page, layer) = doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+            current = doc.get_page(page).get_layer(layer);
+            y = PAGE_HEIGHT - MARGIN;
+        }
+        let font = if is_bold { &bold } else { &regular };
+        current.use_text(text, size, Mm(MARGIN), Mm(y), font);
+        y -= LINE_HEIGHT * size / 11.0;
+    }
+
+    doc.save_to_bytes().unwrap()
+}
+
+// Greedy word wrap for the PDF, which has no text layout of its own
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DifficultyChange;
+
+    // Two pictures: the first fully found, the second half found
+    fn session() -> Session {
+        let mut session = Session {
+            topic_focus: "farm <animals>".to_string(),
+            key_details: vec!["cow".to_string(), "barn".to_string()],
+            identified_details: vec!["cow".to_string(), "barn".to_string()],
+            ..Default::default()
+        };
+        session.history.push(session.current_round());
+        session.identified_details = vec!["cow".to_string()];
+        session.difficulty_changes.push(DifficultyChange {
+            from: "Very Simple".to_string(),
+            to: "Simple".to_string(),
+            at: Utc::now(),
+            round: 0,
+        });
+        session
+    }
+
+    #[test]
+    fn parses_the_summary_out_of_surrounding_text() {
+        let text = "Here you go:\n```json\n{\"summary\": \"Great day.\",\n \"home_activities\": [\"Read a book\"]}\n```";
+        let summary = parse_summary(text).unwrap();
+        assert_eq!(summary.summary, "Great day.");
+        assert_eq!(summary.home_activities, vec!["Read a book"]);
+    }
+
+    #[test]
+    fn malformed_output_falls_back_to_the_session() {
+        for text in [
+            "Sorry, I can't help with that.",
+            "{\"summary\": \"Missing activities\"}",
+            "{\"summary\": \"Cut off\", \"home_activities\": [",
+        ] {
+            assert!(parse_summary(text).is_none(), "{}", text);
+        }
+
+        let session = session();
+        let summary = parse_summary("no JSON").unwrap_or_else(|| fallback_summary(&session));
+        assert_eq!(
+            summary.summary,
+            "Today your child looked at 2 pictures about \"farm <animals>\" and found 3 of 4 details. They were ready to move up to the Simple level."
+        );
+        assert_eq!(summary.home_activities.len(), 2);
+    }
+
+    #[test]
+    fn escapes_child_text_in_html() {
+        assert_eq!(
+            escape_html(r#"<b>"Tom" & 'Jo'</b>"#),
+            "&lt;b&gt;&quot;Tom&quot; &amp; &#39;Jo&#39;&lt;/b&gt;"
+        );
+
+        let summary = CaregiverSummary {
+            summary: "They said <script>alert(1)</script>".to_string(),
+            home_activities: vec!["Draw a <cow>".to_string()],
+            generated_at: Utc::now(),
+        };
+        let html = render_html(&session(), &summary);
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(html.contains("Session summary: farm &lt;animals&gt;"));
+        assert!(html.contains("<li>Draw a &lt;cow&gt;</li>"));
+    }
+
+    #[test]
+    fn renders_a_pdf() {
+        let session = session();
+        let pdf = render_pdf(&session, &fallback_summary(&session));
+        assert!(pdf.starts_with(b"%PDF"));
+    }
+
+    #[test]
+    fn wraps_at_word_boundaries() {
+        assert_eq!(
+            wrap("one two three four", 9),
+            vec!["one two", "three", "four"]
+        );
+        // A word longer than the line gets a line of its own
+        assert_eq!(wrap("a verylongword b", 4), vec!["a", "verylongword", "b"]);
+        assert!(wrap("  ", 10).is_empty());
+    }
+}
@@ -0,0 +1,419 @@
+use axum::{
+    Json,
+    extract::{
+        Path, Query, Request, State, WebSocketUpgrade,
+        ws::{Message, WebSocket},
+    },
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    AppState, CallContext, DIFFICULTIES, Detail, DifficultyChange, audit::AuditEntry, finish_round,
+    generate_round, next_group_event, similar_details,
+};
+
+pub const TOKEN_HEADER: &str = "x-therapist-token";
+pub const THERAPIST_HEADER: &str = "x-therapist-id";
+
+// Live intervention by a therapist watching a session. The same JSON is
+// accepted by the HTTP endpoint and as a WebSocket command.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Override {
+    // Marks a checklist item as found by the child
+    MarkDetail { detail_id: usize },
+    UnmarkDetail { detail_id: usize },
+    // Skips to a new image at the current difficulty
+    RegenerateImage,
+    // Applies from the next image on
+    SetDifficulty { difficulty: String },
+    // Message shown to the child as coming from the teacher
+    SendMessage { message: String },
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct OverrideResponse {
+    #[schema(value_type = Vec<Vec<String>>)]
+    pub chat: Vec<(String, String)>,
+    pub checklist: Vec<Detail>,
+    pub difficulty: String,
+    // Set when the override replaced the image
+    pub new_image: Option<String>,
+    pub sequence: Option<Vec<String>>,
+}
+
+// Query parameters accepted in place of headers, for browser WebSockets
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TherapistQuery {
+    /// Therapist token for WebSocket upgrades, which can't send the `x-therapist-token` header
+    pub token: Option<String>,
+    /// Therapist id recorded in the audit trail
+    pub therapist_id: Option<String>,
+}
+
+fn therapist_id(headers: &HeaderMap, query: &TherapistQuery) -> String {
+    headers
+        .get(THERAPIST_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| query.therapist_id.clone())
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| "therapist".to_string())
+}
+
+// Compares digests so the time taken doesn't reveal how much of the token matched
+fn tokens_match(given: &str, expected: &str) -> bool {
+    let given = Sha256::digest(given.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    given
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"websocket"))
+}
+
+// Tower middleware for the therapist routes. Therapist controls are disabled
+// unless `THERAPIST_TOKEN` is set. The token is read from the header; the
+// query string is only accepted on WebSocket upgrades, since it ends up in
+// access logs and browser history.
+pub async fn require_therapist(
+    State(state): State<AppState>,
+    Query(query): Query<TherapistQuery>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(expected) = &state.therapist_token else {
+        return (
+            StatusCode::FORBIDDEN,
+            "Therapist controls are disabled on this server.",
+        )
+            .into_response();
+    };
+    let token = request
+        .headers()
+        .get(TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or(query
+            .token
+            .filter(|_| is_websocket_upgrade(request.headers())));
+
+    if !token.is_some_and(|token| tokens_match(&token, expected)) {
+        return (StatusCode::UNAUTHORIZED, "Invalid therapist token.").into_response();
+    }
+    next.run(request).await
+}
+
+// Applies an override to a live session, records it in the audit trail and
+// pushes the result to the child's session WebSocket
+pub async fn apply_override(
+    state: &AppState,
+    session_id: Uuid,
+    therapist: &str,
+    command: Override,
+) -> Result<OverrideResponse, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&session_id)
+        .ok_or((StatusCode::NOT_FOUND, "Unknown session".to_string()))?;
+    let ctx = CallContext {
+        session_id,
+        clinic_id: session.clinic_id.clone(),
+    };
+
+    let unknown_detail = |detail_id: usize| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("No checklist item {}", detail_id),
+        )
+    };
+    let image_failed = |status| (status, "Couldn't generate a new image".to_string());
+    let mut new_image = None;
+    let outcome = match &command {
+        Override::MarkDetail { detail_id } => {
+            let detail = session
+                .key_details
+                .get(*detail_id)
+                .cloned()
+                .ok_or_else(|| unknown_detail(*detail_id))?;
+            if !session.identified_details.contains(&detail) {
+                session.identified_details.push(detail.clone());
+            }
+
+            // Marking the last item finishes the image, as if the child had
+            // found it
+            if session.checklist().iter().all(|item| item.identified) {
+                let child_id = session.child_key(session_id);
+                let difficulty = session.difficulty.clone();
+                let (image, _) =
+                    finish_round(session, &child_id, difficulty, false, 0, &ctx, state)
+                        .await
+                        .map_err(image_failed)?;
+                new_image = Some(image);
+                format!("Marked \"{}\" as found, which finished the image", detail)
+            } else {
+                format!("Marked \"{}\" as found", detail)
+            }
+        }
+        Override::UnmarkDetail { detail_id } => {
+            let detail = session
+                .key_details
+                .get(*detail_id)
+                .cloned()
+                .ok_or_else(|| unknown_detail(*detail_id))?;
+            let marked: Vec<bool> = session
+                .checklist()
+                .iter()
+                .map(|item| item.identified)
+                .collect();
+            session
+                .identified_details
+                .retain(|identified| !similar_details(identified, &detail));
+            // A removed entry may also have covered other items; keep those marked
+            for (idx, item) in session.checklist().into_iter().enumerate() {
+                if marked[idx] && idx != *detail_id && !item.identified {
+                    session.identified_details.push(item.detail);
+                }
+            }
+            format!("Unmarked \"{}\"", detail)
+        }
+        Override::RegenerateImage => {
+            let difficulty = session.difficulty.clone();
+            let round = generate_round(session.round_spec(&difficulty), &ctx, state)
+                .await
+                .map_err(image_failed)?;
+            let finished = session.current_round();
+            session.history.push(finished);
+            new_image = Some(session.start_round(round));
+            session.chat.push((
+                "System".to_string(),
+                format!(
+                    "Let's try a different picture. {}",
+                    session.activity.child_instructions()
+                ),
+            ));
+            "Replaced the image".to_string()
+        }
+        Override::SetDifficulty { difficulty } => {
+            if !DIFFICULTIES.contains(&difficulty.as_str()) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "Unknown difficulty \"{}\", expected one of: {}",
+                        difficulty,
+                        DIFFICULTIES.join(", ")
+                    ),
+                ));
+            }
+            if *difficulty != session.difficulty {
+                session.difficulty_changes.push(DifficultyChange {
+                    from: session.difficulty.clone(),
+                    to: difficulty.clone(),
+                    at: Utc::now(),
+                    round: session.history.len(),
+                });
+                session.difficulty = difficulty.clone();
+            }
+            format!("Set difficulty to {}", difficulty)
+        }
+        Override::SendMessage { message } => {
+            let message = state.redactor.redact(message);
+            session.chat.push(("Teacher".to_string(), message));
+            "Sent teacher message".to_string()
+        }
+    };
+
+    state
+        .audit
+        .record(
+            session_id,
+            AuditEntry::therapist_override(
+                therapist,
+                &serde_json::to_string(&command).unwrap(),
+                &outcome,
+            ),
+        )
+        .await;
+
+    let response = OverrideResponse {
+        chat: session.chat.clone(),
+        checklist: session.checklist(),
+        difficulty: session.difficulty.clone(),
+        sequence: new_image.as_ref().map(|_| session.sequence.clone()),
+        new_image,
+    };
+    drop(sessions);
+
+    let _ = state.session_events(session_id).await.send(
+        json!({
+            "type": "therapist_override",
+            "override": command,
+            "state": response,
+        })
+        .to_string(),
+    );
+    Ok(response)
+}
+
+// Therapist override API endpoints
+#[utoipa::path(
+    post,
+    path = "/sessions/{session_id}/overrides",
+    request_body = Override,
+    params(
+        ("session_id" = Uuid, Path, description = "Live session"),
+        ("x-therapist-token" = String, Header, description = "Therapist token"),
+        ("x-therapist-id" = Option<String>, Header, nullable = false, description = "Therapist recorded in the audit trail")
+    ),
+    responses(
+        (status = 200, description = "Session state after the override", body = OverrideResponse),
+        (status = 400, description = "Invalid override"),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "Unknown session"),
+        (status = 502, description = "Image generation failed")
+    )
+)]
+pub async fn override_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+    headers: HeaderMap,
+    Json(command): Json<Override>,
+) -> Result<Json<OverrideResponse>, (StatusCode, String)> {
+    let therapist = therapist_id(&headers, &TherapistQuery::default());
+    apply_override(&state, session_id, &therapist, command)
+        .await
+        .map(Json)
+}
+
+// Therapist console: receives the session's live events and accepts
+// `Override` commands as JSON text messages
+pub async fn therapist_ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+    Query(query): Query<TherapistQuery>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !state.sessions.read().await.contains_key(&session_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let therapist = therapist_id(&headers, &query);
+    let events = state.session_events(session_id).await.subscribe();
+    Ok(ws.on_upgrade(move |socket| therapist_socket(socket, state, session_id, therapist, events)))
+}
+
+async fn therapist_socket(
+    mut socket: WebSocket,
+    state: AppState,
+    session_id: Uuid,
+    therapist: String,
+    events: broadcast::Receiver<String>,
+) {
+    let mut events = Some(events);
+    loop {
+        tokio::select! {
+            Some(event) = next_group_event(&mut events) => {
+                if socket.send(Message::Text(event)).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    // Successful overrides come back through the session events
+                    let error = match serde_json::from_str::<Override>(&text) {
+                        Ok(command) => apply_override(&state, session_id, &therapist, command)
+                            .await
+                            .err()
+                            .map(|(_, message)| message),
+                        Err(err) => Some(format!("Invalid override: {}", err)),
+                    };
+                    if let Some(message) = error {
+                        let reply = json!({"type": "error", "message": message}).to_string();
+                        if socket.send(Message::Text(reply)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Session, provider::Provider};
+
+    async fn state_with(session_id: Uuid, session: Session) -> AppState {
+        let dir = std::env::temp_dir().join(format!("spectrum-therapist-{}", session_id));
+        let state = AppState::new(
+            String::new(),
+            String::new(),
+            Provider::Mock,
+            dir.to_string_lossy().into_owned(),
+        )
+        .unwrap()
+        .with_store_dir(&dir);
+        state.sessions.write().await.insert(session_id, session);
+        state
+    }
+
+    #[test]
+    fn tokens_match_only_when_equal() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secre", "secret"));
+        assert!(!tokens_match("secret ", "secret"));
+        assert!(!tokens_match("", "secret"));
+    }
+
+    #[tokio::test]
+    async fn unmarking_a_detail_keeps_other_details_marked() {
+        let session_id = Uuid::new_v4();
+        let session = Session {
+            key_details: vec![
+                "kitten".to_string(),
+                "puppy".to_string(),
+                "red ball".to_string(),
+            ],
+            // Found by the child in one message; covers both animals
+            identified_details: vec!["kitten and puppy".to_string()],
+            ..Default::default()
+        };
+        let state = state_with(session_id, session).await;
+
+        let response = apply_override(
+            &state,
+            session_id,
+            "therapist",
+            Override::UnmarkDetail { detail_id: 0 },
+        )
+        .await
+        .unwrap();
+
+        let marked: Vec<bool> = response
+            .checklist
+            .iter()
+            .map(|item| item.identified)
+            .collect();
+        assert_eq!(marked, vec![false, true, false]);
+    }
+}
@@ -0,0 +1,346 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::Deserialize;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::{
+    AppState, DifficultyChange, RoundRecord, Session, similar_details, summary::escape_html,
+};
+
+// Only inline images are embedded; anything else would make the file depend
+// on the server still being around
+fn render_image(image: Option<&String>, alt: &str) -> String {
+    match image {
+        Some(image) if image.starts_with("data:image/") => format!(
+            r#"<img src="{}" alt="{}">"#,
+            escape_html(image),
+            escape_html(alt)
+        ),
+        _ => r#"<p class="missing">Image not available</p>"#.to_string(),
+    }
+}
+
+fn render_time(at: Option<DateTime<Utc>>, tz: FixedOffset) -> String {
+    at.map(|at| at.with_timezone(&tz).format("%H:%M").to_string())
+        .unwrap_or_else(|| "?".to_string())
+}
+
+// "UTC" or e.g. "UTC+02:00", printed once so readers know what the times mean
+fn tz_label(tz: FixedOffset) -> String {
+    if tz.local_minus_utc() == 0 {
+        "UTC".to_string()
+    } else {
+        format!("UTC{}", tz)
+    }
+}
+
+fn render_round(idx: usize, round: &RoundRecord, in_progress: bool, tz: FixedOffset) -> String {
+    let mut html = format!(
+        "  <section class=\"round\">\n    <h2>Image {}: {}</h2>\n    <p class=\"meta\">{} difficulty, {}, {}&ndash;{}</p>\n",
+        idx + 1,
+        escape_html(&round.topic_focus),
+        escape_html(&round.difficulty),
+        escape_html(&round.activity.task_description()),
+        render_time(round.started_at, tz),
+        if in_progress {
+            "in progress".to_string()
+        } else {
+            render_time(Some(round.ended_at), tz)
+        }
+    );
+
+    // Sequencing rounds show every picture in the order the child saw them
+    html.push_str("    <div class=\"images\">\n");
+    if round.sequence.is_empty() {
+        html.push_str(&format!(
+            "      {}\n",
+            render_image(round.image.as_ref(), &round.topic_focus)
+        ));
+    } else {
+        for (step, image) in round.sequence.iter().enumerate() {
+            html.push_str(&format!(
+                "      <figure>{}<figcaption>Picture {}</figcaption></figure>\n",
+                render_image(Some(image), &format!("Picture {}", step + 1)),
+                step + 1
+            ));
+        }
+    }
+    html.push_str("    </div>\n");
+
+    let found = round
+        .key_details
+        .iter()
+        .filter(|detail| {
+            round
+                .identified_details
+                .iter()
+                .any(|identified| similar_details(identified, detail))
+        })
+        .count();
+    html.push_str(&format!(
+        "    <h3>{} ({} of {})</h3>\n    <ul class=\"checklist\">\n",
+        escape_html(round.activity.checklist_heading()),
+        found,
+        round.key_details.len()
+    ));
+    for detail in &round.key_details {
+        let identified = round
+            .identified_details
+            .iter()
+            .any(|identified| similar_details(identified, detail));
+        html.push_str(&format!(
+            "      <li class=\"{}\">{} {}</li>\n",
+            if identified { "found" } else { "open" },
+            if identified { "&#10003;" } else { "&#9675;" },
+            escape_html(detail)
+        ));
+    }
+    html.push_str("    </ul>\n");
+    if !round.used_hints.is_empty() {
+        html.push_str(&format!(
+            "    <p class=\"meta\">Hints given: {}</p>\n",
+            round.used_hints.len()
+        ));
+    }
+
+    if !round.chat.is_empty() {
+        html.push_str("    <h3>Conversation</h3>\n    <ol class=\"chat\">\n");
+        for (speaker, message) in &round.chat {
+            html.push_str(&format!(
+                "      <li class=\"{}\"><strong>{}:</strong> {}</li>\n",
+                escape_html(&speaker.to_lowercase()),
+                escape_html(speaker),
+                escape_html(message)
+            ));
+        }
+        html.push_str("    </ol>\n");
+    }
+    html.push_str("  </section>\n");
+    html
+}
+
+fn render_change(change: &DifficultyChange, tz: FixedOffset) -> String {
+    format!(
+        "  <p class=\"change\">Difficulty changed from {} to {} at {}</p>\n",
+        escape_html(&change.from),
+        escape_html(&change.to),
+        render_time(Some(change.at), tz)
+    )
+}
+
+// Whole session as one HTML file with the images inlined, so it can be
+// emailed or opened offline. Times are shown in `tz`.
+pub fn render_timeline(session: &Session, tz: FixedOffset) -> String {
+    let current = session.current_round();
+    let mut rounds = String::new();
+    for (idx, round) in session.history.iter().enumerate() {
+        rounds.push_str(&render_round(idx, round, false, tz));
+        for change in session
+            .difficulty_changes
+            .iter()
+            .filter(|change| change.round == idx)
+        {
+            rounds.push_str(&render_change(change, tz));
+        }
+    }
+    rounds.push_str(&render_round(
+        session.history.len(),
+        &current,
+        session.ended_at.is_none(),
+        tz,
+    ));
+
+    let summary = session
+        .summary
+        .as_ref()
+        .map(|summary| {
+            format!(
+                "  <section class=\"summary\">\n    <h2>Summary</h2>\n    <p>{}</p>\n  </section>\n",
+                escape_html(&summary.summary)
+            )
+        })
+        .unwrap_or_default();
+    let date = session
+        .started_at
+        .map(|at| at.with_timezone(&tz).format("%B %-d, %Y").to_string())
+        .unwrap_or_default();
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>Session timeline</title>
+  <style>
+    body {{ font-family: sans-serif; max-width: 48em; margin: 2em auto; line-height: 1.5; color: #333; }}
+    h1 {{ font-size: 1.4em; }}
+    h2 {{ font-size: 1.2em; margin-bottom: 0; }}
+    h3 {{ font-size: 1em; }}
+    .meta {{ color: #777; font-size: 0.9em; }}
+    .round {{ border-top: 1px solid #ddd; padding-top: 1em; margin-top: 1em; }}
+    .images {{ display: flex; flex-wrap: wrap; gap: 0.5em; }}
+    .images img {{ max-width: 100%; width: 24em; border-radius: 6px; }}
+    .images figure img {{ width: 11em; }}
+    .images figure {{ margin: 0; text-align: center; font-size: 0.9em; }}
+    .missing {{ color: #999; font-style: italic; }}
+    .checklist {{ list-style: none; padding-left: 0; }}
+    .checklist .found {{ color: #2e7d32; }}
+    .checklist .open {{ color: #999; }}
+    .chat {{ list-style: none; padding-left: 0; }}
+    .chat li {{ margin: 0.3em 0; padding: 0.3em 0.6em; border-radius: 6px; }}
+    .chat .child {{ background: #e3f2fd; }}
+    .chat .teacher {{ background: #f1f8e9; }}
+    .chat .system {{ background: #fff8e1; font-style: italic; }}
+    .change {{ text-align: center; font-weight: bold; color: #6a1b9a; }}
+  </style>
+</head>
+<body>
+  <h1>Session timeline: {}</h1>
+  <p class="meta">{} &middot; {} image{} &middot; times in {}</p>
+{}{}</body>
+</html>
+"#,
+        escape_html(&session.topic_focus),
+        date,
+        session.history.len() + 1,
+        if session.history.is_empty() { "" } else { "s" },
+        tz_label(tz),
+        summary,
+        rounds
+    )
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TimelineQuery {
+    /// Minutes the reader's time zone is ahead of UTC, e.g. 120 for UTC+2 or
+    /// -300 for UTC-5; times are in UTC without it
+    tz_offset_minutes: Option<i32>,
+}
+
+// Timeline API endpoint
+#[utoipa::path(
+    get,
+    path = "/sessions/{session_id}/timeline",
+    params(
+        ("session_id" = Uuid, Path, description = "Session"),
+        TimelineQuery,
+        ("x-therapist-token" = String, Header, description = "Therapist token")
+    ),
+    responses(
+        (status = 200, description = "Self-contained HTML timeline of the session", content_type = "text/html", body = String),
+        (status = 400, description = "Time zone offset out of range"),
+        (status = 401, description = "Missing or wrong therapist token"),
+        (status = 403, description = "Therapist controls are disabled"),
+        (status = 404, description = "Unknown session")
+    )
+)]
+pub async fn session_timeline_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+    Query(query): Query<TimelineQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let tz = query
+        .tz_offset_minutes
+        .unwrap_or(0)
+        .checked_mul(60)
+        .and_then(FixedOffset::east_opt)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let sessions = state.sessions.read().await;
+    let session = sessions.get(&session_id).ok_or(StatusCode::NOT_FOUND)?;
+    let html = render_timeline(session, tz);
+    tracing::info!(%session_id, bytes = html.len(), "Exported session timeline");
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"session-timeline.html\"",
+            ),
+        ],
+        html,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn session() -> Session {
+        let started = Utc.with_ymd_and_hms(2024, 3, 4, 9, 30, 0).unwrap();
+        let first = Session {
+            topic_focus: "animals".to_string(),
+            difficulty: "Very Simple".to_string(),
+            image: Some("data:image/png;base64,AAAA".to_string()),
+            key_details: vec!["red ball".to_string(), "cat".to_string()],
+            identified_details: vec!["red ball".to_string()],
+            chat: vec![(
+                "Child".to_string(),
+                "<script>alert(1)</script> & a cat".to_string(),
+            )],
+            round_started_at: Some(started),
+            ..Default::default()
+        }
+        .current_round();
+        Session {
+            topic_focus: "animals".to_string(),
+            difficulty: "Simple".to_string(),
+            image: Some("https://example.com/dog.png".to_string()),
+            started_at: Some(started),
+            round_started_at: Some(started + Duration::minutes(5)),
+            history: vec![first],
+            difficulty_changes: vec![DifficultyChange {
+                from: "Very Simple".to_string(),
+                to: "Simple".to_string(),
+                at: started + Duration::minutes(5),
+                round: 0,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn escapes_chat_and_inlines_only_data_images() {
+        let html = render_timeline(&session(), FixedOffset::east_opt(0).unwrap());
+
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt; &amp; a cat"));
+        assert!(html.contains(r#"<img src="data:image/png;base64,AAAA""#));
+        assert!(!html.contains("example.com"));
+        assert!(html.contains("Image not available"));
+        assert!(html.contains("(1 of 2)"));
+    }
+
+    #[test]
+    fn difficulty_changes_follow_the_image_that_caused_them() {
+        let html = render_timeline(&session(), FixedOffset::east_opt(0).unwrap());
+
+        let first = html.find("Image 1: animals").unwrap();
+        let change = html
+            .find("Difficulty changed from Very Simple to Simple at 09:35")
+            .unwrap();
+        let second = html.find("Image 2: animals").unwrap();
+        assert!(first < change && change < second);
+        assert!(html.contains("in progress"));
+    }
+
+    #[test]
+    fn shows_times_in_the_requested_zone() {
+        let html = render_timeline(&session(), FixedOffset::east_opt(0).unwrap());
+        assert!(html.contains("times in UTC</p>"));
+        assert!(html.contains("09:30"));
+
+        let html = render_timeline(&session(), FixedOffset::west_opt(10 * 3600).unwrap());
+        assert!(html.contains("times in UTC-10:00"));
+        assert!(html.contains("at 23:35"));
+        // The session started the evening before in that zone
+        assert!(html.contains("March 3, 2024"));
+    }
+}
@@ -0,0 +1,633 @@
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode, header},
+};
+use http_body_util::BodyExt;
+use serde_json::{Value, json};
+use spectrum::{AppState, app, provider::Provider};
+use tower::ServiceExt;
+
+const THERAPIST_TOKEN: &str = "test-therapist-token";
+
+// Mock state keeping its audit log and stores under a per-process temp dir
+fn mock_state_in(name: &str) -> AppState {
+    let dir = std::env::temp_dir().join(format!("spectrum-test-{}-{}", std::process::id(), name));
+    AppState::new(
+        "test".to_string(),
+        "test".to_string(),
+        Provider::Mock,
+        dir.to_string_lossy().into_owned(),
+    )
+    .unwrap()
+    .with_store_dir(&dir)
+}
+
+fn mock_state() -> AppState {
+    mock_state_in("app")
+}
+
+fn mock_app() -> Router {
+    app(mock_state())
+}
+
+// App with the therapist routes enabled; requests still need the token
+fn therapist_app() -> Router {
+    app(mock_state().with_therapist_token(THERAPIST_TOKEN))
+}
+
+async fn json_body(response: axum::response::Response) -> Value {
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+#[tokio::test]
+async fn serves_metrics() {
+    let response = mock_app()
+        .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unknown_session_summary_is_not_found() {
+    let response = therapist_app()
+        .oneshot(
+            Request::get("/sessions/00000000-0000-0000-0000-000000000000/summary")
+                .header("x-therapist-token", THERAPIST_TOKEN)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn creates_and_fetches_lesson_plan_after_a_restart() {
+    let server = app(mock_state_in("lesson-plans").with_therapist_token(THERAPIST_TOKEN));
+    let plan = json!({
+        "name": "Feelings",
+        "topics": [
+            {"topic_focus": "emotions", "activity": {"type": "emotions"}},
+            {"topic_focus": "facial expressions"}
+        ]
+    });
+
+    // Only therapists write lesson plans
+    let response = server
+        .clone()
+        .oneshot(
+            Request::post("/lesson_plans")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(plan.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = server
+        .oneshot(
+            Request::post("/lesson_plans")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-therapist-token", THERAPIST_TOKEN)
+                .body(Body::from(plan.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created = json_body(response).await;
+
+    // A new server on the same stores picks the plan up
+    let restarted = mock_state_in("lesson-plans");
+    restarted.restore().await;
+    let response = app(restarted)
+        .oneshot(
+            Request::get(format!("/lesson_plans/{}", created["id"].as_str().unwrap()))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let fetched = json_body(response).await;
+    assert_eq!(fetched["name"], "Feelings");
+    assert_eq!(fetched["topics"][1]["mastery"]["images_required"], 3);
+}
+
+#[tokio::test]
+async fn rejects_lesson_plan_without_topics() {
+    let response = therapist_app()
+        .oneshot(
+            Request::post("/lesson_plans")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-therapist-token", THERAPIST_TOKEN)
+                .body(Body::from(
+                    json!({"name": "Empty", "topics": []}).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn unknown_group_is_not_found() {
+    let response = mock_app()
+        .oneshot(
+            Request::post("/groups/00000000-0000-0000-0000-000000000000/participants")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({"name": "Sam"}).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn overrides_require_therapist_token() {
+    let response = mock_app()
+        .oneshot(
+            Request::post("/sessions/00000000-0000-0000-0000-000000000000/overrides")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({"type": "regenerate_image"}).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn unknown_child_has_no_rewards() {
+    let response = therapist_app()
+        .oneshot(
+            Request::get("/children/nobody/rewards")
+                .header("x-therapist-token", THERAPIST_TOKEN)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn reports_and_purges_empty_cache() {
+    let response = mock_app()
+        .oneshot(Request::get("/cache").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let app = therapist_app();
+    let response = app
+        .clone()
+        .oneshot(
+            Request::get("/cache")
+                .header("x-therapist-token", THERAPIST_TOKEN)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let report = json_body(response).await;
+    assert_eq!(report["entries"], json!([]));
+
+    let response = app
+        .oneshot(
+            Request::delete("/cache?stage=generate_image")
+                .header("x-therapist-token", THERAPIST_TOKEN)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await["purged"], 0);
+}
+
+#[tokio::test]
+async fn deleting_child_data_requires_therapist_token() {
+    let response = mock_app()
+        .oneshot(
+            Request::delete("/children/child-1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn creates_experiment_and_reports_empty_arms() {
+    let response = mock_app()
+        .oneshot(
+            Request::post("/experiments")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({"name": "Solo", "arms": [{"name": "control"}]}).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let app = therapist_app();
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/experiments")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-therapist-token", THERAPIST_TOKEN)
+                .body(Body::from(
+                    json!({"name": "Solo", "arms": [{"name": "control"}]}).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let experiment = json!({
+        "name": "Hint wording",
+        "arms": [
+            {"name": "control"},
+            {
+                "name": "gentle",
+                "feedback_guidance": "Praise effort before giving hints.",
+                "progression": {"type": "detail_ratio", "min_ratio": 0.8}
+            }
+        ]
+    });
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/experiments")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-therapist-token", THERAPIST_TOKEN)
+                .body(Body::from(experiment.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let id = json_body(response).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let response = app
+        .oneshot(
+            Request::get(format!("/experiments/{}/report", id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let report = json_body(response).await;
+    assert_eq!(report["arms"][1]["arm"], "gentle");
+    assert_eq!(report["arms"][1]["sessions"], 0);
+}
+
+#[tokio::test]
+async fn timeline_export_requires_therapist_token() {
+    let response = mock_app()
+        .oneshot(
+            Request::get("/sessions/00000000-0000-0000-0000-000000000000/timeline")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn audit_log_requires_therapist_token() {
+    let response = therapist_app()
+        .oneshot(
+            Request::get("/sessions/00000000-0000-0000-0000-000000000000/audit")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn replay_only_uses_configured_providers() {
+    let replay = |provider: Value| {
+        Request::post("/sessions/00000000-0000-0000-0000-000000000000/replay")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-therapist-token", THERAPIST_TOKEN)
+            .body(Body::from(json!({ "provider": provider }).to_string()))
+            .unwrap()
+    };
+    let app = therapist_app();
+
+    // A provider spelled out in the request would let callers pick the host
+    let response = app
+        .clone()
+        .oneshot(replay(json!({
+            "type": "openai_compatible",
+            "base_url": "http://attacker.example",
+            "model": "any"
+        })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let response = app
+        .clone()
+        .oneshot(replay(json!("attacker")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app.oneshot(replay(json!("mock"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn paid_routes_require_a_clinic_key_once_keys_are_configured() {
+    let keys = [("clinic-a".to_string(), "key-a".to_string())].into();
+    let app = app(mock_state().with_clinic_keys(keys));
+    let end = |key: Option<&str>| {
+        let mut request = Request::post("/sessions/00000000-0000-0000-0000-000000000000/end");
+        if let Some(key) = key {
+            request = request.header("x-api-key", key);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    let response = app.clone().oneshot(end(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.clone().oneshot(end(Some("key-b"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.oneshot(end(Some("key-a"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn session_without_topic_or_lesson_plan_is_rejected() {
+    let response = mock_app()
+        .oneshot(
+            Request::post("/generate_image")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({
+                        "age": "6",
+                        "autism_level": "Level 1",
+                        "topic_focus": " ",
+                        "treatment_plan": "Naming objects",
+                    })
+                    .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn therapist_token_in_the_query_only_opens_websockets() {
+    let response = therapist_app()
+        .oneshot(
+            Request::get(format!(
+                "/sessions/00000000-0000-0000-0000-000000000000/audit?token={}",
+                THERAPIST_TOKEN
+            ))
+            .body(Body::empty())
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+// POSTs `body` as JSON to `uri` on `app`
+async fn post_json(app: &Router, uri: &str, body: Value) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::post(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-therapist-token", THERAPIST_TOKEN)
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+fn new_session(experiment_id: Option<&str>) -> Value {
+    json!({
+        "age": "6",
+        "autism_level": "Level 1",
+        "topic_focus": "animals",
+        "treatment_plan": "Naming objects",
+        "experiment_id": experiment_id,
+    })
+}
+
+#[tokio::test]
+async fn starts_a_session_and_answers_the_child_with_the_mock_provider() {
+    let app = app(mock_state_in("session"));
+
+    let response = post_json(&app, "/generate_image", new_session(None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let session = json_body(response).await;
+    assert!(
+        session["image"]
+            .as_str()
+            .unwrap()
+            .starts_with("data:image/png;base64,")
+    );
+    assert!(!session["checklist"].as_array().unwrap().is_empty());
+
+    let response = post_json(
+        &app,
+        "/process_chat",
+        json!({"session_id": session["session_id"], "user_message": "I see a dog"}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let reply = json_body(response).await;
+    assert_eq!(reply["chat"][0], json!(["Child", "I see a dog"]));
+    assert_eq!(reply["chat"][1][0], "Teacher");
+    assert!(reply["new_image"].is_null());
+}
+
+#[tokio::test]
+async fn finishes_a_round_and_moves_up_a_level() {
+    let app = app(mock_state_in("round").with_therapist_token(THERAPIST_TOKEN));
+    // Both arms advance the child on their first message
+    let advance = json!({"type": "detail_ratio", "min_ratio": 0.0});
+    let response = post_json(
+        &app,
+        "/experiments",
+        json!({
+            "name": "Fast progression",
+            "arms": [
+                {"name": "a", "progression": advance},
+                {"name": "b", "progression": advance}
+            ]
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let experiment_id = json_body(response).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let response = post_json(&app, "/generate_image", new_session(Some(&experiment_id))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let session = json_body(response).await;
+
+    let response = post_json(
+        &app,
+        "/process_chat",
+        json!({"session_id": session["session_id"], "user_message": "A cat"}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let reply = json_body(response).await;
+    assert!(
+        reply["new_image"]
+            .as_str()
+            .unwrap()
+            .starts_with("data:image/png")
+    );
+    let announcement = reply["chat"].as_array().unwrap().last().unwrap();
+    assert_eq!(announcement[0], "System");
+    assert!(
+        announcement[1]
+            .as_str()
+            .unwrap()
+            .contains("advanced to Simple difficulty")
+    );
+
+    let response = app
+        .oneshot(
+            Request::get(format!("/experiments/{}/report", experiment_id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let report = json_body(response).await;
+    let sessions: u64 = report["arms"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|arm| arm["sessions"].as_u64().unwrap())
+        .sum();
+    assert_eq!(sessions, 1);
+}
+
+#[tokio::test]
+async fn chat_for_an_unknown_session_is_not_found() {
+    let app = mock_app();
+    let response = post_json(
+        &app,
+        "/process_chat",
+        json!({"session_id": "00000000-0000-0000-0000-000000000000", "user_message": "Hi"}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = post_json(
+        &app,
+        "/process_chat",
+        json!({"session_id": "not-a-session", "user_message": "Hi"}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn starts_a_group_and_takes_a_message() {
+    let app = app(mock_state_in("group"));
+
+    let response = post_json(
+        &app,
+        "/groups",
+        json!({
+            "age": "7",
+            "autism_level": "Level 1",
+            "topic_focus": "farm",
+            "treatment_plan": "Turn taking",
+            "participants": ["Ann", "Ben"]
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let group = json_body(response).await;
+    let group_id = group["group_id"].as_str().unwrap();
+    let ann = &group["participants"][0]["id"];
+
+    let response = post_json(
+        &app,
+        &format!("/groups/{}/messages", group_id),
+        json!({"participant_id": ann, "message": "A red barn"}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let reply = json_body(response).await;
+    assert_eq!(reply["chat"][0], json!(["Child (Ann)", "A red barn"]));
+    assert_eq!(reply["chat"][1][0], "Teacher");
+}
+
+#[tokio::test]
+async fn clinics_only_see_their_own_usage() {
+    let keys = [
+        ("clinic-a".to_string(), "key-a".to_string()),
+        ("clinic-b".to_string(), "key-b".to_string()),
+    ]
+    .into();
+    let app = app(mock_state().with_clinic_keys(keys));
+    let usage = |key: Option<&str>| {
+        let mut request = Request::get("/clinics/clinic-a/usage");
+        if let Some(key) = key {
+            request = request.header("x-api-key", key);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    let response = app.clone().oneshot(usage(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.clone().oneshot(usage(Some("key-b"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = app.oneshot(usage(Some("key-a"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = json_body(response).await;
+    assert_eq!(body["clinic_id"], "clinic-a");
+    assert_eq!(body["usage"]["model_calls"], 0);
+}